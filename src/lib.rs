@@ -1,510 +1,9363 @@
 use regex::Regex;
-use std::path::Path;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+mod json;
 
 /// Build a packaged dependency that is stored in directory `name` under
 /// `thirdparty` in the project tree, e.g. `thirdparty/zlib`.
 ///
+/// It's installed into `target_dir`, with its libraries under [`lib_dir`]
+/// even if it uses `GNUInstallDirs` on a distro that would pick `lib64`,
+/// unless `definitions` sets `CMAKE_INSTALL_LIBDIR` itself.
+///
 pub fn build_thirdparty(
     name: &str,
     target_dir: &Path,
     profile: &str,
     definitions: &[(&str, &str)],
 ) -> String {
-    // We need to create a dedicated subdirectory for the build or cmake will
-    // wipe it every time, forcing a rebuild
-    let out_dir = target_dir.join(&format!("build-{}", name));
-    match std::fs::create_dir(&out_dir) {
-        Ok(_) => (),
-        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
-        Err(e) => panic!(
-            "Could not create build directory '{}': {}",
-            out_dir.display(),
-            e
-        ),
-    }
-
-    let mut config = cmake::Config::new(&format!("thirdparty/{}", name));
-    config.profile(profile);
-    config.define("CMAKE_INSTALL_PREFIX", target_dir.to_str().unwrap());
-    config.define("CMAKE_PREFIX_PATH", target_dir.join("lib").join("cmake"));
-    config.out_dir(&out_dir);
+    check_cmake().unwrap_or_else(|e| panic!("{}", e));
+    let mut thirdparty =
+        ThirdpartyBuild::new(name, target_dir, profile, definitions);
+    thirdparty.compilers = Compilers::resolve(None, None);
+    let (dst, _) = thirdparty.build();
+    path_str(&dst)
+        .unwrap_or_else(|e| panic!("Unable to return {} dst: {}", name, e))
+        .to_string()
+}
 
-    for def in definitions {
-        config.define(def.0, def.1);
-    }
+/// Borrow `path` as a `str`, for the few places that need one, with an
+/// error naming the path if it isn't valid UTF-8.
+///
+fn path_str(path: &Path) -> Result<&str, BuildError> {
+    path.to_str()
+        .ok_or_else(|| BuildError::NonUtf8Path(path.to_path_buf()))
+}
 
-    config
-        .build()
-        .to_str()
-        .expect(&format!("Unable to convert {} dst to str", name))
-        .to_string()
+/// The directory headers are installed into under the install prefix
+/// `target_dir`, for crates that run bindgen against the C wrapper or its
+/// dependencies themselves.
+///
+pub fn include_dir(target_dir: &Path) -> PathBuf {
+    target_dir.join("include")
 }
 
-/// Path information for a linked library.
+/// The directory libraries are installed into under the install prefix
+/// `target_dir`.
 ///
-/// For a `path` '/home/libs/libmylib.so`, `basename` will be `mylib` and `libname`
-/// will be `libmylib.so`
+pub fn lib_dir(target_dir: &Path) -> PathBuf {
+    target_dir.join("lib")
+}
+
+/// The directory executables (and DLLs, on Windows) are installed into under
+/// the install prefix `target_dir`.
 ///
-#[derive(Debug)]
-pub struct DylibPathInfo {
-    pub path: String,
-    pub basename: String,
-    pub libname: String,
+pub fn bin_dir(target_dir: &Path) -> PathBuf {
+    target_dir.join("bin")
 }
 
-#[derive(Debug)]
-pub enum LinkArg {
-    LinkDir(String),
-    LinkLib(String),
-    Path(DylibPathInfo),
+/// The shared libraries the build of the cppmm project `project_name` left
+/// its binaries depending on, for installers to bundle with them. Call this
+/// from the build script after building, e.g. with [`build`].
+///
+/// On Windows these are the C wrapper's DLL and the DLLs of every library
+/// on its link line that could be found. On Unices the wrapper is linked
+/// statically, so they're the `.so`s or `.dylib`s on its link line, as
+/// they were linked (which may be a development symlink rather than the
+/// file the soname names) and including system libraries that were linked
+/// by path. Empty if the wrapper hasn't been built.
+///
+pub fn runtime_dlls(
+    project_name: &str,
+    major_version: u32,
+    minor_version: u32,
+) -> Vec<PathBuf> {
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    std::fs::read_to_string(runtime_libs_path(
+        &out_dir,
+        project_name,
+        major_version,
+        minor_version,
+    ))
+    .unwrap_or_default()
+    .lines()
+    .filter(|l| !l.is_empty())
+    .map(PathBuf::from)
+    .collect()
 }
 
-#[cfg(not(target_os = "windows"))]
-fn is_dylib_path(s: &str, re: &Regex) -> Option<LinkArg> {
-    if let Ok(_) = std::env::var("CPPMM_DEBUG_BUILD") {
-        println!("cargo:warning=- {}", s);
+/// Remove the build directories of the packaged dependency `name` from
+/// `target_dir`, for every profile and set of definitions it was built
+/// with, along with everything they installed there, so that the next build
+/// rebuilds it from scratch.
+///
+/// The installed files are the ones listed in CMake's install manifest, so
+/// only files under `target_dir` are ever removed.
+///
+pub fn clean_thirdparty(name: &str, target_dir: &Path) -> std::io::Result<()> {
+    for build_dir in thirdparty_build_dirs(name, target_dir) {
+        clean_build_dir(&build_dir, target_dir)?;
     }
-    
-    if let Some(pos @ 0) = s.find("-l") {
-        return Some(LinkArg::LinkLib(s[2..].to_string()))
-    } else if let Some(pos @ 0) = s.find("-L") {
-        if let Ok(_) = std::env::var("CPPMM_DEBUG_BUILD") {
-            println!("cargo:warning=    is a link dir {}", s);
-        }
-        return Some(LinkArg::LinkDir(s[2..].to_string()))
-    } else if let Some(m) = re.captures_iter(s).next() {
-        if let Some(c0) = m.get(0) {
-            if let Some(c1) = m.get(1) {
-                if let Ok(_) = std::env::var("CPPMM_DEBUG_BUILD") {
-                    println!("cargo:warning=    is a dylib path {}", s);
-                }
-                return Some(LinkArg::Path(DylibPathInfo {
-                    path: s.to_string(),
-                    basename: c0.as_str().to_string(),
-                    libname: c1.as_str().to_string(),
-                }));
+    Ok(())
+}
+
+/// Remove one build directory of a packaged dependency, and the files it
+/// installed into `target_dir`, as for [`clean_thirdparty`].
+///
+fn clean_build_dir(build_dir: &Path, target_dir: &Path) -> std::io::Result<()> {
+    if let Ok(manifest) = std::fs::read_to_string(
+        build_dir.join("build").join("install_manifest.txt"),
+    ) {
+        for file in manifest.lines().map(Path::new) {
+            // symlink_metadata so the versioned .so symlinks go too
+            if file.starts_with(target_dir)
+                && file.symlink_metadata().map_or(false, |m| !m.is_dir())
+            {
+                std::fs::remove_file(file)?;
             }
         }
     }
-    if let Ok(_) = std::env::var("CPPMM_DEBUG_BUILD") {
-        println!("cargo:warning=    is not a dylib path");
+
+    match std::fs::remove_dir_all(build_dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        r => r,
     }
+}
 
-    None
+/// The build directories for the dependency `name` under `target_dir`:
+/// `build-<name>-<variant>` for each variant built (see
+/// [`ThirdpartyBuild::build_dir`]), as well as a plain `build-<name>` left
+/// by older versions.
+///
+fn thirdparty_build_dirs(name: &str, target_dir: &Path) -> Vec<PathBuf> {
+    let plain = format!("build-{}", name);
+    let mut dirs: Vec<PathBuf> = std::fs::read_dir(target_dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|e| {
+                    let file_name = e.file_name();
+                    let file_name = file_name.to_string_lossy();
+                    match file_name.strip_prefix(plain.as_str()) {
+                        Some("") => true,
+                        Some(variant) => {
+                            variant.strip_prefix('-').map_or(false, |v| {
+                                v.len() == 8
+                                    && v.bytes().all(|b| b.is_ascii_hexdigit())
+                            })
+                        }
+                        None => false,
+                    }
+                })
+                .map(|e| e.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    dirs.sort();
+    dirs
 }
 
-#[cfg(target_os = "windows")]
-fn is_dll_lib_path(s: &str, re: &Regex) -> Option<LinkArg> {
-    if let Some(m) = re.captures_iter(s).next() {
-        if let Some(c0) = m.get(0) {
-            if let Some(c1) = m.get(1) {
-                return Some(LinkArg::Path(DylibPathInfo {
-                    path: s.to_string(),
-                    basename: c0.as_str().to_string(),
-                    libname: c1.as_str().to_string(),
-                }));
-            }
+/// Everything needed to build and install one packaged dependency.
+///
+struct ThirdpartyBuild<'a> {
+    name: &'a str,
+    source_dir: PathBuf,
+    target_dir: &'a Path,
+    profile: &'a str,
+    definitions: Vec<(String, String)>,
+    jobs: Option<usize>,
+    /// Not part of the settings hash, since it doesn't change the output.
+    compiler_launcher: Option<PathBuf>,
+    compilers: Compilers,
+    cflags: Vec<String>,
+    cxxflags: Vec<String>,
+    /// CMake policy ids and their settings, e.g. `("CMP0091", "NEW")`.
+    policies: Vec<(String, String)>,
+    /// Not part of the settings hash either.
+    verbose: bool,
+    /// How many times to retry a build that failed before it got as far as
+    /// compiling anything.
+    retries: u32,
+}
+
+impl<'a> ThirdpartyBuild<'a> {
+    /// Default settings for building `thirdparty/<name>`, installing into
+    /// `target_dir`.
+    fn new(
+        name: &'a str,
+        target_dir: &'a Path,
+        profile: &'a str,
+        definitions: &[(&str, &str)],
+    ) -> ThirdpartyBuild<'a> {
+        ThirdpartyBuild {
+            name,
+            source_dir: Path::new("thirdparty").join(name),
+            target_dir,
+            profile,
+            definitions: definitions
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            jobs: None,
+            compiler_launcher: None,
+            compilers: Compilers::default(),
+            cflags: Vec::new(),
+            cxxflags: Vec::new(),
+            policies: default_policies(),
+            verbose: false,
+            retries: 0,
         }
     }
 
-    None
-}
-
-#[cfg(target_os = "windows")]
-fn get_linking_from_vsproj(
-    build_path: &Path,
-    clib_versioned_name: &str,
-    build_type: &str,
-) -> Option<Vec<LinkArg>> {
-    use quick_xml::events::{BytesEnd, BytesStart, Event};
-    use quick_xml::Reader;
-    use std::borrow::Borrow;
-    use std::io::Cursor;
-    use std::iter;
+    /// Set `policies` on top of the defaults, overriding any with the same
+    /// id.
+    fn set_policies(&mut self, policies: &[(&str, &str)]) {
+        for (id, value) in policies {
+            self.policies.retain(|(i, _)| i != id);
+            self.policies.push((id.to_string(), value.to_string()));
+        }
+    }
 
-    let proj_path = build_path.join(format!("{}.vcxproj", clib_versioned_name));
-    let proj_xml = std::fs::read_to_string(&proj_path).ok()?;
+    /// Set the CMake variable `key` to `value`, replacing any definition of
+    /// it the dependency already had.
+    fn define(&mut self, key: &str, value: &str) {
+        self.definitions.retain(|(k, _)| k != key);
+        self.definitions.push((key.to_string(), value.to_string()));
+    }
 
-    let re = Regex::new(r"(?:.*\\(.*))(\.lib)$").unwrap();
+    /// The directory the dependency is built in, `build-<name>-<variant>`,
+    /// where the variant is a short hash of the profile and definitions.
+    ///
+    /// That way differently configured builds of the same dependency, e.g.
+    /// for Debug and Release, each get a build directory of their own rather
+    /// than reconfiguring one back and forth. They all still install into
+    /// `target_dir`.
+    ///
+    fn build_dir(&self) -> PathBuf {
+        let mut hash = Fnv1a::new();
+        hash.write(self.profile);
+        for (k, v) in &self.definitions {
+            hash.write(k);
+            hash.write(v);
+        }
+        self.target_dir.join(format!(
+            "build-{}-{:08x}",
+            self.name,
+            hash.finish() as u32
+        ))
+    }
 
-    let mut reader = Reader::from_str(&proj_xml);
-    reader.trim_text(true);
+    /// Set up the `cmake::Config` for the build, without running it.
+    fn config(&self) -> cmake::Config {
+        // We need to create a dedicated subdirectory for the build or cmake
+        // will wipe it every time, forcing a rebuild
+        let out_dir = self.build_dir();
+        match std::fs::create_dir(&out_dir) {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(e) => panic!(
+                "Could not create build directory '{}': {}",
+                out_dir.display(),
+                e
+            ),
+        }
 
-    let mut in_item_definition = false;
-    let mut in_link = false;
-    let mut in_deps = false;
+        let mut config = cmake::Config::new(&self.source_dir);
+        config.profile(self.profile);
+        config.define("CMAKE_INSTALL_PREFIX", self.target_dir);
+        config.define("CMAKE_PREFIX_PATH", cmake_prefix_path(self.target_dir));
+        config.out_dir(&out_dir);
 
-    let mut buf = Vec::new();
+        // GNUInstallDirs picks lib64 on some distros, but everything after
+        // the install expects the libraries and package configs in lib
+        if !self
+            .definitions
+            .iter()
+            .any(|(k, _)| k == "CMAKE_INSTALL_LIBDIR")
+        {
+            config.define("CMAKE_INSTALL_LIBDIR", "lib");
+        }
 
-    loop {
-        match reader.read_event(&mut buf) {
-            Ok(Event::Start(ref e)) => match e.name() {
-                b"ItemDefinitionGroup" => {
-                    for attr in e.attributes() {
-                        if let Ok(attr) = attr {
-                            if attr.key == b"Condition" {
-                                let s =
-                                    std::str::from_utf8(attr.value.borrow())
-                                        .unwrap();
-                                if s.contains(build_type) {
-                                    in_item_definition = true;
-                                }
-                            }
-                        }
-                    }
-                }
-                b"Link" if in_item_definition => {
-                    in_link = true;
-                }
-                b"AdditionalDependencies" if in_item_definition && in_link => {
-                    in_deps = true;
-                }
-                _ => (),
-            },
-            Ok(Event::End(ref e)) => match e.name() {
-                b"ItemDefinitionGroup" => {
-                    in_item_definition = false;
-                }
-                b"Link" => {
-                    in_link = false;
-                }
-                b"AdditionalDependencies" => in_deps = false,
-                _ => (),
-            },
-            Ok(Event::Text(e)) if in_deps => {
-                let mut dlls = Vec::new();
-                for tok in e.unescape_and_decode(&reader).unwrap().split(";") {
-                    if let Some(dll) = is_dll_lib_path(tok, &re) {
-                        dlls.push(dll)
-                    }
-                }
-                return Some(dlls);
-            }
-            Ok(Event::Eof) => break,
-            Err(e) => panic!("Error parsing vsproj xml"),
-            _ => (),
+        for def in &self.definitions {
+            config.define(&def.0, &def.1);
         }
-    }
 
-    None
-}
+        if let Some(launcher) = &self.compiler_launcher {
+            set_compiler_launcher(&mut config, launcher);
+        }
+        self.compilers.apply(&mut config);
 
-#[cfg(target_os = "windows")]
-fn get_linking_from_nmake(
-    build_path: &Path,
-    clib_versioned_name: &str,
-) -> Option<Vec<LinkArg>> {
-    let build_make_path = build_path
-        .join("CMakeFiles")
-        .join(format!("{}-shared.dir", clib_versioned_name))
-        .join("build.make");
+        // Static archives end up linked into the wrapper's shared library
+        #[cfg(target_os = "linux")]
+        if !self
+            .definitions
+            .iter()
+            .any(|(k, _)| k == "CMAKE_POSITION_INDEPENDENT_CODE")
+        {
+            config.define("CMAKE_POSITION_INDEPENDENT_CODE", "ON");
+        }
 
-    let build_make = std::fs::read_to_string(&build_make_path).ok()?;
+        add_compiler_flags(&mut config, &self.cflags, &self.cxxflags);
 
-    let re = Regex::new(r"(?:.*\\(.*))(\.lib)$").unwrap();
+        for (id, value) in &self.policies {
+            config.define(format!("CMAKE_POLICY_DEFAULT_{}", id), value);
+        }
 
-    let mut found_slash_dll = false;
-    let mut libs = Vec::new();
-    // println!("cargo:warning=Found links:");
-    for tok in build_make.split_whitespace() {
-        if tok == "/dll" {
-            found_slash_dll = true;
-        } else if found_slash_dll {
-            if tok == "<<" {
-                break;
-            } else {
-                if let Some(dlp) = is_dll_lib_path(tok, &re) {
-                    libs.push(dlp);
-                }
-            }
+        if !self
+            .definitions
+            .iter()
+            .any(|(k, _)| k == "CMAKE_VERBOSE_MAKEFILE")
+        {
+            set_verbose_makefile(&mut config, self.verbose);
         }
-    }
 
-    Some(libs)
-}
+        config
+    }
 
-#[cfg(target_os = "windows")]
-/// Parse the generated project files from our C wrapper in order to get its 
-/// set of linker arguments.
-///
-/// On Unices this will parse CMake's auxiliary link.txt file for `.so`s or 
-/// `.dylib`s. On Windows this will parse NMake or VS XML project files.
-///
-pub fn get_linking_from_cmake(
-    build_path: &Path,
-    clib_versioned_name: &str,
-    build_type: &str,
-) -> Vec<LinkArg> {
-    if let Some(libs) =
-        get_linking_from_vsproj(build_path, clib_versioned_name, build_type)
-    {
-        libs
-    } else if let Some(libs) =
-        get_linking_from_nmake(build_path, clib_versioned_name)
-    {
-        libs
-    } else {
-        panic!("Could not open either vsproj or nmake build");
+    /// Returns true if the dependency has already been installed with the
+    /// same settings, and none of its sources have changed since.
+    ///
+    fn is_up_to_date(&self) -> bool {
+        let out_dir = self.build_dir();
+        let stamp_path = out_dir.join(".cppmm-stamp");
+        let stamp = format!("{:016x}", self.settings_hash());
+        std::fs::read_to_string(&stamp_path).ok().as_deref() == Some(&stamp)
+            && is_installed(&out_dir)
+            && !sources_newer_than(&self.source_dir, &stamp_path)
     }
-}
 
-#[cfg(not(target_os = "windows"))]
-pub fn get_linking_from_cmake(
-    build_path: &Path,
-    clib_versioned_name: &str,
-    _build_type: &str,
-) -> Vec<LinkArg> {
-    let link_txt_path = build_path
-        .join("CMakeFiles")
-        .join(format!("{}.dir", clib_versioned_name))
-        .join("link.txt");
-    let link_txt = std::fs::read_to_string(&link_txt_path).expect(&format!(
-        "Could not read link_txt_path: {}",
-        link_txt_path.display()
-    ));
-
-    if let Ok(_) = std::env::var("CPPMM_DEBUG_BUILD") {
-        println!("cargo:warning=Reading link.txt {}", link_txt);
+    /// If the last build of the dependency left its `.cppmm-building` marker
+    /// behind, clean its build directory and everything it installed and
+    /// return true.
+    ///
+    /// If the process that left the marker is still running, it's building
+    /// the dependency right now, e.g. for another crate sharing the prefix,
+    /// so we wait for it to finish instead.
+    ///
+    fn clean_interrupted_build(&self) -> bool {
+        let out_dir = self.build_dir();
+        let marker = out_dir.join(".cppmm-building");
+        let mut waiting = false;
+        let pid = loop {
+            let pid = match std::fs::read_to_string(&marker) {
+                Ok(pid) => pid,
+                Err(_) => return false,
+            };
+            if !is_building_elsewhere(pid.trim()) {
+                break pid;
+            }
+            if !waiting {
+                println!(
+                    "cargo:warning=Waiting for process {} to finish building \
+                     {}",
+                    pid.trim(),
+                    self.name
+                );
+                waiting = true;
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        };
+        println!(
+            "cargo:warning=The last build of {} (by process {}) did not \
+             finish, cleaning it and building it again",
+            self.name,
+            pid.trim()
+        );
+        if let Err(e) = clean_build_dir(&out_dir, self.target_dir) {
+            println!(
+                "cargo:warning=Could not clean {}: {}",
+                out_dir.display(),
+                e
+            );
+        }
+        true
     }
 
-    let re = Regex::new(
-        r"lib([^/]+?)(?:\.dylib|\.so|\.so.\d+|\.so.\d+.\d+|\.so.\d+.\d+.\d+)$",
-    )
-    .unwrap();
+    /// Build and install the dependency, unless it's already up to date.
+    ///
+    /// Just running CMake again would work, but it pays for a full configure
+    /// of every dependency on every build, which adds up. So instead we leave
+    /// a stamp file in the dependency's build directory recording the
+    /// settings it was built with, and skip CMake entirely as long as those
+    /// match, everything CMake says it installed is still there and no
+    /// source file is newer than the stamp.
+    ///
+    /// A `.cppmm-building` marker is also left in the build directory while
+    /// CMake runs. If it's still there next time and the process that wrote
+    /// it has gone, the last build was interrupted or failed part way
+    /// through, so the build directory and whatever it installed are
+    /// cleaned first and the dependency is built from scratch. If that
+    /// process is still running, we wait for it to finish first.
+    ///
+    /// Returns where it was built, and whether CMake actually ran.
+    ///
+    fn build(&self) -> (PathBuf, bool) {
+        let out_dir = self.build_dir();
+        let stamp_path = out_dir.join(".cppmm-stamp");
+        let stamp = format!("{:016x}", self.settings_hash());
+        let building_path = out_dir.join(".cppmm-building");
 
-    // Try and figure out what are libraries we want to copy to target.
-    // Libraries will end with `.so` or `.so.28.1.0` or `.dylib`
+        if !self.clean_interrupted_build() && self.is_up_to_date() {
+            if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+                println!("cargo:warning={} is up to date, skipping", self.name);
+            }
+            return (out_dir, false);
+        }
 
-    // First, strip off everything up to and including the initial "-o whatever.so"
-    let mut link_txt = link_txt.split(' ');
-    while let Some(s) = link_txt.next() {
-        if s == "-o" {
-            // pop off the output lib as well
-            let _ = link_txt.next();
-            break;
+        // config() creates the build directory, so the marker has to wait
+        let mut config = self.config();
+        if let Err(e) =
+            std::fs::write(&building_path, std::process::id().to_string())
+        {
+            println!(
+                "cargo:warning=Could not write {}: {}",
+                building_path.display(),
+                e
+            );
+        }
+        let mut attempt = 0;
+        let build_dir = out_dir.join("build");
+        let dst = loop {
+            // so whether there is one afterwards says if configuring worked,
+            // since CMake doesn't always rewrite an unchanged one
+            remove_build_system(&build_dir);
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                build_with_jobs(&mut config, self.jobs)
+            }));
+            let payload = match result {
+                Ok(dst) => break dst,
+                Err(payload) => payload,
+            };
+            if attempt >= self.retries || !is_transient_failure(&build_dir) {
+                std::panic::resume_unwind(payload);
+            }
+            attempt += 1;
+            let delay = retry_delay(attempt);
+            println!(
+                "cargo:warning=Building {} failed before compiling anything, \
+                 retrying in {}s (retry {} of {})",
+                self.name,
+                delay.as_secs(),
+                attempt,
+                self.retries
+            );
+            std::thread::sleep(delay);
+            config = self.config();
+        };
+        let _ = std::fs::remove_file(&building_path);
+        if self.verbose {
+            forward_cmake_warnings(self.name, &dst.join("build"));
         }
-    }
 
-    // Now match all the remaining arguments against a regex looking for
-    // shared library paths.
-    link_txt.filter_map(|s| is_dylib_path(s, &re)).collect()
-}
+        if let Err(e) = std::fs::write(&stamp_path, &stamp) {
+            println!(
+                "cargo:warning=Could not write stamp file {}: {}",
+                stamp_path.display(),
+                e
+            );
+        }
 
-pub struct Dependency {
-    pub name: &'static str,
-    pub definitions: Vec<(&'static str, &'static str)>,
-}
+        (dst, true)
+    }
 
-use std::fmt;
-impl fmt::Debug for Dependency {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.name)
+    /// A stable hash of the settings the dependency is built with, so we can
+    /// tell when they change between builds.
+    ///
+    fn settings_hash(&self) -> u64 {
+        let mut hash = Fnv1a::new();
+        let mut write = |s: &str| hash.write(s);
+        write(&self.source_dir.to_string_lossy());
+        write(self.profile);
+        // so anything installed into lib64 before we set this is reinstalled
+        write("CMAKE_INSTALL_LIBDIR=lib");
+        for (k, v) in &self.definitions {
+            write(k);
+            write(v);
+        }
+        for flag in self.cflags.iter().chain(&self.cxxflags) {
+            write(flag);
+        }
+        for compiler in [&self.compilers.c, &self.compilers.cxx] {
+            write(&format!("{:?}", compiler));
+        }
+        for (id, value) in &self.policies {
+            write(id);
+            write(value);
+        }
+        // The cmake crate picks these up from the environment itself
+        for (k, v) in compiler_flags_from_env() {
+            write(&k);
+            write(&v);
+        }
+        hash.finish()
     }
 }
 
-/// Build a standard-formatted cppmm c wrapper project and its dependencies.
-///
-/// If the environment variable `CMAKE_PREFIX_PATH` is set, any `dependencies`
-/// will be assumed to be present on the system, available in `CMAKE_PREFIX_PATH`.
-/// If `CMAKE_PREFIX_PATH` is not set, the list of dependencies will be built
-/// from the `thirdparty` directory.
-///
-/// `project_name` controls the name of the generated C library, as well as the 
-/// names of environment variables the user can set to control the build. For
-/// example, setting `project_name` to `openexr` will cause the script to respond
-/// to:
-/// * `CPPMM_OPENEXR_BUILD_LIBRARIES` - Ignore `CMAKE_PREFIX_PATH` and force  
-/// building the dependencies if this is set to "1".
-/// * `CPPMM_OPENEXR_BUILD_TYPE` - Set the build profile used for the C library 
-/// and all dependencies. This defaults to "Release" so you can use this to set 
-/// it to "Debug", for example.
+/// FNV-1a over a sequence of strings.
 ///
-/// `major_version` and `minor_version` are the crate version numbers and are 
-/// baked into the C library filename.
+/// This is used rather than `DefaultHasher` since the latter is allowed to
+/// change between Rust releases, which would needlessly invalidate
+/// everything on a toolchain upgrade.
 ///
-pub fn build(project_name: &str, major_version: u32, minor_version: u32, dependencies: &[Dependency]) {
+struct Fnv1a(u64);
 
-    let env_build_libraries = format!("CPPMM_{}_BUILD_LIBRARIES", project_name.to_ascii_uppercase());
-    let env_build_type = format!("CPPMM_{}_BUILD_TYPE", project_name.to_ascii_uppercase());
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf29ce484222325)
+    }
 
-    // If the user has set CMAKE_PREFIX_PATH then we don't want to build the
-    // bundled libraries, *unless* they have also set CPPMM_<project_name>_BUILD_LIBRARIES=1
-    let build_libraries = if std::env::var("CMAKE_PREFIX_PATH").is_ok() {
-        if let Ok(obl) = std::env::var(&env_build_libraries) {
-            obl == "1"
-        } else {
-            false
+    fn write(&mut self, s: &str) {
+        // terminate each string so ("ab", "c") and ("a", "bc") differ
+        for b in s.bytes().chain(std::iter::once(0)) {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
         }
-    } else {
-        true
-    };
+    }
 
-    let out_dir = std::env::var("OUT_DIR").unwrap();
-    let target_dir = Path::new(&out_dir).ancestors().skip(3).next().unwrap();
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
 
-    let clib_name = format!("{}-c", project_name);
-    let clib_versioned_name =
-        format!("{}-c-{}_{}", project_name, major_version, minor_version);
-    let clib_shared_versioned_name =
-        format!("{}-c-{}_{}-shared", project_name, major_version, minor_version);
+/// Have the generated build files for `config` print the full compiler and
+/// linker commands or not. This is always set, since it's cached and would
+/// otherwise stay on after one verbose build.
+///
+fn set_verbose_makefile(config: &mut cmake::Config, verbose: bool) {
+    config.define("CMAKE_VERBOSE_MAKEFILE", if verbose { "ON" } else { "OFF" });
+}
 
-    let lib_path = target_dir.join("lib");
-    let bin_path = target_dir.join("bin");
-    let cmake_prefix_path = lib_path.join("cmake");
+/// Run all C and C++ compiles for `config` through `launcher`, e.g. ccache.
+///
+fn set_compiler_launcher(config: &mut cmake::Config, launcher: &Path) {
+    config.define("CMAKE_C_COMPILER_LAUNCHER", launcher);
+    config.define("CMAKE_CXX_COMPILER_LAUNCHER", launcher);
+}
 
-    // allow user to override build type with environment variables
-    let build_type =
-        if let Ok(build_type) = std::env::var(&env_build_type) {
-            build_type
-        } else {
-            "Release".to_string()
+/// The C and C++ compilers to build everything with, so the dependencies and
+/// the wrapper all use the same ones. `None` leaves it to CMake (or rather,
+/// the cmake crate) to pick.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Compilers {
+    c: Option<PathBuf>,
+    cxx: Option<PathBuf>,
+}
+
+impl Compilers {
+    /// The compilers given to [`Builder::compiler`], if any, or else those
+    /// from `CC` and `CXX` (see [`compiler_from_env`]). Compilers that can't
+    /// be found are left out with a warning.
+    fn resolve(c: Option<&Path>, cxx: Option<&Path>) -> Compilers {
+        let find = |compiler: Option<PathBuf>, var: &str| {
+            let compiler = compiler.or_else(|| compiler_from_env(var))?;
+            let found = find_program(&compiler);
+            if found.is_none() {
+                println!(
+                    "cargo:warning={} compiler {} not found, leaving it to \
+                     CMake to choose",
+                    var,
+                    compiler.display()
+                );
+            }
+            found
         };
+        Compilers {
+            c: find(c.map(Path::to_path_buf), "CC"),
+            cxx: find(cxx.map(Path::to_path_buf), "CXX"),
+        }
+    }
 
-    let dst = if build_libraries {
-        println!("cargo:warning=Building packaged dependencies {:?}", dependencies);
-        for dep in dependencies {
-            build_thirdparty(dep.name, target_dir, &build_type, &dep.definitions);
+    fn apply(&self, config: &mut cmake::Config) {
+        if let Some(c) = &self.c {
+            config.define("CMAKE_C_COMPILER", c);
+        }
+        if let Some(cxx) = &self.cxx {
+            config.define("CMAKE_CXX_COMPILER", cxx);
         }
+    }
+}
 
-        cmake::Config::new(clib_name)
-            .define("CMAKE_EXPORT_COMPILE_COMMANDS", "ON")
-            .define("CMAKE_PREFIX_PATH", cmake_prefix_path.to_str().unwrap())
-            .profile(&build_type)
-            .build()
+/// The compiler set by the environment variable `var` (`CC` or `CXX`),
+/// following the `cc` crate's conventions: the first of `<var>_<target>`,
+/// `<var>_<target_with_underscores>`, `TARGET_<var>` and `<var>` that's set.
+///
+fn compiler_from_env(var: &str) -> Option<PathBuf> {
+    let target = std::env::var("TARGET").unwrap_or_default();
+    let names = [
+        format!("{}_{}", var, target),
+        format!("{}_{}", var, target.replace('-', "_")),
+        format!("TARGET_{}", var),
+        var.to_string(),
+    ];
+    names
+        .iter()
+        .filter_map(std::env::var_os)
+        .find(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Find `program`, either as a path to an existing file or if it's a bare
+/// name, by searching `PATH`.
+///
+fn find_program(program: &Path) -> Option<PathBuf> {
+    if program.components().count() > 1 || program.is_absolute() {
+        return Some(program.to_path_buf()).filter(|p| p.is_file());
+    }
+
+    let exe_suffixes: &[&str] = if cfg!(target_os = "windows") {
+        &["", ".exe"]
     } else {
-        println!("cargo:warning=Using system dependencies {:?}", dependencies);
-        cmake::Config::new(clib_name)
-            .define("CMAKE_EXPORT_COMPILE_COMMANDS", "ON")
-            .profile(&build_type)
-            .build()
+        &[""]
     };
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .flat_map(|dir| {
+            exe_suffixes.iter().map(move |suffix| {
+                let mut name = program.as_os_str().to_os_string();
+                name.push(suffix);
+                dir.join(name)
+            })
+        })
+        .find(|p| p.is_file())
+}
 
-    let build_path = Path::new(&dst).join("build");
-
-    let link_args = get_linking_from_cmake(
-        &build_path,
-        &clib_shared_versioned_name,
-        &build_type,
-    );
-    println!("cargo:warning=Link libs: {:?}", link_args);
-
-    // Link our wrapper library
-    //
-    // We currently build a dylib on windows just so we can enable Debug
-    // builds. This is because Rust always links against the release msvcrt 
-    // (presumably since the debug one is unusable in a lot of situations), thus
-    // we cannot link statically since setting the C shim to Debug mode will 
-    // cause it to link against the debug msvcrt. This in turn causes all sorts
-    // of bad shit to happen (segfaults mostly). By the way, did you know that 
-    // STL types are different sizes in debug and release builds on Windows?
-    // I didn't until today because I couldn't imagine a world in which something
-    // like that would be allowed to happen.
-    //
-    // In theory, you can override this, but like most things with CMake, the 
-    // correct incantations are buried somewhere in vague mailing list 
-    // threads, and don't actually seem to work (at least not with VS generators, 
-    // which appear to want to force the runtime for you).
-    //
-    // So, the easiest way out here is just to build everything from the C shim
-    // down as a DLL so we can neatly sidestep all this (because the C library 
-    // provides a nice ABI dambreak against the insanity).
-    //
-    // We still build statically on Linux since that way you don't need to install
-    // the DSO along with any Rust binaries you might want to build. Ultimately
-    // installation in a production environment will require a bit more thought,
-    // but suffice to say it's complex. On Windows at least, just copying DLLs
-    // around everywhere seems to be the norm so we assume it's not the end of 
-    // the world.
-    //
-    println!("cargo:rustc-link-search=native={}", dst.display());
-    #[cfg(not(target_os = "windows"))]
-    println!("cargo:rustc-link-lib=static={}", clib_versioned_name);
-    #[cfg(target_os = "windows")]
-    println!("cargo:rustc-link-lib=dylib={}", clib_shared_versioned_name);
+/// Compile the C++ in `config` as C++`standard`, e.g. 17.
+///
+fn set_cxx_standard(config: &mut cmake::Config, standard: u32) {
+    config.define("CMAKE_CXX_STANDARD", standard.to_string());
+    config.define("CMAKE_CXX_STANDARD_REQUIRED", "ON");
+}
 
-    if build_libraries {
-        // Link against the stuff what we built
-        println!("cargo:rustc-link-search=native={}", lib_path.display());
-        // we don't actually want to link against anything in /bin but we 
-        // need to tell rustc where the DLLs are on windows and this is the 
-        // way to do it
-        println!("cargo:rustc-link-search=native={}", bin_path.display());
-    }
+/// Warnings about `dependencies` that set their own `CMAKE_CXX_STANDARD`:
+/// any that differ from `cxx_standard` if it's set, since that overrides
+/// them, or any that disagree with each other if it isn't.
+///
+fn cxx_standard_warnings(
+    dependencies: &[DependencyOwned],
+    cxx_standard: Option<u32>,
+) -> Vec<String> {
+    let declared: Vec<(&str, &str)> = dependencies
+        .iter()
+        .filter_map(|d| {
+            d.definitions
+                .iter()
+                .find(|(k, _)| k == "CMAKE_CXX_STANDARD")
+                .map(|(_, v)| (d.name.as_str(), v.as_str()))
+        })
+        .collect();
 
-    for arg in link_args {
-        // Link against all our dependencies
-        match arg {
-            LinkArg::Path(d) => {
-                let libdir = Path::new(&d.path).parent().unwrap();
-                println!("cargo:rustc-link-search=native={}", libdir.display());
-                println!("cargo:rustc-link-lib=dylib={}", &d.libname);
-            }
-            LinkArg::LinkDir(dir) => {
-                println!("cargo:rustc-link-search=native={}", dir);
-            }
-            LinkArg::LinkLib(lib) => {
-                println!("cargo:rustc-link-lib=dylib={}", lib);
-            }
+    match cxx_standard {
+        Some(standard) => declared
+            .iter()
+            .filter(|(_, v)| v.trim() != standard.to_string())
+            .map(|(name, v)| {
+                format!(
+                    "{} sets CMAKE_CXX_STANDARD={} but is being built as \
+                     C++{}",
+                    name, v, standard
+                )
+            })
+            .collect(),
+        None if declared.iter().any(|(_, v)| *v != declared[0].1) => {
+            let list: Vec<String> = declared
+                .iter()
+                .map(|(name, v)| format!("{}={}", name, v))
+                .collect();
+            vec![format!(
+                "Dependencies set different CMAKE_CXX_STANDARDs ({}), which \
+                 can make them ABI incompatible. Use Builder::cxx_standard \
+                 to build them all with one.",
+                list.join(", ")
+            )]
         }
+        None => Vec::new(),
     }
+}
 
-    // On unices we need to link against the stdlib
-    #[cfg(target_os = "linux")]
-    println!("cargo:rustc-link-lib=dylib=stdc++");
-    #[cfg(target_os = "macos")]
-    println!("cargo:rustc-link-lib=dylib=c++");
-
-    // Insert the C++ ABI info
-    //
-    // abigen is a small binary that's autogenerated by cppmm. It simply outputs
-    // the size of all opaquebytes types to a file, `abigen.txt`. Meanwhile, 
-    // cppmm sets up both the C and Rust layer source with placeholder markers
-    // that are replaced by the Python script `insert_abi.py`, below. 
-    //
-    // We do this because certain types (STL mainly) are different sizes between
-    // platforms (and even between build types on Windows!), and generating 
-    // their ABI info at build time here saves us from having to run the entire
-    // binding generation at the crate build level, and thus keeps a libclang 
-    // dependency out of all our end-user crates.
-    //
-    let build_dir = Path::new(&out_dir).join("build");
-    let abigen_bin = build_dir.join("abigen").join("abigen");
-    let abigen_txt = build_dir.join("abigen.txt");
-
-    // Run abigen again if the output doesn't exist.
-    if !abigen_txt.exists() {
-        let _ = std::process::Command::new(abigen_bin)
-            .current_dir(build_dir)
-            .output()
-            .expect("Could not run abigen");
+/// Choose the macOS deployment target from the one `configured` with
+/// [`Builder::macos_deployment_target`] and the value of the
+/// `MACOSX_DEPLOYMENT_TARGET` environment variable, which wins since the Rust
+/// toolchain uses it too. Also returns a warning if the two disagree.
+///
+fn macos_deployment_target(
+    configured: Option<&str>,
+    env: Option<&str>,
+) -> (Option<String>, Option<String>) {
+    match (configured, env.map(str::trim).filter(|e| !e.is_empty())) {
+        (Some(configured), Some(env)) if configured != env => (
+            Some(env.to_string()),
+            Some(format!(
+                "MACOSX_DEPLOYMENT_TARGET is {} but the build asked for {}, \
+                 using {} so that the C++ and Rust code agree",
+                env, configured, env
+            )),
+        ),
+        (_, Some(env)) => (Some(env.to_string()), None),
+        (configured, None) => (configured.map(str::to_string), None),
     }
+}
 
-    let cppmm_abi_out = Path::new(&out_dir).join("cppmm_abi_out").join("cppmmabi.rs");
+/// The CMake the cmake crate runs: `CMAKE` if that's set, otherwise `cmake`
+/// from the `PATH`.
+///
+fn cmake_program() -> std::ffi::OsString {
+    std::env::var_os("CMAKE").unwrap_or_else(|| "cmake".into())
+}
+
+/// Run `cmake --version` and return the version it reports, e.g. `3.27.4`.
+///
+fn cmake_version(cmake: &std::ffi::OsStr) -> Result<String, BuildError> {
+    let output = std::process::Command::new(cmake)
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .ok_or_else(|| {
+            BuildError::MissingTool(cmake.to_string_lossy().into_owned())
+        })?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first_line = stdout.lines().next().unwrap_or_default();
+    Ok(first_line
+        .strip_prefix("cmake version ")
+        .unwrap_or(first_line)
+        .trim()
+        .to_string())
+}
+
+/// The warnings in CMake's configure output, one line each: the header,
+/// e.g. `CMake Deprecation Warning at CMakeLists.txt:1
+/// (cmake_minimum_required):`, followed by the indented message, with
+/// repeats left out.
+///
+fn cmake_warnings(output: &str) -> Vec<String> {
+    let mut warnings: Vec<String> = Vec::new();
+    let mut lines = output.lines().peekable();
+    while let Some(line) = lines.next() {
+        if !line.starts_with("CMake Warning")
+            && !line.starts_with("CMake Deprecation Warning")
+        {
+            continue;
+        }
+        let mut warning = line.trim_end().to_string();
+        while let Some(body) =
+            lines.next_if(|l| l.is_empty() || l.starts_with(' '))
+        {
+            // The message ends at the first line that isn't indented, but
+            // may have blank lines of its own
+            if body.is_empty()
+                && !lines.peek().map_or(false, |l| l.starts_with(' '))
+            {
+                break;
+            }
+            if !body.trim().is_empty() {
+                warning.push(' ');
+                warning.push_str(body.trim());
+            }
+        }
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+    warnings
+}
+
+/// Configure the CMake project in `build_dir` again from its cache, to pass
+/// on the warnings CMake printed as Cargo warnings, prefixed with `name`.
+/// Cargo only shows a build script's output when it fails, so these would
+/// otherwise go unseen, though they're often the first sign of a CMake
+/// policy change that will break the build.
+///
+/// The cmake crate doesn't give us the output of its own configure, hence
+/// the second one. That's quicker since everything it found is cached, but
+/// still adds to every build, so this is only done in verbose mode. The full
+/// output is printed as warnings too under `CPPMM_DEBUG_BUILD`.
+///
+fn forward_cmake_warnings(name: &str, build_dir: &Path) {
+    let output = match std::process::Command::new(cmake_program())
+        .arg(build_dir)
+        .current_dir(build_dir)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            println!(
+                "cargo:warning=Could not run CMake for the warnings from {}: \
+                 {}",
+                name, e
+            );
+            return;
+        }
+    };
+    // CMake prints its warnings to stderr, and everything else to stdout
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+        for line in stdout.lines().chain(stderr.lines()) {
+            println!("cargo:warning={}", line);
+        }
+    }
+    for warning in cmake_warnings(&stderr) {
+        println!("cargo:warning={}: {}", name, warning);
+    }
+}
+
+/// Parse the environment variable `var` if it's set, rather than silently
+/// ignoring a value we don't understand.
+///
+fn parse_env<T: std::str::FromStr>(var: &str) -> Result<Option<T>, BuildError> {
+    match std::env::var(var) {
+        Ok(value) => {
+            value.parse().map(Some).map_err(|_| BuildError::InvalidEnv {
+                var: var.to_string(),
+                value,
+            })
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Check that CMake can be run before we start on anything that needs it,
+/// since otherwise the cmake crate fails with a panic that doesn't say much.
+///
+fn check_cmake() -> Result<(), BuildError> {
+    let cmake = cmake_program();
+    match cmake_version(&cmake) {
+        Ok(version) => {
+            if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+                println!("cargo:warning=Using CMake {}", version);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            let install = if cfg!(target_os = "windows") {
+                "install it with the Visual Studio C++ workload, `winget \
+                 install Kitware.CMake` or from https://cmake.org/download/"
+            } else if cfg!(target_os = "macos") {
+                "install it with `brew install cmake` or from \
+                 https://cmake.org/download/"
+            } else {
+                "install it with your package manager, e.g. `apt install \
+                 cmake` or `dnf install cmake`"
+            };
+            println!(
+                "cargo:warning=CMake is needed to build the C library and its \
+                 dependencies but {} could not be run. Please {}, or set \
+                 CMAKE to the cmake to use.",
+                cmake.to_string_lossy(),
+                install
+            );
+            Err(e)
+        }
+    }
+}
+
+/// The CMake policies set for every packaged dependency.
+///
+/// On Windows that's CMP0091, so that the MSVC runtime library is chosen by
+/// `CMAKE_MSVC_RUNTIME_LIBRARY` for every dependency alike, rather than by
+/// flags that some projects set themselves depending on their minimum CMake
+/// version.
+///
+fn default_policies() -> Vec<(String, String)> {
+    if cfg!(target_os = "windows") {
+        vec![("CMP0091".to_string(), "NEW".to_string())]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Pass extra flags to the C and C++ compilers for `config`.
+///
+fn add_compiler_flags(
+    config: &mut cmake::Config,
+    cflags: &[String],
+    cxxflags: &[String],
+) {
+    for flag in cflags {
+        config.cflag(flag);
+    }
+    for flag in cxxflags {
+        config.cxxflag(flag);
+    }
+}
+
+/// The compiler flags environment variables that are set, which the cmake
+/// crate passes on to every build, following the `cc` crate's conventions:
+/// `CFLAGS`, `CFLAGS_<target>`, `CFLAGS_<target_with_underscores>` and
+/// `TARGET_CFLAGS`, and the same for `CXXFLAGS`.
+///
+fn compiler_flags_from_env() -> Vec<(String, String)> {
+    let target = std::env::var("TARGET").unwrap_or_default();
+    let mut flags = Vec::new();
+    for var in &["CFLAGS", "CXXFLAGS"] {
+        let names = [
+            var.to_string(),
+            format!("{}_{}", var, target),
+            format!("{}_{}", var, target.replace('-', "_")),
+            format!("TARGET_{}", var),
+        ];
+        for name in &names {
+            if let Ok(value) = std::env::var(name) {
+                if !flags.iter().any(|(k, _)| k == name) {
+                    flags.push((name.clone(), value));
+                }
+            }
+        }
+    }
+    flags
+}
+
+/// Returns true if the dependency built in `out_dir` was installed and all
+/// the files CMake installed for it are still present.
+///
+fn is_installed(out_dir: &Path) -> bool {
+    let manifest = match std::fs::read_to_string(
+        out_dir.join("build").join("install_manifest.txt"),
+    ) {
+        Ok(manifest) => manifest,
+        Err(_) => return false,
+    };
+
+    let mut files = manifest.lines().filter(|l| !l.is_empty()).peekable();
+    files.peek().is_some() && files.all(|f| Path::new(f).exists())
+}
+
+/// The static archives CMake installed for the dependency built in
+/// `out_dir`, as listed in its install manifest.
+///
+fn installed_archives(out_dir: &Path) -> Vec<LinkArg> {
+    std::fs::read_to_string(out_dir.join("build").join("install_manifest.txt"))
+        .unwrap_or_default()
+        .lines()
+        .filter(|f| f.ends_with(".a"))
+        .filter_map(parse_dylib_path)
+        .map(LinkArg::Path)
+        .collect()
+}
+
+/// The libraries CMake installed for the dependency built in `out_dir`, as
+/// listed in its install manifest.
+///
+fn installed_libraries(out_dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_to_string(out_dir.join("build").join("install_manifest.txt"))
+        .unwrap_or_default()
+        .lines()
+        .filter(|f| parse_dylib_path(f).is_some())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Returns true if any file under `dir` was modified after `stamp`, or if
+/// `stamp` doesn't exist. Version control directories are skipped.
+///
+fn sources_newer_than(dir: &Path, stamp: &Path) -> bool {
+    let stamp_time = match std::fs::metadata(stamp).and_then(|m| m.modified()) {
+        Ok(t) => t,
+        Err(_) => return true,
+    };
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let file_type = match entry.file_type() {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            if file_type.is_dir() {
+                if entry.file_name() != ".git" {
+                    dirs.push(entry.path());
+                }
+            } else if entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .map_or(false, |t| t > stamp_time)
+            {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// The text a panic was raised with, or an empty string if it wasn't a
+/// string.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else {
+        ""
+    }
+}
+
+/// Whether a build in `build_dir` that failed is worth trying again, given
+/// that its build system was removed with [`remove_build_system`] first.
+///
+/// The cmake crate doesn't tell us whether configuring or building failed,
+/// so we go by whether CMake got as far as generating the build system:
+/// if not, it was most likely a download in the configure step (e.g.
+/// `FetchContent`) that can succeed next time, whereas an error after that
+/// is a genuine compile error that will fail the same way again. So will a
+/// configure that failed because a package wasn't found, which CMake records
+/// as `-NOTFOUND` in the cache. CMake not being installed has already been
+/// caught by [`check_cmake`].
+///
+fn is_transient_failure(build_dir: &Path) -> bool {
+    let missing_package =
+        std::fs::read_to_string(build_dir.join("CMakeCache.txt"))
+            .map_or(false, |cache| cache.contains("-NOTFOUND"));
+    build_system_files(build_dir).is_empty() && !missing_package
+}
+
+/// The build system CMake generated in `build_dir`: its `Makefile`,
+/// `build.ninja` or Visual Studio solution.
+fn build_system_files(build_dir: &Path) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(build_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            matches!(
+                path.file_name().and_then(|n| n.to_str()),
+                Some("Makefile" | "build.ninja")
+            ) || path.extension().map_or(false, |e| e == "sln")
+        })
+        .collect()
+}
+
+/// Whether the process `pid` that left a `.cppmm-building` marker is another
+/// one that's still running.
+///
+fn is_building_elsewhere(pid: &str) -> bool {
+    match pid.parse::<u32>() {
+        Ok(pid) => pid != std::process::id() && is_running(pid),
+        Err(_) => false,
+    }
+}
+
+/// Whether a process with the id `pid` is running.
+///
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Whether a process with the id `pid` is running.
+///
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_running(pid: u32) -> bool {
+    std::process::Command::new("ps")
+        .args(["-p", &pid.to_string()])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map_or(false, |status| status.success())
+}
+
+/// Whether a process with the id `pid` is running.
+///
+#[cfg(windows)]
+fn is_running(pid: u32) -> bool {
+    std::process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/FO", "CSV", "/NH"])
+        .output()
+        .map_or(false, |output| {
+            String::from_utf8_lossy(&output.stdout)
+                .contains(&format!("\"{}\"", pid))
+        })
+}
+
+/// Remove the build system from `build_dir`, for the next configure to
+/// generate again.
+fn remove_build_system(build_dir: &Path) {
+    for path in build_system_files(build_dir) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// How long to wait before retry number `attempt` (counting from 1).
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(2 * u64::from(attempt))
+}
+
+/// Configure and build `config`, limiting the build to `jobs` parallel jobs
+/// if specified, otherwise using whatever Cargo gave us.
+///
+fn build_with_jobs(config: &mut cmake::Config, jobs: Option<usize>) -> PathBuf {
+    match jobs {
+        Some(jobs) => {
+            let _jobs = JobsOverride::new(jobs);
+            config.build()
+        }
+        None => config.build(),
+    }
+}
+
+/// Temporarily overrides the job count the cmake crate passes to
+/// `cmake --build`, restoring the previous environment when dropped.
+///
+/// The cmake crate reads `NUM_JOBS` straight from the process environment
+/// rather than from the `Config`, and when `CARGO_MAKEFLAGS` is set it hands
+/// Cargo's jobserver to make instead, which would ignore our limit entirely,
+/// so we have to hide that for the duration of the build too.
+///
+struct JobsOverride {
+    num_jobs: Option<std::ffi::OsString>,
+    cargo_makeflags: Option<std::ffi::OsString>,
+}
+
+impl JobsOverride {
+    fn new(jobs: usize) -> JobsOverride {
+        let guard = JobsOverride {
+            num_jobs: std::env::var_os("NUM_JOBS"),
+            cargo_makeflags: std::env::var_os("CARGO_MAKEFLAGS"),
+        };
+        std::env::set_var("NUM_JOBS", jobs.max(1).to_string());
+        std::env::remove_var("CARGO_MAKEFLAGS");
+        guard
+    }
+}
+
+impl Drop for JobsOverride {
+    fn drop(&mut self) {
+        match &self.num_jobs {
+            Some(v) => std::env::set_var("NUM_JOBS", v),
+            None => std::env::remove_var("NUM_JOBS"),
+        }
+        if let Some(v) = &self.cargo_makeflags {
+            std::env::set_var("CARGO_MAKEFLAGS", v);
+        }
+    }
+}
+
+/// Sets `VERBOSE` for `cmake --build` to read as if it was passed
+/// `--verbose`, which the cmake crate has no way to do, restoring the
+/// previous environment when dropped so it doesn't leak into whatever the
+/// build script runs next.
+///
+struct VerboseOverride {
+    verbose: Option<std::ffi::OsString>,
+}
+
+impl VerboseOverride {
+    fn new() -> VerboseOverride {
+        let guard = VerboseOverride {
+            verbose: std::env::var_os("VERBOSE"),
+        };
+        std::env::set_var("VERBOSE", "1");
+        guard
+    }
+}
+
+impl Drop for VerboseOverride {
+    fn drop(&mut self) {
+        match &self.verbose {
+            Some(v) => std::env::set_var("VERBOSE", v),
+            None => std::env::remove_var("VERBOSE"),
+        }
+    }
+}
+
+/// Work out how many parallel jobs to use for a dependency with the given
+/// `max_jobs` cap, when the global job count is `build_jobs`.
+///
+/// If no global job count was requested we cap Cargo's own `NUM_JOBS`
+/// instead, so `max_jobs` can only ever lower the parallelism.
+///
+fn dependency_jobs(
+    max_jobs: Option<usize>,
+    build_jobs: Option<usize>,
+) -> Option<usize> {
+    let build_jobs = build_jobs.or_else(|| {
+        std::env::var("NUM_JOBS").ok().and_then(|j| j.parse().ok())
+    });
+    match (max_jobs, build_jobs) {
+        (Some(max_jobs), Some(build_jobs)) => Some(max_jobs.min(build_jobs)),
+        (Some(max_jobs), None) => Some(max_jobs),
+        (None, build_jobs) => build_jobs,
+    }
+}
+
+/// Path information for a linked library.
+///
+/// `basename` is the file name of `path` and `libname` is the name the
+/// library is linked by, as you'd pass it to `-l` (see [`link_name`]). For
+/// example:
+///
+/// | `path`                     | `basename`          | `libname`   |
+/// |----------------------------|---------------------|-------------|
+/// | `/home/libs/libmylib.so.3` | `libmylib.so.3`     | `mylib`     |
+/// | `/home/libs/libmylib.dylib` | `libmylib.dylib`    | `mylib`     |
+/// | `C:\libs\mylib-3_1.lib`    | `mylib-3_1.lib`     | `mylib-3_1` |
+///
+/// On Windows, `dll` is the file name of the DLL an import library loads,
+/// which is what has to be found at runtime. This is read from the import
+/// library, since it needn't match `libname`: `foo_md.lib` can be the import
+/// library for `foo.dll`. It's `None` if that couldn't be read, e.g. for a
+/// static library, and on other platforms.
+///
+/// More fields may be added, so these are made with [`parse_dylib_path`]
+/// rather than constructed directly.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DylibPathInfo {
+    pub path: String,
+    pub basename: String,
+    pub libname: String,
+    pub dll: Option<String>,
+}
+
+/// Matches the paths of shared libraries and static archives on Unices,
+/// capturing the link name.
+#[cfg(not(target_os = "windows"))]
+const DYLIB_RE: &str = r"lib([^/]+?)(?:\.dylib|\.so(?:.\d+){0,3}|\.a)$";
+
+/// Matches the paths of import libraries on Windows, capturing the link name.
+/// Only paths are matched (with either separator), as bare names like
+/// `kernel32.lib` are system libraries that the linker finds by itself. The
+/// extension is matched ignoring case, as the filesystem does, since some
+/// SDKs ship libraries like `OpenGL32.Lib`.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+const DLL_LIB_RE: &str = r"(?i)^.*[\\/]([^\\/]+)\.lib$";
+
+/// Matches library paths and names on Windows for [`link_name`]: import
+/// libraries and DLLs, with or without a directory.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+const WINDOWS_LINK_NAME_RE: &str = r"(?i)(?:^|[\\/])([^\\/]+)\.(?:lib|dll)$";
+
+/// The name to link the library at `path` by, as you'd pass it to
+/// `rustc-link-lib` or `-l`, using the naming conventions of the current
+/// platform. Returns `None` if `path` doesn't look like a library.
+///
+/// | Platform | `path`                  | Link name |
+/// |----------|-------------------------|-----------|
+/// | Unices   | `/usr/lib/libFoo.so`    | `Foo`     |
+/// | Unices   | `/usr/lib/libFoo.so.3`  | `Foo`     |
+/// | macOS    | `/usr/lib/libFoo.dylib` | `Foo`     |
+/// | Unices   | `/usr/lib/libFoo.a`     | `Foo`     |
+/// | Windows  | `C:\libs\Foo.lib`       | `Foo`     |
+/// | Windows  | `C:\bin\Foo.dll`        | `Foo`     |
+///
+/// On Windows a DLL is linked through the import library of the same name,
+/// which is the name given here.
+///
+pub fn link_name(path: &str) -> Option<String> {
+    #[cfg(not(target_os = "windows"))]
+    let re = Regex::new(DYLIB_RE).unwrap();
+    #[cfg(target_os = "windows")]
+    let re = Regex::new(WINDOWS_LINK_NAME_RE).unwrap();
+    link_name_with(path, &re).map(str::to_string)
+}
+
+/// The link name captured from `path` by `re`, one of `DYLIB_RE`,
+/// `DLL_LIB_RE` or `WINDOWS_LINK_NAME_RE`.
+///
+fn link_name_with<'a>(path: &'a str, re: &Regex) -> Option<&'a str> {
+    Some(re.captures(path)?.get(1)?.as_str())
+}
+
+/// Parse a library path into its [`DylibPathInfo`], using the naming
+/// conventions of the current platform: `.so`s, `.dylib`s and `.a` static
+/// archives on Unices and `.lib`s on Windows. Returns `None` if `path`
+/// doesn't look like a library.
+///
+pub fn parse_dylib_path(path: &str) -> Option<DylibPathInfo> {
+    #[cfg(not(target_os = "windows"))]
+    let re = Regex::new(DYLIB_RE).unwrap();
+    #[cfg(target_os = "windows")]
+    let re = Regex::new(DLL_LIB_RE).unwrap();
+    #[cfg(target_os = "windows")]
+    return dll_lib_path_info(path, &re);
+    #[cfg(not(target_os = "windows"))]
+    dylib_path_info(path, &re, UNIX_SEPARATORS)
+}
+
+/// The path separators on Unices.
+#[cfg(not(target_os = "windows"))]
+const UNIX_SEPARATORS: &[char] = &['/'];
+
+/// The path separators on Windows, where paths from CMake can have either.
+const WINDOWS_SEPARATORS: &[char] = &['/', '\\'];
+
+/// [`parse_dylib_path`] with an already compiled `DYLIB_RE` or `DLL_LIB_RE`
+/// and the path `separators` to go with it.
+///
+fn dylib_path_info(
+    path: &str,
+    re: &Regex,
+    separators: &[char],
+) -> Option<DylibPathInfo> {
+    let libname = link_name_with(path, re)?;
+    let basename = path.rsplit(separators).next().unwrap_or(path);
+
+    Some(DylibPathInfo {
+        path: path.to_string(),
+        basename: basename.to_string(),
+        libname: libname.to_string(),
+        dll: None,
+    })
+}
+
+/// [`dylib_path_info`] for a Windows library, with the DLL read from the
+/// import library at `path` if there is one.
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn dll_lib_path_info(path: &str, re: &Regex) -> Option<DylibPathInfo> {
+    let mut info = dylib_path_info(path, re, WINDOWS_SEPARATORS)?;
+    info.dll = std::fs::File::open(path).ok().and_then(import_lib_dll);
+    Some(info)
+}
+
+/// The DLL the import library `lib` loads, e.g. `foo.dll`, or `None` if it
+/// isn't an import library.
+///
+/// Import libraries are `ar` archives, whose members for each imported
+/// symbol start with an import header, followed by the symbol's name and
+/// then the DLL's, as nul-terminated strings. We take the first one, since
+/// all of them name the same DLL.
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn import_lib_dll(
+    mut lib: impl std::io::Read + std::io::Seek,
+) -> Option<String> {
+    use std::convert::TryFrom;
+    use std::io::Read;
+
+    let mut magic = [0u8; 8];
+    lib.read_exact(&mut magic).ok()?;
+    if &magic != b"!<arch>\n" {
+        return None;
+    }
+
+    let mut header = [0u8; 60];
+    while lib.read_exact(&mut header).is_ok() {
+        let size: u64 = std::str::from_utf8(&header[48..58])
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        // Members are padded to an even size
+        let mut remaining = size + size % 2;
+        // The symbol tables and long names table are named /, // and so on,
+        // whereas a / followed by digits is an offset into the long names
+        let special = header[0] == b'/' && !header[1].is_ascii_digit();
+        if !special && size >= 20 {
+            let mut import = [0u8; 20];
+            lib.read_exact(&mut import).ok()?;
+            remaining -= 20;
+            // The signature, then a version of 0 for the short import
+            // format rather than an object file
+            if import[..6] == [0, 0, 0xff, 0xff, 0, 0] {
+                let size_of_data = u64::from(u32::from_le_bytes([
+                    import[12], import[13], import[14], import[15],
+                ]));
+                // Don't trust a size that doesn't fit in the member
+                if size_of_data > size - 20 {
+                    return None;
+                }
+                let mut data = Vec::new();
+                lib.by_ref()
+                    .take(size_of_data)
+                    .read_to_end(&mut data)
+                    .ok()?;
+                if data.len() as u64 != size_of_data {
+                    return None;
+                }
+                let dll = data.split(|b| *b == 0).nth(1)?;
+                return Some(String::from_utf8_lossy(dll).into_owned())
+                    .filter(|dll| !dll.is_empty());
+            }
+        }
+        lib.seek(std::io::SeekFrom::Current(i64::try_from(remaining).ok()?))
+            .ok()?;
+    }
+    None
+}
+
+/// The file name of the DLL for the import library `lib`: the one it loads
+/// if we could read that, otherwise the one named after it.
+///
+#[cfg(target_os = "windows")]
+fn dll_file_name(lib: &DylibPathInfo) -> String {
+    lib.dll
+        .clone()
+        .unwrap_or_else(|| format!("{}.dll", lib.libname))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LinkArg {
+    LinkDir(String),
+    /// A library linked by name. This may start with a `rustc-link-lib` kind,
+    /// as in `static=name`, to link it as something other than a dylib.
+    LinkLib(String),
+    Path(DylibPathInfo),
+    /// An object file (`.o` or `.obj`) given directly on the link line.
+    ObjectFile(String),
+    /// An include directory (`-isystem` or `-iquote`) that leaked onto the
+    /// link line. These link nothing, but are passed on to dependent crates
+    /// with the other include directories.
+    IncludeDir(String),
+}
+
+/// The framework a [`LinkArg::LinkLib`] links, if it's of the `framework`
+/// kind, e.g. `Metal` for `framework=Metal`.
+///
+fn framework_name(lib: &str) -> Option<&str> {
+    let (kind, name) = lib.rsplit_once('=')?;
+    Some(name).filter(|_| kind.split(':').next() == Some("framework"))
+}
+
+/// The `rustc-link-lib` directive for a [`LinkArg::LinkLib`].
+///
+fn link_lib_directive(lib: &str) -> String {
+    if lib.contains('=') {
+        format!("cargo:rustc-link-lib={}", lib)
+    } else {
+        format!("cargo:rustc-link-lib=dylib={}", lib)
+    }
+}
+
+/// The `rustc-link-arg` values that link the libraries in `args` again as a
+/// single linker group, so the linker searches them until nothing new is
+/// resolved. Search directories and object files are left out, as they're
+/// already on the link line.
+///
+#[cfg_attr(any(target_os = "windows", target_os = "macos"), allow(dead_code))]
+fn link_group_args(args: &[LinkArg]) -> Vec<String> {
+    let mut group = vec!["-Wl,--start-group".to_string()];
+    for arg in args {
+        match arg {
+            LinkArg::Path(d) => group.push(d.path.clone()),
+            LinkArg::LinkLib(lib) => {
+                // drop any kind and modifiers, e.g. `static:+verbatim=`
+                let (kind, name) = match lib.rfind('=') {
+                    Some(i) => (&lib[..i], &lib[i + 1..]),
+                    None => ("", lib.as_str()),
+                };
+                if kind != "framework" {
+                    group.push(format!("-l{}", name));
+                }
+            }
+            LinkArg::LinkDir(_)
+            | LinkArg::IncludeDir(_)
+            | LinkArg::ObjectFile(_) => (),
+        }
+    }
+    group.push("-Wl,--end-group".to_string());
+    group
+}
+
+/// Returns true if `s` is the path of an object file.
+///
+fn is_object_file(s: &str) -> bool {
+    let lower = s.to_ascii_lowercase();
+    lower.ends_with(".o") || lower.ends_with(".obj")
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_dylib_path(s: &str, re: &Regex) -> Option<LinkArg> {
+    if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+        println!("cargo:warning=- {}", s);
+    }
+
+    if let Some(lib) = s.strip_prefix("-l") {
+        return Some(LinkArg::LinkLib(lib.to_string()));
+    } else if let Some(dir) = s.strip_prefix("-L") {
+        if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+            println!("cargo:warning=    is a link dir {}", s);
+        }
+        return Some(LinkArg::LinkDir(dir.to_string()));
+    } else if is_object_file(s) {
+        if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+            println!("cargo:warning=    is an object file {}", s);
+        }
+        return Some(LinkArg::ObjectFile(s.to_string()));
+    } else if let Some(info) = dylib_path_info(s, re, UNIX_SEPARATORS) {
+        if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+            println!("cargo:warning=    is a dylib path {}", s);
+        }
+        return Some(LinkArg::Path(info));
+    }
+    if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+        println!("cargo:warning=    is not a dylib path");
+    }
+
+    None
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn is_dll_lib_path(s: &str, re: &Regex) -> Option<LinkArg> {
+    // Paths with spaces in come quoted from CMake
+    let s = s.trim().trim_matches('"');
+    if is_object_file(s) {
+        return Some(LinkArg::ObjectFile(s.to_string()));
+    }
+    dll_lib_path_info(s, re).map(LinkArg::Path)
+}
+
+/// Split a command line into its arguments the way Windows programs do,
+/// which is how CMake quotes paths with spaces in for Ninja and NMake:
+/// double quotes group words together, and backslashes are only special
+/// right before a `"`, where each pair is one backslash and an odd one out
+/// escapes the quote.
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn split_windows_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // whether there's a word at all, since `""` is an empty one
+    let mut in_word = false;
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let mut backslashes = 1;
+                while chars.peek() == Some(&'\\') {
+                    chars.next();
+                    backslashes += 1;
+                }
+                in_word = true;
+                if chars.peek() == Some(&'"') {
+                    word.extend(std::iter::repeat('\\').take(backslashes / 2));
+                    if backslashes % 2 == 1 {
+                        chars.next();
+                        word.push('"');
+                    }
+                } else {
+                    word.extend(std::iter::repeat('\\').take(backslashes));
+                }
+            }
+            '"' => {
+                in_word = true;
+                quoted = !quoted;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// Check each of `tokens` with [`is_dll_lib_path`], adding the non-empty ones
+/// that aren't link arguments to `unclassified`.
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn dll_lib_args<'a>(
+    tokens: impl Iterator<Item = &'a str>,
+    re: &Regex,
+    unclassified: &mut Vec<String>,
+) -> Vec<LinkArg> {
+    let mut args = Vec::new();
+    for tok in tokens {
+        match is_dll_lib_path(tok, re) {
+            Some(arg) => args.push(arg),
+            None if !tok.trim().is_empty() => {
+                unclassified.push(tok.trim().to_string())
+            }
+            None => (),
+        }
+    }
+    args
+}
+
+#[cfg(target_os = "windows")]
+fn get_linking_from_vsproj(
+    build_path: &Path,
+    clib_versioned_name: &str,
+    build_type: &str,
+    unclassified: &mut Vec<String>,
+) -> Option<Vec<LinkArg>> {
+    let proj_path = build_path.join(format!("{}.vcxproj", clib_versioned_name));
+    let proj_xml = std::fs::read_to_string(&proj_path).ok()?;
+
+    vsproj_link_libs(&proj_xml, build_type, unclassified)
+}
+
+/// Find the `.lib`s passed to the linker for the `configuration` in the
+/// contents of a `.vcxproj`, or to the librarian (`Lib`) for a static
+/// library target.
+///
+/// `configuration` is either `Configuration|Platform`, e.g. `Release|x64`,
+/// or just the configuration to match it on every platform. Every
+/// `ItemDefinitionGroup` that matches is checked, and the libraries from all
+/// of them are returned in order. Returns `None` if there are no linker
+/// settings for the configuration at all, or if the project can't be parsed,
+/// so that the other build files are tried instead.
+///
+/// Anything else in the libraries is added to `unclassified`.
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn vsproj_link_libs(
+    proj_xml: &str,
+    configuration: &str,
+    unclassified: &mut Vec<String>,
+) -> Option<Vec<LinkArg>> {
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+    use std::borrow::Borrow;
+
+    let re = Regex::new(DLL_LIB_RE).unwrap();
+
+    let mut reader = Reader::from_str(proj_xml);
+    reader.trim_text(true);
+
+    let mut in_item_definition = false;
+    // in either Link or Lib
+    let mut in_link = false;
+    let mut in_deps = false;
+
+    let mut dlls: Option<Vec<LinkArg>> = None;
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => match e.name() {
+                b"ItemDefinitionGroup" => {
+                    in_item_definition = e
+                        .attributes()
+                        .flatten()
+                        .filter(|attr| attr.key == b"Condition")
+                        .any(|attr| {
+                            let s = std::str::from_utf8(attr.value.borrow())
+                                .unwrap_or("");
+                            vsproj_condition_matches(s, configuration)
+                        });
+                }
+                b"Link" | b"Lib" if in_item_definition => {
+                    in_link = true;
+                }
+                b"AdditionalDependencies" if in_item_definition && in_link => {
+                    in_deps = true;
+                }
+                _ => (),
+            },
+            Ok(Event::End(ref e)) => match e.name() {
+                b"ItemDefinitionGroup" => {
+                    in_item_definition = false;
+                }
+                b"Link" | b"Lib" => {
+                    in_link = false;
+                }
+                b"AdditionalDependencies" => in_deps = false,
+                _ => (),
+            },
+            Ok(Event::Text(e)) if in_deps => {
+                let dlls = dlls.get_or_insert_with(Vec::new);
+                let text = match e.unescape_and_decode(&reader) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        println!(
+                            "cargo:warning=Could not read the libraries in \
+                             vsproj xml at position {}: {}",
+                            reader.buffer_position(),
+                            e
+                        );
+                        return None;
+                    }
+                };
+                for dll in dll_lib_args(text.split(';'), &re, unclassified) {
+                    if !dlls.contains(&dll) {
+                        dlls.push(dll);
+                    }
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                println!(
+                    "cargo:warning=Error parsing vsproj xml at position {}: {}",
+                    reader.buffer_position(),
+                    e
+                );
+                return None;
+            }
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    dlls
+}
+
+/// Returns true if an MSBuild condition of the form
+/// `'$(Configuration)|$(Platform)'=='Release|x64'` selects `configuration`,
+/// as described for [`vsproj_link_libs`].
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn vsproj_condition_matches(condition: &str, configuration: &str) -> bool {
+    let (lhs, rhs) = match condition.split_once("==") {
+        Some(sides) => sides,
+        None => return false,
+    };
+    let keys = lhs.trim().trim_matches('\'').split('|');
+    let values = rhs.trim().trim_matches('\'').split('|');
+    let value = |key: &str| {
+        keys.clone()
+            .zip(values.clone())
+            .find(|(k, _)| k.trim() == key)
+            .map(|(_, v)| v.trim())
+    };
+
+    let mut wanted = configuration.split('|');
+    let wanted_config = wanted.next().unwrap_or("");
+    let config_matches = value("$(Configuration)")
+        .map_or(false, |c| c.eq_ignore_ascii_case(wanted_config));
+    let platform_matches = match wanted.next() {
+        Some(platform) => value("$(Platform)")
+            .map_or(false, |p| p.eq_ignore_ascii_case(platform)),
+        None => true,
+    };
+    config_matches && platform_matches
+}
+
+/// The Visual Studio platform for the target triple `target`, e.g.
+/// `aarch64-pc-windows-msvc` gives `ARM64`. This is what the cmake crate
+/// passes to the Visual Studio generators with `-A`.
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn vs_platform(target: &str) -> &str {
+    let arch = target.split('-').next().unwrap_or(target);
+    match arch {
+        "x86_64" => "x64",
+        "i586" | "i686" | "x86" => "Win32",
+        "aarch64" | "arm64ec" => "ARM64",
+        arch if arch.starts_with("thumbv7") || arch.starts_with("arm") => "ARM",
+        arch => arch,
+    }
+}
+
+/// Turn `build_type` into the `Configuration|Platform` form the Visual Studio
+/// generators use for the target triple `target`, e.g. `release` and
+/// `x86_64-pc-windows-msvc` give `Release|x64`.
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn vsproj_configuration(build_type: &str, target: &str) -> String {
+    // CMake's standard configurations, spelled as it does
+    let configuration = ["Debug", "Release", "RelWithDebInfo", "MinSizeRel"]
+        .iter()
+        .find(|c| c.eq_ignore_ascii_case(build_type))
+        .copied()
+        .unwrap_or(build_type);
+    format!("{}|{}", configuration, vs_platform(target))
+}
+
+#[cfg(target_os = "windows")]
+fn get_linking_from_nmake(
+    build_path: &Path,
+    clib_versioned_name: &str,
+    unclassified: &mut Vec<String>,
+) -> Option<Vec<LinkArg>> {
+    let build_make_path = build_path
+        .join("CMakeFiles")
+        .join(format!("{}-shared.dir", clib_versioned_name))
+        .join("build.make");
+
+    let build_make = std::fs::read_to_string(&build_make_path).ok()?;
+
+    Some(nmake_link_libs(&build_make, build_path, unclassified))
+}
+
+/// Find the `.lib`s passed to the linker in an NMake `build.make`.
+///
+/// The link inputs are either written inline in a `@<<` ... `<<` heredoc
+/// following the link command, or into separate `@file.rsp` response files
+/// (relative to `build_path`) when the command line would get too long, or a
+/// mixture of both. Any other arguments are added to `unclassified`.
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn nmake_link_libs(
+    build_make: &str,
+    build_path: &Path,
+    unclassified: &mut Vec<String>,
+) -> Vec<LinkArg> {
+    let re = Regex::new(DLL_LIB_RE).unwrap();
+
+    // Find the link command and gather it into one string, along with its
+    // heredoc if it has one
+    let mut lines = build_make.lines();
+    let mut link_cmd = String::new();
+    for line in lines.by_ref() {
+        if line.contains("vs_link_dll")
+            || line.to_ascii_lowercase().contains("link.exe")
+            || line.split_whitespace().any(|t| t == "/dll")
+        {
+            link_cmd.push_str(line);
+            break;
+        }
+    }
+    if link_cmd.contains("@<<") {
+        for line in lines {
+            if line.trim_start().starts_with("<<") {
+                break;
+            }
+            link_cmd.push(' ');
+            link_cmd.push_str(line);
+        }
+    }
+
+    let mut libs = Vec::new();
+    for tok in split_windows_words(&link_cmd) {
+        match tok.strip_prefix('@') {
+            Some("<<") => (),
+            Some(rsp) => {
+                let rsp_path = build_path.join(rsp);
+                match std::fs::read_to_string(&rsp_path) {
+                    Ok(rsp) => libs.extend(dll_lib_args(
+                        split_windows_words(&rsp).iter().map(String::as_str),
+                        &re,
+                        unclassified,
+                    )),
+                    Err(e) => println!(
+                        "cargo:warning=Could not read response file {}: {}",
+                        rsp_path.display(),
+                        e
+                    ),
+                }
+            }
+            None => libs.extend(dll_lib_args(
+                std::iter::once(tok.as_str()),
+                &re,
+                unclassified,
+            )),
+        }
+    }
+
+    libs
+}
+
+#[cfg(target_os = "windows")]
+fn get_linking_from_ninja(
+    build_path: &Path,
+    clib_versioned_name: &str,
+    unclassified: &mut Vec<String>,
+) -> Option<Vec<LinkArg>> {
+    let build_ninja =
+        std::fs::read_to_string(build_path.join("build.ninja")).ok()?;
+
+    ninja_link_libs(&build_ninja, clib_versioned_name, build_path, unclassified)
+}
+
+/// Find the `.lib`s passed to the linker for the wrapper's shared library in a
+/// Ninja `build.ninja`.
+///
+/// These are normally in the `LINK_LIBRARIES` variable of the link
+/// statement. If that isn't there we fall back to the `RSP_FILE` that the
+/// link is run with, relative to `build_path`, which only exists once the
+/// link has been run at least once. Anything else in them is added to
+/// `unclassified`.
+///
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn ninja_link_libs(
+    build_ninja: &str,
+    clib_versioned_name: &str,
+    build_path: &Path,
+    unclassified: &mut Vec<String>,
+) -> Option<Vec<LinkArg>> {
+    let re = Regex::new(DLL_LIB_RE).unwrap();
+    let rule = format!("LINKER__{}-shared", clib_versioned_name);
+
+    // Find the link statement, then its indented variables
+    let mut lines = build_ninja.lines();
+    lines.find(|l| l.starts_with("build ") && l.contains(&rule))?;
+
+    let mut link_libraries = None;
+    let mut rsp_file = None;
+    for line in lines.take_while(|l| l.starts_with(' ')) {
+        if let Some((key, value)) = line.split_once('=') {
+            match key.trim() {
+                "LINK_LIBRARIES" => link_libraries = Some(value.to_string()),
+                "RSP_FILE" => rsp_file = Some(value.trim().to_string()),
+                _ => (),
+            }
+        }
+    }
+
+    let link_libraries = match (link_libraries, rsp_file) {
+        (Some(libs), _) => libs,
+        (None, Some(rsp)) => {
+            let rsp_path = build_path.join(&rsp);
+            match std::fs::read_to_string(&rsp_path) {
+                Ok(rsp) => rsp,
+                Err(e) => {
+                    println!(
+                        "cargo:warning=Could not read response file {}: {}",
+                        rsp_path.display(),
+                        e
+                    );
+                    return None;
+                }
+            }
+        }
+        (None, None) => return None,
+    };
+
+    Some(dll_lib_args(
+        split_windows_words(&link_libraries)
+            .iter()
+            .map(String::as_str),
+        &re,
+        unclassified,
+    ))
+}
+
+/// Parse the generated project files from our C wrapper in order to get its
+/// set of linker arguments.
+///
+/// On Unices this will parse CMake's auxiliary link.txt file for `.so`s or
+/// `.dylib`s. On Windows this will parse VS XML project files, NMake
+/// makefiles or Ninja build files.
+///
+pub fn get_linking_from_cmake(
+    build_path: &Path,
+    clib_versioned_name: &str,
+    build_type: &str,
+) -> Vec<LinkArg> {
+    get_linking_from_cmake_verbose(build_path, clib_versioned_name, build_type)
+        .args
+}
+
+/// The link arguments found by [`get_linking_from_cmake_verbose`], along with
+/// the tokens of the link line that weren't recognised as any of them.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkLine {
+    pub args: Vec<LinkArg>,
+    /// Compiler flags, system libraries and anything else that was skipped.
+    pub unclassified: Vec<String>,
+}
+
+/// On Windows a missing link line is only warned about, see
+/// [`get_linking_from_cmake_verbose`].
+///
+#[cfg(target_os = "windows")]
+fn link_line(
+    build_path: &Path,
+    clib_versioned_name: &str,
+    build_type: &str,
+) -> Result<LinkLine, BuildError> {
+    Ok(get_linking_from_cmake_verbose(
+        build_path,
+        clib_versioned_name,
+        build_type,
+    ))
+}
+
+/// Like [`get_linking_from_cmake`], but also returning the tokens that
+/// weren't recognised, to help work out why something didn't get linked.
+///
+#[cfg(target_os = "windows")]
+pub fn get_linking_from_cmake_verbose(
+    build_path: &Path,
+    clib_versioned_name: &str,
+    build_type: &str,
+) -> LinkLine {
+    let target = std::env::var("TARGET")
+        .unwrap_or_else(|_| "x86_64-pc-windows-msvc".to_string());
+    let configuration = vsproj_configuration(build_type, &target);
+    let mut unclassified = Vec::new();
+    let args = if let Some(libs) = get_linking_from_vsproj(
+        build_path,
+        clib_versioned_name,
+        &configuration,
+        &mut unclassified,
+    ) {
+        libs
+    } else if let Some(libs) = get_linking_from_nmake(
+        build_path,
+        clib_versioned_name,
+        &mut unclassified,
+    ) {
+        libs
+    } else if let Some(libs) = get_linking_from_ninja(
+        build_path,
+        clib_versioned_name,
+        &mut unclassified,
+    ) {
+        libs
+    } else {
+        // Don't fail the whole build over this: the wrapper itself was built,
+        // so give the user a chance to supply the link line themselves.
+        println!(
+            "cargo:warning=Could not find the link line for {} in {}: tried \
+             {}.vcxproj, {} and build.ninja. Dependencies will not be linked.",
+            clib_versioned_name,
+            build_path.display(),
+            clib_versioned_name,
+            Path::new("CMakeFiles")
+                .join(format!("{}-shared.dir", clib_versioned_name))
+                .join("build.make")
+                .display(),
+        );
+        Vec::new()
+    };
+    LinkLine { args, unclassified }
+}
+
+/// Like [`get_linking_from_cmake`], but also returning the tokens that
+/// weren't recognised, to help work out why something didn't get linked.
+///
+#[cfg(not(target_os = "windows"))]
+pub fn get_linking_from_cmake_verbose(
+    build_path: &Path,
+    clib_versioned_name: &str,
+    build_type: &str,
+) -> LinkLine {
+    link_line(build_path, clib_versioned_name, build_type)
+        .unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// [`get_linking_from_cmake_verbose`], failing with
+/// [`BuildError::MissingLinkLine`] if CMake didn't write the link line.
+///
+#[cfg(not(target_os = "windows"))]
+fn link_line(
+    build_path: &Path,
+    clib_versioned_name: &str,
+    _build_type: &str,
+) -> Result<LinkLine, BuildError> {
+    // Depending on the CMake version the shared library's target directory
+    // may have the same `-shared` suffix it does on Windows
+    let link_txt_paths: Vec<PathBuf> = ["", "-shared"]
+        .iter()
+        .map(|suffix| {
+            build_path
+                .join("CMakeFiles")
+                .join(format!("{}{}.dir", clib_versioned_name, suffix))
+                .join("link.txt")
+        })
+        .collect();
+    let link_txt = match link_txt_paths
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+    {
+        Some(link_txt) => link_txt,
+        None => return Err(BuildError::MissingLinkLine(link_txt_paths)),
+    };
+
+    if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+        println!("cargo:warning=Reading link.txt {}", link_txt);
+    }
+
+    let mut unclassified = Vec::new();
+    let args = link_txt_link_args(
+        &link_txt,
+        build_path,
+        &Regex::new(DYLIB_RE).unwrap(),
+        &mut unclassified,
+    );
+    Ok(LinkLine { args, unclassified })
+}
+
+/// Split a command line into its arguments the way a POSIX shell would,
+/// which is how `link.txt` and response files quote paths with spaces in:
+/// single quotes keep everything literally, double quotes keep everything
+/// but backslash escapes of `"`, `\`, `$` and `` ` ``, and a backslash
+/// anywhere else escapes the next character. A backslash before a newline
+/// continues the line.
+///
+#[cfg(not(target_os = "windows"))]
+fn split_shell_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // whether there's a word at all, since `''` is an empty one
+    let mut in_word = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                word.extend(chars.by_ref().take_while(|&c| c != '\''));
+            }
+            '"' => {
+                in_word = true;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some(e @ ('"' | '\\' | '$' | '`')) => word.push(e),
+                            Some('\n') => (),
+                            Some(e) => {
+                                word.push('\\');
+                                word.push(e);
+                            }
+                            None => word.push('\\'),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => (),
+                Some(e) => {
+                    in_word = true;
+                    word.push(e);
+                }
+                None => {
+                    in_word = true;
+                    word.push('\\');
+                }
+            },
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// Pick the link arguments out of the contents of a CMake `link.txt`.
+///
+/// Archive lines (`ar qc libfoo.a foo.o`) link nothing, so give no arguments.
+/// For anything else the output (`-o whatever.so`) is skipped if there is
+/// one, otherwise every argument after the linker itself is checked.
+///
+/// The target's own objects under `CMakeFiles/` are left out, since we link
+/// the static build of it instead. Other object files relative to
+/// `build_path` are made absolute.
+///
+/// The arguments are split as the shell would (see [`split_shell_words`]),
+/// so quoted paths with spaces in stay whole. Response files (`@file`) are
+/// read and their arguments checked in their place. Arguments that aren't
+/// any kind of [`LinkArg`] are added to `unclassified`.
+///
+/// `-isystem` and `-iquote` include directories, which some configurations
+/// pass to the link step too, are kept as [`LinkArg::IncludeDir`]s, with
+/// relative ones made absolute like object files. macOS frameworks given as
+/// `-framework <name>` become `framework=<name>` [`LinkArg::LinkLib`]s.
+///
+#[cfg(not(target_os = "windows"))]
+fn link_txt_link_args(
+    link_txt: &str,
+    build_path: &Path,
+    re: &Regex,
+    unclassified: &mut Vec<String>,
+) -> Vec<LinkArg> {
+    let args = split_shell_words(link_txt);
+    let is_archiver = args.first().map_or(false, |cmd| {
+        let cmd = cmd.rsplit('/').next().unwrap_or(cmd);
+        cmd == "ar" || cmd.ends_with("-ar")
+    });
+    if is_archiver {
+        return Vec::new();
+    }
+
+    // Try and figure out what are libraries we want to copy to target.
+    // Libraries will end with `.so` or `.so.28.1.0` or `.dylib`
+
+    // First, strip off everything up to and including the initial "-o whatever.so"
+    let rest = match args.iter().position(|s| s == "-o") {
+        Some(i) => args.get(i + 2..).unwrap_or_default(),
+        None => args.get(1..).unwrap_or_default(),
+    };
+
+    // Now match all the remaining arguments against a regex looking for
+    // shared library paths.
+    let mut args = Vec::new();
+    let mut tokens = expand_response_files(rest, build_path, 0).into_iter();
+    while let Some(s) = tokens.next() {
+        let include_dir = match s.as_str() {
+            "-isystem" | "-iquote" => tokens.next(),
+            _ => s
+                .strip_prefix("-isystem")
+                .or_else(|| s.strip_prefix("-iquote"))
+                .map(str::to_string),
+        };
+        if let Some(dir) = include_dir {
+            args.push(LinkArg::IncludeDir(
+                build_path.join(dir).to_string_lossy().into_owned(),
+            ));
+            continue;
+        }
+        if s == "-framework" {
+            if let Some(name) = tokens.next() {
+                args.push(LinkArg::LinkLib(format!("framework={}", name)));
+            }
+            continue;
+        }
+
+        match is_dylib_path(&s, re) {
+            Some(LinkArg::ObjectFile(o)) if o.starts_with("CMakeFiles/") => (),
+            Some(LinkArg::ObjectFile(o)) => args.push(LinkArg::ObjectFile(
+                build_path.join(o).to_string_lossy().into_owned(),
+            )),
+            Some(arg) => args.push(arg),
+            None => unclassified.push(s),
+        }
+    }
+    args
+}
+
+/// Replace the response files (`@file`) in `args` with the arguments they
+/// contain, which CMake uses when a link line gets too long for the shell.
+/// Relative paths are relative to `build_path`, which the link is run from.
+///
+#[cfg(not(target_os = "windows"))]
+fn expand_response_files(
+    args: &[String],
+    build_path: &Path,
+    depth: usize,
+) -> Vec<String> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('@') {
+            // guard against response files that include themselves
+            Some(file) if depth < 8 => {
+                let path = build_path.join(file);
+                match std::fs::read_to_string(&path) {
+                    Ok(contents) => {
+                        expanded.extend(expand_response_files(
+                            &split_shell_words(&contents),
+                            build_path,
+                            depth + 1,
+                        ));
+                    }
+                    Err(e) => println!(
+                        "cargo:warning=Could not read response file {}: {}",
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+            _ => expanded.push(arg.clone()),
+        }
+    }
+    expanded
+}
+
+/// Find the C wrapper library that was installed under `dst`.
+///
+fn find_wrapper_library(
+    dst: &Path,
+    clib_versioned_name: &str,
+    clib_shared_versioned_name: &str,
+) -> Option<PathBuf> {
+    #[cfg(not(target_os = "windows"))]
+    let filenames = [format!("lib{}.a", clib_versioned_name)];
+    #[cfg(target_os = "windows")]
+    let filenames = [
+        format!("{}.dll", clib_shared_versioned_name),
+        format!("{}.lib", clib_shared_versioned_name),
+    ];
+    #[cfg(not(target_os = "windows"))]
+    let _ = clib_shared_versioned_name;
+    #[cfg(target_os = "windows")]
+    let _ = clib_versioned_name;
+
+    [dst.to_path_buf(), dst.join("lib"), dst.join("bin")]
+        .iter()
+        .flat_map(|dir| filenames.iter().map(move |f| dir.join(f)))
+        .find(|p| p.exists())
+}
+
+/// Returns true if the symbol table listing `symbols` (the output of `nm` or
+/// `dumpbin /EXPORTS`) contains `symbol`, allowing for the leading underscore
+/// macOS adds to C symbols.
+///
+fn has_exported_symbol(symbols: &str, symbol: &str) -> bool {
+    has_exported_symbol_matching(symbols, |s| s == symbol)
+}
+
+/// Returns true if the symbol table listing `symbols` contains a symbol that
+/// starts with `prefix`, like [`has_exported_symbol`].
+///
+fn has_exported_symbol_prefix(symbols: &str, prefix: &str) -> bool {
+    has_exported_symbol_matching(symbols, |s| s.starts_with(prefix))
+}
+
+fn has_exported_symbol_matching(
+    symbols: &str,
+    matches: impl Fn(&str) -> bool,
+) -> bool {
+    symbols.lines().any(|line| {
+        let mut toks = line.split_whitespace().rev();
+        match (toks.next(), toks.next()) {
+            // nm also lists the symbols we only reference, as undefined
+            (_, Some("U")) => false,
+            (Some(s), _) => {
+                matches(s) || s.strip_prefix('_').map_or(false, &matches)
+            }
+            _ => false,
+        }
+    })
+}
+
+/// A symbol the C wrapper library is expected to export, see
+/// [`check_exported_symbols`].
+///
+enum ExpectedSymbol<'a> {
+    /// The symbol with exactly this name, see [`Builder::sentinel_symbol`].
+    Named(&'a str),
+    /// Any symbol starting with this, see [`Builder::exported_symbol_prefix`].
+    Prefixed(&'a str),
+}
+
+/// Check that the C wrapper library under `dst` exports all of the
+/// `expected` symbols, failing with a helpful [`BuildError::MissingExports`]
+/// if it doesn't.
+///
+fn check_exported_symbols(
+    dst: &Path,
+    clib_versioned_name: &str,
+    clib_shared_versioned_name: &str,
+    expected: &[ExpectedSymbol],
+) -> Result<(), BuildError> {
+    let lib = find_wrapper_library(
+        dst,
+        clib_versioned_name,
+        clib_shared_versioned_name,
+    )
+    .ok_or_else(|| {
+        BuildError::MissingExports(format!(
+            "Could not find the built C wrapper library {} under {} to check \
+             its exported symbols",
+            clib_versioned_name,
+            dst.display()
+        ))
+    })?;
+
+    #[cfg(not(target_os = "windows"))]
+    let (tool, args) = ("nm", ["-g"]);
+    #[cfg(target_os = "windows")]
+    let (tool, args) = ("dumpbin", ["/NOLOGO", "/EXPORTS"]);
+
+    let output = std::process::Command::new(tool)
+        .args(args)
+        .arg(&lib)
+        .output()
+        .map_err(|e| BuildError::MissingTool(format!("{} ({})", tool, e)))?;
+
+    if !output.status.success() {
+        return Err(BuildError::MissingExports(format!(
+            "{} failed to list the symbols exported by {}: {}",
+            tool,
+            lib.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let symbols = String::from_utf8_lossy(&output.stdout);
+    for expected in expected {
+        let (found, missing) = match expected {
+            ExpectedSymbol::Named(symbol) => (
+                has_exported_symbol(&symbols, symbol),
+                format!("the symbol `{}`", symbol),
+            ),
+            ExpectedSymbol::Prefixed(prefix) => (
+                has_exported_symbol_prefix(&symbols, prefix),
+                format!("any symbols starting with `{}`", prefix),
+            ),
+        };
+        if !found {
+            return Err(BuildError::MissingExports(format!(
+                "The C wrapper library {} built, but does not export {}. \
+                 This usually means the wrapper was built with the wrong \
+                 symbol visibility or export settings: check that the C API \
+                 is declared with the export macro cppmm generates, and that \
+                 nothing builds it with -fvisibility=hidden (or without \
+                 __declspec(dllexport) on Windows) otherwise.",
+                lib.display(),
+                missing
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Parse the `compile_commands.json` CMake exported into `build_path` to find
+/// the include directories the C wrapper was compiled with.
+///
+/// Both `-I` and `-isystem` flags are picked up, whether or not the directory
+/// is attached to the flag. Relative directories are resolved against the
+/// directory each command was run from. The result is deduplicated and kept in
+/// the order the directories were first seen.
+///
+pub fn include_dirs_from_compile_commands(build_path: &Path) -> Vec<PathBuf> {
+    let cc_path = build_path.join("compile_commands.json");
+    let cc_json = match std::fs::read_to_string(&cc_path) {
+        Ok(s) => s,
+        Err(_) => return Vec::new(),
+    };
+
+    let commands = match json::parse(&cc_json) {
+        Ok(json::Value::Array(commands)) => commands,
+        Ok(_) => {
+            println!(
+                "cargo:warning={} is not a list of commands",
+                cc_path.display()
+            );
+            return Vec::new();
+        }
+        Err(e) => {
+            println!(
+                "cargo:warning=Could not parse {}: {}",
+                cc_path.display(),
+                e
+            );
+            return Vec::new();
+        }
+    };
+
+    let mut include_dirs = Vec::new();
+    for command in &commands {
+        let directory = Path::new(
+            command
+                .get("directory")
+                .and_then(|d| d.as_str())
+                .unwrap_or(""),
+        );
+
+        let args: Vec<String> = if let Some(args) =
+            command.get("arguments").and_then(|a| a.as_array())
+        {
+            args.iter()
+                .filter_map(|a| a.as_str())
+                .map(|a| a.to_string())
+                .collect()
+        } else if let Some(cmd) =
+            command.get("command").and_then(|c| c.as_str())
+        {
+            // quoted the way the compiler's shell expects
+            #[cfg(target_os = "windows")]
+            let args = split_windows_words(cmd);
+            #[cfg(not(target_os = "windows"))]
+            let args = split_shell_words(cmd);
+            args
+        } else {
+            continue;
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let dir = if arg == "-I" || arg == "-isystem" {
+                args.next().map(|d| d.as_str())
+            } else if let Some(d) = arg.strip_prefix("-isystem") {
+                Some(d)
+            } else {
+                arg.strip_prefix("-I")
+            };
+
+            if let Some(dir) = dir {
+                let dir = directory.join(dir.trim_matches('"'));
+                if !include_dirs.contains(&dir) {
+                    include_dirs.push(dir);
+                }
+            }
+        }
+    }
+
+    include_dirs
+}
+
+/// Turn a library path into a [`LinkArg`] using the naming conventions of the
+/// current platform.
+///
+fn library_path_link_arg(path: &str) -> Option<LinkArg> {
+    #[cfg(not(target_os = "windows"))]
+    return is_dylib_path(path, &Regex::new(DYLIB_RE).unwrap());
+    #[cfg(target_os = "windows")]
+    return is_dll_lib_path(path, &Regex::new(DLL_LIB_RE).unwrap());
+}
+
+/// Returns true if `a` and `b` link the same library, however they name it.
+///
+fn same_library(a: &LinkArg, b: &LinkArg) -> bool {
+    fn name(arg: &LinkArg) -> Option<&str> {
+        match arg {
+            LinkArg::LinkLib(lib) => Some(lib),
+            LinkArg::Path(d) => Some(&d.libname),
+            LinkArg::LinkDir(_)
+            | LinkArg::ObjectFile(_)
+            | LinkArg::IncludeDir(_) => None,
+        }
+    }
+    match (name(a), name(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Returns true if `arg` links one of the libraries in `ignored`, which are
+/// matched against the name it's linked by or the file name of its path,
+/// e.g. `z` or `libz.so.1`.
+///
+fn is_ignored_lib(arg: &LinkArg, ignored: &[String]) -> bool {
+    match arg {
+        LinkArg::LinkLib(lib) => {
+            // Skip any `kind=` in front of the name
+            let name = lib.rsplit('=').next().unwrap_or(lib);
+            ignored.iter().any(|i| i == name)
+        }
+        LinkArg::Path(d) => {
+            ignored.iter().any(|i| *i == d.libname || *i == d.basename)
+        }
+        LinkArg::LinkDir(_)
+        | LinkArg::ObjectFile(_)
+        | LinkArg::IncludeDir(_) => false,
+    }
+}
+
+/// Merge several sets of link arguments into one, with each search directory
+/// and library appearing only once.
+///
+/// All the search and include directories come first, followed by the
+/// libraries, each in the order they were first seen. If the same library is linked from two
+/// different paths only the first is kept, and a warning is emitted since
+/// that usually means two dependencies were built against different copies
+/// of it.
+///
+pub fn merge_link_args(sets: &[Vec<LinkArg>]) -> Vec<LinkArg> {
+    let mut dirs: Vec<LinkArg> = Vec::new();
+    let mut libs: Vec<LinkArg> = Vec::new();
+
+    for arg in sets.iter().flatten() {
+        match arg {
+            LinkArg::LinkDir(_) | LinkArg::IncludeDir(_) => {
+                if !dirs.contains(arg) {
+                    dirs.push(arg.clone());
+                }
+            }
+            LinkArg::LinkLib(_) | LinkArg::ObjectFile(_) => {
+                if !libs.contains(arg) {
+                    libs.push(arg.clone());
+                }
+            }
+            LinkArg::Path(d) => {
+                let existing = libs.iter().find_map(|l| match l {
+                    LinkArg::Path(e) if e.libname == d.libname => Some(e),
+                    _ => None,
+                });
+                match existing {
+                    Some(e) if e.path != d.path => println!(
+                        "cargo:warning=Library {} is linked from both {} and \
+                         {}, using the first",
+                        d.libname, e.path, d.path
+                    ),
+                    Some(_) => (),
+                    None => libs.push(arg.clone()),
+                }
+            }
+        }
+    }
+
+    dirs.extend(libs);
+    dirs
+}
+
+/// Returns true if `lib` is a static archive rather than a shared library.
+///
+fn is_static_archive(lib: &DylibPathInfo) -> bool {
+    !cfg!(target_os = "windows") && lib.path.ends_with(".a")
+}
+
+/// Remove repeated search directories and libraries from `args`, keeping the
+/// first of each and otherwise leaving the order alone.
+///
+/// A library counts as repeated if it has the same link name as an earlier
+/// one, however that was given (see [`same_library`]). If one was given by
+/// its path and the other only by name, the path is kept in place of the
+/// first, so the linker can't pick up a different library of that name.
+/// Static archives are left alone, since CMake repeats those on purpose
+/// when they depend on each other.
+///
+fn dedup_link_args(args: Vec<LinkArg>) -> Vec<LinkArg> {
+    let mut deduped: Vec<LinkArg> = Vec::with_capacity(args.len());
+    for arg in args {
+        if links_static_archive(&arg) {
+            deduped.push(arg);
+            continue;
+        }
+        match deduped
+            .iter()
+            .position(|a| *a == arg || same_library(a, &arg))
+        {
+            Some(i) => {
+                if let (LinkArg::LinkLib(_), LinkArg::Path(_)) =
+                    (&deduped[i], &arg)
+                {
+                    deduped[i] = arg;
+                }
+            }
+            None => deduped.push(arg),
+        }
+    }
+    deduped
+}
+
+/// Returns true if `arg` links a static archive, by path or as a
+/// `static`-kind [`LinkArg::LinkLib`].
+///
+fn links_static_archive(arg: &LinkArg) -> bool {
+    match arg {
+        LinkArg::Path(d) => is_static_archive(d),
+        LinkArg::LinkLib(lib) => {
+            lib.rsplit_once('=').map_or(false, |(kind, _)| {
+                kind.split(':').next() == Some("static")
+            })
+        }
+        LinkArg::LinkDir(_)
+        | LinkArg::ObjectFile(_)
+        | LinkArg::IncludeDir(_) => false,
+    }
+}
+
+/// The directories to search for the libraries in `args`: those given as
+/// [`LinkArg::LinkDir`] and the ones containing each [`LinkArg::Path`], each
+/// only once and in the order they're first needed.
+///
+pub fn link_search_dirs(args: &[LinkArg]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for arg in args {
+        let dir = match arg {
+            LinkArg::LinkDir(dir) => Some(PathBuf::from(dir)),
+            LinkArg::Path(d) => {
+                Path::new(&d.path).parent().map(Path::to_path_buf)
+            }
+            LinkArg::LinkLib(_)
+            | LinkArg::ObjectFile(_)
+            | LinkArg::IncludeDir(_) => None,
+        };
+        if let Some(dir) = dir.filter(|d| !d.as_os_str().is_empty()) {
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    dirs
+}
+
+/// Check that every library path in `args` exists and every search directory
+/// is a directory.
+///
+/// All the problems found are reported together in a
+/// [`BuildError::MissingLinkArgs`]. The build itself only warns about these,
+/// so call this from your own build script if you'd rather it failed.
+///
+pub fn verify_link_args(args: &[LinkArg]) -> Result<(), BuildError> {
+    let mut missing = Vec::new();
+    for arg in args {
+        match arg {
+            LinkArg::Path(d) if !Path::new(&d.path).is_file() => {
+                missing.push(format!("library {} does not exist", d.path))
+            }
+            LinkArg::LinkDir(dir) if !Path::new(dir).is_dir() => {
+                missing.push(format!("link directory {} does not exist", dir))
+            }
+            LinkArg::ObjectFile(o) if !Path::new(o).is_file() => {
+                missing.push(format!("object file {} does not exist", o))
+            }
+            LinkArg::IncludeDir(dir) if !Path::new(dir).is_dir() => missing
+                .push(format!("include directory {} does not exist", dir)),
+            _ => (),
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(BuildError::MissingLinkArgs(missing))
+    }
+}
+
+/// The directories under `prefix` that CMake package configs are installed
+/// into. Distros differ on where these go, so any of `lib/cmake`,
+/// `lib64/cmake` and `share/cmake` that exist are returned, or just
+/// `lib/cmake` if none do yet.
+///
+fn cmake_package_dirs(prefix: &Path) -> Vec<PathBuf> {
+    let dirs: Vec<PathBuf> = [
+        prefix.join("lib").join("cmake"),
+        prefix.join("lib64").join("cmake"),
+        prefix.join("share").join("cmake"),
+    ]
+    .iter()
+    .filter(|d| d.is_dir())
+    .cloned()
+    .collect();
+
+    if dirs.is_empty() {
+        vec![prefix.join("lib").join("cmake")]
+    } else {
+        dirs
+    }
+}
+
+/// [`cmake_package_dirs`] for `prefix` as a CMake list, for passing as
+/// `CMAKE_PREFIX_PATH`.
+///
+fn cmake_prefix_path(prefix: &Path) -> std::ffi::OsString {
+    let mut path = std::ffi::OsString::new();
+    for (i, dir) in cmake_package_dirs(prefix).iter().enumerate() {
+        if i > 0 {
+            path.push(";");
+        }
+        path.push(dir);
+    }
+    path
+}
+
+/// The `CMAKE_PREFIX_PATH` for configuring the C library: the package
+/// directories of `prefix`, if we installed the dependencies there, followed
+/// by the `extra` prefixes. Returns `None` if there's nothing to search.
+///
+/// This is a CMake list, so it's separated with `;` on every platform.
+///
+fn shim_prefix_path(
+    prefix: Option<&Path>,
+    extra: &[PathBuf],
+) -> Option<std::ffi::OsString> {
+    let mut path = prefix.map(cmake_prefix_path).unwrap_or_default();
+    for dir in extra {
+        if !path.is_empty() {
+            path.push(";");
+        }
+        path.push(dir);
+    }
+    if path.is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// The CMake definitions to search inside `roots` rather than the host, see
+/// [`Builder::find_root_path`], with `modes` overriding the default
+/// `CMAKE_FIND_ROOT_PATH_MODE_*`s. Returns nothing if there are no roots.
+///
+fn find_root_definitions(
+    roots: &[PathBuf],
+    modes: &[(String, String)],
+) -> Result<Vec<(String, String)>, BuildError> {
+    if roots.is_empty() {
+        return Ok(Vec::new());
+    }
+    let roots = roots
+        .iter()
+        .map(|r| path_str(r))
+        .collect::<Result<Vec<&str>, BuildError>>()?;
+    let mut definitions =
+        vec![("CMAKE_FIND_ROOT_PATH".to_string(), roots.join(";"))];
+    for (kind, default) in &[
+        ("PROGRAM", "NEVER"),
+        ("LIBRARY", "ONLY"),
+        ("INCLUDE", "ONLY"),
+        ("PACKAGE", "BOTH"),
+    ] {
+        let mode = modes
+            .iter()
+            .find(|(k, _)| k == kind)
+            .map_or(*default, |(_, m)| m.as_str());
+        definitions.push((
+            format!("CMAKE_FIND_ROOT_PATH_MODE_{}", kind),
+            mode.to_string(),
+        ));
+    }
+    Ok(definitions)
+}
+
+/// Find the libraries listed in `INTERFACE_LINK_LIBRARIES` by the CMake
+/// package configs for the packages `names` installed under any of
+/// `prefixes`, i.e. in `<prefix>/lib/cmake/<name>/*.cmake` (or any of the
+/// other [`cmake_package_dirs`]).
+///
+/// Entries naming imported targets (e.g. `Imath::Imath`) are resolved to
+/// their `IMPORTED_LOCATION` in any of the packages searched, and are skipped
+/// if that's not known. Generator expressions other than `$<LINK_ONLY:...>`
+/// are skipped entirely.
+///
+fn interface_link_libraries(
+    prefixes: &[PathBuf],
+    names: &[&str],
+) -> Vec<LinkArg> {
+    let PackageConfigs {
+        locations,
+        interface_libs,
+    } = read_package_configs(prefixes, names);
+
+    let mut link_args: Vec<LinkArg> = Vec::new();
+    for lib in &interface_libs {
+        let lib = match lib.strip_prefix("$<LINK_ONLY:") {
+            Some(lib) => lib.trim_end_matches('>'),
+            None => lib.as_str(),
+        };
+        if lib.is_empty() || lib.contains("$<") {
+            continue;
+        }
+
+        let arg = if lib.contains("::") {
+            locations
+                .iter()
+                .find(|(target, _)| target == lib)
+                .and_then(|(_, location)| library_path_link_arg(location))
+        } else if Path::new(lib).is_absolute() {
+            library_path_link_arg(lib)
+        } else if let Some(name) = lib.strip_prefix("-l") {
+            Some(LinkArg::LinkLib(name.to_string()))
+        } else if !lib.contains('/') && !lib.contains('\\') {
+            Some(LinkArg::LinkLib(lib.to_string()))
+        } else {
+            None
+        };
+
+        if let Some(arg) = arg {
+            if !link_args.iter().any(|a| same_library(a, &arg)) {
+                link_args.push(arg);
+            }
+        }
+    }
+
+    link_args
+}
+
+/// What the CMake package configs of some packages say about their imported
+/// targets, see [`read_package_configs`].
+#[derive(Debug, Default)]
+struct PackageConfigs {
+    /// Each target's `IMPORTED_LOCATION`s, one for each configuration.
+    locations: Vec<(String, String)>,
+    /// The entries of all of the targets' `INTERFACE_LINK_LIBRARIES`.
+    interface_libs: Vec<String>,
+}
+
+/// Read the `set_target_properties` calls in the CMake package configs for
+/// the packages `names` installed under any of `prefixes`, as for
+/// [`interface_link_libraries`].
+///
+fn read_package_configs(
+    prefixes: &[PathBuf],
+    names: &[&str],
+) -> PackageConfigs {
+    let re_block =
+        Regex::new(r"(?s)set_target_properties\(\s*(\S+)\s+PROPERTIES(.*?)\)")
+            .unwrap();
+    let re_prop = Regex::new(r#"(\w+)\s+"([^"]*)""#).unwrap();
+
+    let mut configs = PackageConfigs::default();
+
+    for (prefix, cmake_dir) in prefixes
+        .iter()
+        .flat_map(|p| cmake_package_dirs(p).into_iter().map(move |d| (p, d)))
+    {
+        let packages = match std::fs::read_dir(&cmake_dir) {
+            Ok(packages) => packages,
+            Err(_) => continue,
+        };
+
+        for package in packages.flatten() {
+            let package_name = package.file_name();
+            let package_name = package_name.to_string_lossy();
+            if !names.iter().any(|n| n.eq_ignore_ascii_case(&package_name)) {
+                continue;
+            }
+
+            let files = match std::fs::read_dir(package.path()) {
+                Ok(files) => files,
+                Err(_) => continue,
+            };
+            for file in files.flatten() {
+                let path = file.path();
+                if path.extension() != Some(std::ffi::OsStr::new("cmake")) {
+                    continue;
+                }
+                let contents = match std::fs::read_to_string(&path) {
+                    Ok(contents) => contents,
+                    Err(_) => continue,
+                };
+                let contents = contents
+                    .replace("${_IMPORT_PREFIX}", &prefix.to_string_lossy());
+
+                for block in re_block.captures_iter(&contents) {
+                    for prop in re_prop.captures_iter(&block[2]) {
+                        if prop[1] == *"INTERFACE_LINK_LIBRARIES" {
+                            configs.interface_libs.extend(
+                                prop[2].split(';').map(|l| l.to_string()),
+                            );
+                        } else if prop[1].starts_with("IMPORTED_LOCATION") {
+                            configs.locations.push((
+                                block[1].to_string(),
+                                prop[2].to_string(),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    configs
+}
+
+/// The version of the package `name` installed under any of `prefixes`, as
+/// its CMake package version file gives it, or failing that its pkg-config
+/// file. Returns `None` if neither is found.
+///
+fn installed_package_version(
+    name: &str,
+    prefixes: &[PathBuf],
+) -> Option<String> {
+    let re_version =
+        Regex::new(r#"set\(\s*PACKAGE_VERSION\s+"([^"]+)"\s*\)"#).unwrap();
+    let entries = |dir: &Path| {
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|e| e.path())
+            .collect::<Vec<PathBuf>>()
+    };
+    let file_name = |path: &Path| {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+
+    for prefix in prefixes {
+        for cmake_dir in cmake_package_dirs(prefix) {
+            for package in entries(&cmake_dir) {
+                if !file_name(&package).eq_ignore_ascii_case(name) {
+                    continue;
+                }
+                for file in entries(&package) {
+                    let file_name = file_name(&file);
+                    if !file_name.ends_with("ConfigVersion.cmake")
+                        && !file_name.ends_with("-config-version.cmake")
+                    {
+                        continue;
+                    }
+                    let contents =
+                        std::fs::read_to_string(&file).unwrap_or_default();
+                    if let Some(version) = re_version.captures(&contents) {
+                        return Some(version[1].to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    let pc = format!("{}.pc", name);
+    for prefix in prefixes {
+        for dir in &["lib", "lib64", "share"] {
+            for file in entries(&prefix.join(dir).join("pkgconfig")) {
+                if !file_name(&file).eq_ignore_ascii_case(&pc) {
+                    continue;
+                }
+                let contents =
+                    std::fs::read_to_string(&file).unwrap_or_default();
+                if let Some(version) = contents
+                    .lines()
+                    .find_map(|l| l.strip_prefix("Version:"))
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                {
+                    return Some(version.to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The prefixes a system dependency may be installed under: the shared
+/// prefix, if we're installing into one, the extra prefix paths, those in
+/// `CMAKE_PREFIX_PATH` and the usual system prefixes on Unices.
+///
+fn system_prefixes(
+    shared_prefix: Option<&Path>,
+    extra_prefix_paths: &[PathBuf],
+) -> Vec<PathBuf> {
+    let mut prefixes: Vec<PathBuf> =
+        shared_prefix.into_iter().map(Path::to_path_buf).collect();
+    prefixes.extend(extra_prefix_paths.iter().cloned());
+    if let Some(paths) = std::env::var_os("CMAKE_PREFIX_PATH") {
+        prefixes.extend(std::env::split_paths(&paths));
+    }
+    if !cfg!(target_os = "windows") {
+        prefixes.push(PathBuf::from("/usr"));
+        prefixes.push(PathBuf::from("/usr/local"));
+    }
+    prefixes
+}
+
+/// What one native dependency of a build resolved to, see
+/// [`BuildReport::resolved_dependencies`].
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedDependency {
+    pub name: String,
+    /// Whether the dependency was built from its packaged sources, rather
+    /// than found on the system.
+    pub built: bool,
+    /// The version installed, as its CMake package version file or
+    /// pkg-config file gives it, if it has one.
+    pub version: Option<String>,
+    /// The dependency's libraries: those it installed if it was built,
+    /// otherwise the imported locations its CMake package config gives.
+    pub libraries: Vec<PathBuf>,
+}
+
+/// Work out what each of `dependencies` resolved to. Those in `built` were
+/// built into `target_dir`, with the libraries given, and the rest are
+/// looked for under `system_prefixes`.
+///
+fn resolve_dependencies(
+    dependencies: &[DependencyOwned],
+    built: &[(String, Vec<PathBuf>)],
+    target_dir: &Path,
+    system_prefixes: &[PathBuf],
+) -> Vec<ResolvedDependency> {
+    let mut resolved: Vec<ResolvedDependency> = Vec::new();
+    for dep in dependencies {
+        if resolved.iter().any(|r| r.name == dep.name) {
+            continue;
+        }
+        let built_libraries = built
+            .iter()
+            .find(|(name, _)| *name == dep.name)
+            .map(|(_, l)| l);
+        let (version, libraries) = match built_libraries {
+            Some(libraries) => (
+                installed_package_version(&dep.name, &[target_dir.into()]),
+                libraries.clone(),
+            ),
+            None => {
+                let mut libraries: Vec<PathBuf> = Vec::new();
+                for (_, location) in
+                    read_package_configs(system_prefixes, &[&dep.name])
+                        .locations
+                {
+                    let location = PathBuf::from(location);
+                    if !libraries.contains(&location) {
+                        libraries.push(location);
+                    }
+                }
+                (
+                    installed_package_version(&dep.name, system_prefixes),
+                    libraries,
+                )
+            }
+        };
+        resolved.push(ResolvedDependency {
+            name: dep.name.clone(),
+            built: built_libraries.is_some(),
+            version,
+            libraries,
+        });
+    }
+    resolved
+}
+
+/// The lock file recording what the native dependencies resolved to, under
+/// `out_dir`.
+///
+fn native_lock_path(out_dir: &Path) -> PathBuf {
+    out_dir.join("cppmm-native-lock.json")
+}
+
+/// The contents of the lock file for `resolved`: a JSON object with a
+/// `dependencies` array, holding the `name`, `source` (`"built"` or
+/// `"system"`), `version` (or `null`) and `libraries` of each.
+///
+fn native_lock(resolved: &[ResolvedDependency]) -> String {
+    let dependencies = resolved
+        .iter()
+        .map(|dep| {
+            json::Value::Object(vec![
+                ("name".into(), json::Value::String(dep.name.clone())),
+                (
+                    "source".into(),
+                    json::Value::String(
+                        if dep.built { "built" } else { "system" }.into(),
+                    ),
+                ),
+                (
+                    "version".into(),
+                    dep.version
+                        .clone()
+                        .map_or(json::Value::Null, json::Value::String),
+                ),
+                (
+                    "libraries".into(),
+                    json::Value::Array(
+                        dep.libraries
+                            .iter()
+                            .map(|l| {
+                                json::Value::String(
+                                    l.to_string_lossy().into_owned(),
+                                )
+                            })
+                            .collect(),
+                    ),
+                ),
+            ])
+        })
+        .collect();
+    let mut lock = json::to_string_pretty(&json::Value::Object(vec![(
+        "dependencies".into(),
+        json::Value::Array(dependencies),
+    )]));
+    lock.push('\n');
+    lock
+}
+
+#[derive(Clone)]
+pub struct Dependency {
+    pub name: &'static str,
+    pub definitions: Vec<(&'static str, &'static str)>,
+    /// Cap the number of parallel compile jobs used to build this dependency,
+    /// for memory-hungry libraries that fall over at the full job count.
+    ///
+    /// This takes precedence over `CPPMM_<PROJECT>_BUILD_JOBS` for this
+    /// dependency only, but never raises the job count above it (or above
+    /// Cargo's own job count if that isn't set).
+    pub max_jobs: Option<usize>,
+    /// Names of other dependencies in the list that must be built before
+    /// this one.
+    pub depends_on: &'static [&'static str],
+    /// Where the dependency's sources live, if not in `thirdparty/<name>`.
+    /// Relative paths are relative to the crate root, but this can also be an
+    /// absolute path to sources checked out elsewhere.
+    pub source_dir: Option<&'static str>,
+    /// Only build this dependency when this Cargo feature of the crate being
+    /// built is enabled, i.e. when `CARGO_FEATURE_<FEATURE>` is set.
+    pub feature: Option<&'static str>,
+    /// CMake policies to set for this dependency, as the policy id and
+    /// `NEW` or `OLD`, e.g. `&[("CMP0077", "NEW")]`. These are passed as
+    /// `CMAKE_POLICY_DEFAULT_<id>`, so they only apply to policies the
+    /// project doesn't set itself.
+    ///
+    /// CMP0091 is set to `NEW` by default on Windows, which makes
+    /// `CMAKE_MSVC_RUNTIME_LIBRARY` (rather than `/MD` and `/MT` in the
+    /// compiler flags) select the C runtime. Setting it to `OLD` here means
+    /// the dependency picks its runtime the old way, which may not match the
+    /// runtime of everything else.
+    pub policies: &'static [(&'static str, &'static str)],
+    /// Build this dependency's libraries as shared (`Some(true)`) or static
+    /// (`Some(false)`), whatever [`Builder::prefer_static_deps`] says, e.g.
+    /// for a library that can't be built statically. `None` follows the
+    /// builder.
+    pub shared: Option<bool>,
+    /// The oldest version of this dependency that will do when it's found
+    /// on the system rather than built, e.g. `"3.1"`. Only as many version
+    /// components as are given here are compared, so `"3.1"` accepts 3.1.4.
+    /// See [`BuildError::DependencyVersion`].
+    pub min_version: Option<&'static str>,
+    /// The newest version of this dependency that will do when it's found
+    /// on the system, compared like `min_version`, so `"3.2"` accepts any
+    /// 3.2.x but not 3.3.
+    pub max_version: Option<&'static str>,
+    /// Build this dependency with parallel jobs. Defaults to `true`.
+    ///
+    /// This is a compatibility shim for projects whose builds have broken
+    /// dependencies between targets and fail now and then with `-j`: setting
+    /// it to `false` builds just this dependency with `--parallel 1`, while
+    /// the others still build in parallel.
+    pub parallel: bool,
+    /// Always build this dependency from `thirdparty`, even when the others
+    /// are found on the system through `CMAKE_PREFIX_PATH`, e.g. for a
+    /// patched copy of one library. It's installed into the prefix, which
+    /// the C library searches ahead of `CMAKE_PREFIX_PATH`, so it shadows
+    /// any system copy.
+    pub force_build: bool,
+}
+
+impl Default for Dependency {
+    fn default() -> Dependency {
+        Dependency {
+            name: "",
+            definitions: Vec::new(),
+            max_jobs: None,
+            depends_on: &[],
+            source_dir: None,
+            feature: None,
+            policies: &[],
+            shared: None,
+            min_version: None,
+            max_version: None,
+            parallel: true,
+            force_build: false,
+        }
+    }
+}
+
+use std::fmt;
+impl fmt::Debug for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// A [`Dependency`] that owns its strings, for dependencies that aren't known
+/// when the build script is compiled, e.g. ones read from a manifest with
+/// [`read_dependency_manifest`]. The fields mean the same as in
+/// [`Dependency`].
+///
+#[derive(Clone, PartialEq, Eq)]
+pub struct DependencyOwned {
+    pub name: String,
+    pub definitions: Vec<(String, String)>,
+    pub max_jobs: Option<usize>,
+    pub depends_on: Vec<String>,
+    pub source_dir: Option<String>,
+    pub feature: Option<String>,
+    pub policies: Vec<(String, String)>,
+    pub shared: Option<bool>,
+    pub min_version: Option<String>,
+    pub max_version: Option<String>,
+    pub parallel: bool,
+    pub force_build: bool,
+}
+
+impl Default for DependencyOwned {
+    fn default() -> DependencyOwned {
+        DependencyOwned::from(&Dependency::default())
+    }
+}
+
+impl fmt::Debug for DependencyOwned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl From<&Dependency> for DependencyOwned {
+    fn from(dep: &Dependency) -> DependencyOwned {
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        DependencyOwned {
+            name: dep.name.to_string(),
+            definitions: pairs(&dep.definitions),
+            max_jobs: dep.max_jobs,
+            depends_on: dep.depends_on.iter().map(|d| d.to_string()).collect(),
+            source_dir: dep.source_dir.map(str::to_string),
+            feature: dep.feature.map(str::to_string),
+            policies: pairs(dep.policies),
+            shared: dep.shared,
+            min_version: dep.min_version.map(str::to_string),
+            max_version: dep.max_version.map(str::to_string),
+            parallel: dep.parallel,
+            force_build: dep.force_build,
+        }
+    }
+}
+
+impl From<Dependency> for DependencyOwned {
+    fn from(dep: Dependency) -> DependencyOwned {
+        DependencyOwned::from(&dep)
+    }
+}
+
+/// Where the sources of the packaged dependency `dep` are, see
+/// [`Dependency::source_dir`].
+///
+fn dependency_source_dir(dep: &DependencyOwned) -> PathBuf {
+    match &dep.source_dir {
+        Some(source_dir) => PathBuf::from(source_dir),
+        None => Path::new("thirdparty").join(&dep.name),
+    }
+}
+
+/// Borrow owned pairs of strings, e.g. a [`DependencyOwned`]'s definitions, to
+/// pass where borrowed ones are expected.
+///
+fn str_pairs(pairs: &[(String, String)]) -> Vec<(&str, &str)> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect()
+}
+
+/// Read the dependencies to build from a JSON manifest at `path`, in the
+/// order they're listed. For example:
+///
+/// ```json
+/// {
+///     "dependencies": [
+///         { "name": "zlib" },
+///         {
+///             "name": "openexr",
+///             "definitions": { "BUILD_TESTING": "OFF" },
+///             "depends_on": ["zlib"],
+///             "max_jobs": 4
+///         }
+///     ]
+/// }
+/// ```
+///
+/// Each entry takes the same fields as [`Dependency`], with `definitions`
+/// and `policies` as objects of strings. Only `name` is required, and any
+/// field that isn't recognized is an error so that typos don't go unnoticed.
+///
+pub fn read_dependency_manifest(
+    path: &Path,
+) -> Result<Vec<DependencyOwned>, BuildError> {
+    let invalid = |message: String| BuildError::InvalidManifest {
+        path: path.to_path_buf(),
+        message,
+    };
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| invalid(format!("could not read it: {}", e)))?;
+    let manifest =
+        json::parse(&contents).map_err(|e| invalid(e.to_string()))?;
+
+    let entries = manifest
+        .get("dependencies")
+        .and_then(json::Value::as_array)
+        .ok_or_else(|| {
+            invalid("expected an object with a \"dependencies\" array".into())
+        })?;
+
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            manifest_dependency(entry).map_err(|message| {
+                invalid(format!("dependency {}: {}", i, message))
+            })
+        })
+        .collect()
+}
+
+/// Convert one entry of a dependency manifest, describing what's wrong with
+/// it if it isn't valid.
+///
+fn manifest_dependency(entry: &json::Value) -> Result<DependencyOwned, String> {
+    let members = match entry {
+        json::Value::Object(members) => members,
+        _ => return Err("expected an object".into()),
+    };
+
+    let string = |key: &str, value: &json::Value| {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| format!("\"{}\" must be a string", key))
+    };
+    let string_pairs = |key: &str, value: &json::Value| match value {
+        json::Value::Object(pairs) => pairs
+            .iter()
+            .map(|(k, v)| {
+                let v = v.as_str().ok_or_else(|| {
+                    format!("\"{}\" values must be strings", key)
+                })?;
+                Ok((k.clone(), v.to_string()))
+            })
+            .collect(),
+        _ => Err(format!("\"{}\" must be an object", key)),
+    };
+
+    let mut dep = DependencyOwned::default();
+    for (key, value) in members {
+        match key.as_str() {
+            "name" => dep.name = string(key, value)?,
+            "definitions" => dep.definitions = string_pairs(key, value)?,
+            "policies" => dep.policies = string_pairs(key, value)?,
+            "max_jobs" => match value {
+                json::Value::Number(n) if *n >= 1.0 && n.fract() == 0.0 => {
+                    dep.max_jobs = Some(*n as usize)
+                }
+                _ => {
+                    return Err(
+                        "\"max_jobs\" must be a positive whole number".into()
+                    )
+                }
+            },
+            "depends_on" => {
+                dep.depends_on = value
+                    .as_array()
+                    .ok_or_else(|| {
+                        "\"depends_on\" must be an array".to_string()
+                    })?
+                    .iter()
+                    .map(|v| string(key, v))
+                    .collect::<Result<_, _>>()?
+            }
+            "source_dir" => dep.source_dir = Some(string(key, value)?),
+            "feature" => dep.feature = Some(string(key, value)?),
+            "shared" => match value {
+                json::Value::Bool(shared) => dep.shared = Some(*shared),
+                _ => return Err("\"shared\" must be true or false".into()),
+            },
+            "min_version" => dep.min_version = Some(string(key, value)?),
+            "max_version" => dep.max_version = Some(string(key, value)?),
+            "parallel" => match value {
+                json::Value::Bool(parallel) => dep.parallel = *parallel,
+                _ => return Err("\"parallel\" must be true or false".into()),
+            },
+            "force_build" => match value {
+                json::Value::Bool(force) => dep.force_build = *force,
+                _ => return Err("\"force_build\" must be true or false".into()),
+            },
+            _ => return Err(format!("unknown field \"{}\"", key)),
+        }
+    }
+
+    if dep.name.is_empty() {
+        return Err("missing \"name\"".into());
+    }
+    Ok(dep)
+}
+
+/// Compare the version `found` against the version `bound`, looking only at
+/// as many components as `bound` has. So `3.1.4` is equal to `3.1` but
+/// greater than `3.1.2`. Anything after the leading digits of a component,
+/// as in `2.5.8-rc1`, is ignored.
+///
+fn compare_versions(found: &str, bound: &str) -> std::cmp::Ordering {
+    let number = |c: &str| -> u64 {
+        let digits = c.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(c.len());
+        c[..digits].parse().unwrap_or(0)
+    };
+    let mut found = found.split('.').map(number);
+    bound
+        .split('.')
+        .map(number)
+        .map(|b| found.next().unwrap_or(0).cmp(&b))
+        .find(|o| o.is_ne())
+        .unwrap_or(std::cmp::Ordering::Equal)
+}
+
+/// Check the version `found` of the system dependency `dependency` against
+/// its `min_version` and `max_version`.
+///
+fn check_dependency_version(
+    dependency: &DependencyOwned,
+    found: &str,
+) -> Result<(), BuildError> {
+    let too_old = dependency.min_version.as_deref().map_or(false, |min| {
+        compare_versions(found, min) == std::cmp::Ordering::Less
+    });
+    let too_new = dependency.max_version.as_deref().map_or(false, |max| {
+        compare_versions(found, max) == std::cmp::Ordering::Greater
+    });
+    if !too_old && !too_new {
+        return Ok(());
+    }
+
+    let required = match (&dependency.min_version, &dependency.max_version) {
+        (Some(min), Some(max)) => format!("{} to {}", min, max),
+        (Some(min), None) => format!("at least {}", min),
+        (None, Some(max)) => format!("at most {}", max),
+        (None, None) => unreachable!(),
+    };
+    Err(BuildError::DependencyVersion {
+        dependency: dependency.name.clone(),
+        found: found.to_string(),
+        required,
+    })
+}
+
+/// The names the CMake package `name` is installed under in any of
+/// `prefixes`, since CMake matches the directories it searches
+/// case-insensitively but not the config file names, e.g. `OpenEXR` for
+/// `openexr`. `name` itself always comes first.
+///
+fn package_names(name: &str, prefixes: &[PathBuf]) -> Vec<String> {
+    let mut names = vec![name.to_string()];
+    for dir in prefixes
+        .iter()
+        .flat_map(|p| cmake_package_dirs(p).into_iter().chain([p.clone()]))
+    {
+        for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let package = entry.file_name().to_string_lossy().into_owned();
+            if package.eq_ignore_ascii_case(name) && !names.contains(&package) {
+                names.push(package);
+            }
+        }
+    }
+    names
+}
+
+/// [`probe_package_version`], reusing the version found by an earlier build
+/// if nothing it depends on changed: the names and definitions it's probed
+/// with, `CMAKE_PREFIX_PATH` and the package config files in `prefixes`.
+/// Configuring the probe takes about as long as configuring anything else,
+/// so it's worth not doing it on every build.
+///
+fn cached_package_version(
+    names: &[String],
+    prefixes: &[PathBuf],
+    probe_dir: &Path,
+    definitions: &[(String, std::ffi::OsString)],
+) -> Option<String> {
+    let stamp_path = probe_dir.join("version.stamp");
+    let key = version_probe_key(names, prefixes, definitions);
+    if let Ok(stamp) = std::fs::read_to_string(&stamp_path) {
+        if let Some((stamp_key, version)) = stamp.split_once('\n') {
+            if stamp_key == key {
+                return Some(version.to_string());
+            }
+        }
+    }
+    let version = probe_package_version(names, probe_dir, definitions)?;
+    // Not finding it isn't cached, since that's usually fixed next
+    let _ = std::fs::write(&stamp_path, format!("{}\n{}", key, version));
+    Some(version)
+}
+
+/// The key [`cached_package_version`] stores a probed version under. Each
+/// package config file under a directory named like the package counts with
+/// its modification time, so installing, upgrading or removing it changes the
+/// key.
+///
+fn version_probe_key(
+    names: &[String],
+    prefixes: &[PathBuf],
+    definitions: &[(String, std::ffi::OsString)],
+) -> String {
+    let mut hash = Fnv1a::new();
+    for name in names {
+        hash.write(name);
+    }
+    for (k, v) in definitions {
+        hash.write(k);
+        hash.write(&v.to_string_lossy());
+    }
+    // CMake searches these too
+    hash.write(
+        &std::env::var_os("CMAKE_PREFIX_PATH")
+            .unwrap_or_default()
+            .to_string_lossy(),
+    );
+    for dir in prefixes
+        .iter()
+        .flat_map(|p| cmake_package_dirs(p).into_iter().chain([p.clone()]))
+    {
+        let mut files: Vec<(PathBuf, u128)> = std::fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| {
+                let package = entry.file_name().to_string_lossy().into_owned();
+                names.iter().any(|n| n.eq_ignore_ascii_case(&package))
+            })
+            .flat_map(|entry| std::fs::read_dir(entry.path()).into_iter())
+            .flatten()
+            .flatten()
+            .map(|entry| {
+                let modified = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_nanos());
+                (entry.path(), modified)
+            })
+            .collect();
+        files.sort();
+        for (path, modified) in files {
+            hash.write(&path.to_string_lossy());
+            hash.write(&modified.to_string());
+        }
+    }
+    format!("{:016x}", hash.finish())
+}
+
+/// Find the version of the package `names[0]` installed on the system, by
+/// configuring a tiny CMake project in `probe_dir` that runs `find_package`
+/// for it with `definitions`. Returns `None` if it isn't found or doesn't
+/// say what version it is.
+///
+fn probe_package_version(
+    names: &[String],
+    probe_dir: &Path,
+    definitions: &[(String, std::ffi::OsString)],
+) -> Option<String> {
+    let name = &names[0];
+    // NAMES forces config mode, so only give it if we need it
+    let alternatives = if names.len() > 1 {
+        format!(" NAMES {}", names.join(" "))
+    } else {
+        String::new()
+    };
+    let build_dir = probe_dir.join("build");
+    std::fs::create_dir_all(&build_dir).ok()?;
+    std::fs::write(
+        probe_dir.join("CMakeLists.txt"),
+        format!(
+            "cmake_minimum_required(VERSION 3.5)\n\
+             project(cppmm_version_probe NONE)\n\
+             find_package({0} QUIET{1})\n\
+             file(WRITE \"${{CMAKE_BINARY_DIR}}/version.txt\" \
+             \"${{{0}_VERSION}}\")\n",
+            name, alternatives
+        ),
+    )
+    .ok()?;
+
+    let mut command = std::process::Command::new(cmake_program());
+    command.arg(probe_dir).current_dir(&build_dir);
+    for (key, value) in definitions {
+        let mut arg = std::ffi::OsString::from(format!("-D{}=", key));
+        arg.push(value);
+        command.arg(arg);
+    }
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let version =
+        std::fs::read_to_string(build_dir.join("version.txt")).ok()?;
+    Some(version.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+/// The CMake definitions for offline builds: `FetchContent` never downloads
+/// or updates anything, using only content that's already there, and tries
+/// `find_package` first for anything it's asked to fetch.
+///
+fn offline_definitions() -> Vec<(String, String)> {
+    vec![
+        (
+            "FETCHCONTENT_FULLY_DISCONNECTED".to_string(),
+            "ON".to_string(),
+        ),
+        (
+            "FETCHCONTENT_TRY_FIND_PACKAGE_MODE".to_string(),
+            "ALWAYS".to_string(),
+        ),
+    ]
+}
+
+/// Returns true if we're building for an Emscripten target such as
+/// `wasm32-unknown-emscripten`. The cmake crate runs CMake through
+/// `emcmake` and `emmake` for these, which sets up Emscripten's toolchain.
+///
+fn is_emscripten_target() -> bool {
+    std::env::var("TARGET").map_or(false, |t| t.contains("emscripten"))
+}
+
+/// The extra CMake definitions for the C wrapper on Emscripten. abigen is
+/// built as JavaScript to be run with node, and needs to use the real
+/// filesystem to be able to write out `abigen.txt`.
+///
+fn emscripten_definitions() -> Vec<(String, String)> {
+    vec![(
+        "CMAKE_EXE_LINKER_FLAGS".to_string(),
+        "-sNODERAWFS=1".to_string(),
+    )]
+}
+
+/// Find node to run the abigen built for Emscripten: the one `EMSDK_NODE`
+/// points to, as set by the emsdk environment scripts, or `node` on `PATH`.
+///
+fn find_node() -> Option<PathBuf> {
+    let node = std::env::var_os("EMSDK_NODE")
+        .filter(|node| !node.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("node"));
+    find_program(&node)
+}
+
+/// The CMake definitions to build the dependency `name` with shared or static
+/// libraries. Besides `BUILD_SHARED_LIBS`, some projects have options of
+/// their own for this, e.g. `OPENEXR_BUILD_SHARED` in OpenEXR 2 or
+/// `ALEMBIC_SHARED_LIBS`, so those are set too.
+///
+fn linkage_definitions(name: &str, shared: bool) -> Vec<(String, String)> {
+    let (on, off) = if shared { ("ON", "OFF") } else { ("OFF", "ON") };
+    let name = name.to_ascii_uppercase().replace('-', "_");
+    vec![
+        ("BUILD_SHARED_LIBS".to_string(), on.to_string()),
+        (format!("{}_BUILD_SHARED", name), on.to_string()),
+        (format!("{}_BUILD_STATIC", name), off.to_string()),
+        (format!("{}_SHARED_LIBS", name), on.to_string()),
+    ]
+}
+
+/// The default format of the C library's versioned name, see
+/// [`Builder::versioned_name_format`].
+///
+const DEFAULT_VERSIONED_NAME_FORMAT: &str = "{project}-c-{major}_{minor}";
+
+/// The default format when the crate has a non-zero patch version, see
+/// [`Builder::version`].
+///
+const DEFAULT_PATCH_VERSIONED_NAME_FORMAT: &str =
+    "{project}-c-{major}_{minor}_{patch}";
+
+/// Fill in a [`Builder::versioned_name_format`], or the default format for
+/// the version if none was given.
+///
+fn versioned_name(
+    format: Option<&str>,
+    project_name: &str,
+    major_version: u32,
+    minor_version: u32,
+    patch_version: u32,
+) -> String {
+    let format = match format {
+        Some(format) => format,
+        None if patch_version != 0 => DEFAULT_PATCH_VERSIONED_NAME_FORMAT,
+        None => DEFAULT_VERSIONED_NAME_FORMAT,
+    };
+    format
+        .replace("{project}", project_name)
+        .replace("{major}", &major_version.to_string())
+        .replace("{minor}", &minor_version.to_string())
+        .replace("{patch}", &patch_version.to_string())
+}
+
+/// Resolve a user-supplied install prefix against the crate root in
+/// `manifest_dir`, since the build script's working directory isn't
+/// something users should have to think about.
+///
+fn resolve_prefix(prefix: &Path, manifest_dir: &Path) -> PathBuf {
+    if prefix.is_absolute() {
+        prefix.to_path_buf()
+    } else {
+        manifest_dir.join(prefix)
+    }
+}
+
+/// The environment variable Cargo sets for build scripts when `feature` is
+/// enabled.
+///
+fn feature_env_var(feature: &str) -> String {
+    format!(
+        "CARGO_FEATURE_{}",
+        feature.to_ascii_uppercase().replace('-', "_")
+    )
+}
+
+/// Returns true if the Cargo feature `feature` is enabled for the crate whose
+/// build script is running.
+///
+fn feature_enabled(feature: &str) -> bool {
+    std::env::var_os(feature_env_var(feature)).is_some()
+}
+
+/// Interpret the value of a boolean environment variable. `1`, `true`, `yes`
+/// and `on` are true and `0`, `false`, `no`, `off` and the empty string are
+/// false, ignoring case. Anything else is false with a warning.
+///
+fn parse_bool_env(val: &str) -> bool {
+    match val.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => true,
+        "0" | "false" | "no" | "off" | "" => false,
+        _ => {
+            println!(
+                "cargo:warning=Expected a boolean like 1/0, true/false, \
+                 yes/no or on/off but got '{}', treating it as false",
+                val
+            );
+            false
+        }
+    }
+}
+
+/// Sort `dependencies` so that each one comes after everything in its
+/// `depends_on`, keeping the order they were given in otherwise.
+///
+/// Dependencies listed more than once are only built the first time, and a
+/// cycle in `depends_on` is an error.
+///
+fn build_order(
+    dependencies: &[DependencyOwned],
+) -> Result<Vec<&DependencyOwned>, BuildError> {
+    let mut unique: Vec<&DependencyOwned> = Vec::new();
+    for dep in dependencies {
+        if !unique.iter().any(|d| d.name == dep.name) {
+            unique.push(dep);
+        }
+    }
+
+    fn visit<'a>(
+        dep: &'a DependencyOwned,
+        unique: &[&'a DependencyOwned],
+        stack: &mut Vec<&'a str>,
+        order: &mut Vec<&'a DependencyOwned>,
+    ) -> Result<(), BuildError> {
+        if order.iter().any(|d| d.name == dep.name) {
+            return Ok(());
+        }
+        if let Some(pos) = stack.iter().position(|n| *n == dep.name) {
+            let mut cycle: Vec<String> =
+                stack[pos..].iter().map(|n| n.to_string()).collect();
+            cycle.push(dep.name.to_string());
+            return Err(BuildError::DependencyCycle(cycle));
+        }
+
+        stack.push(&dep.name);
+        for name in &dep.depends_on {
+            match unique.iter().find(|d| d.name == *name) {
+                Some(upstream) => visit(upstream, unique, stack, order)?,
+                None => println!(
+                    "cargo:warning={} depends on {}, which is not in the \
+                     list of dependencies to build",
+                    dep.name, name
+                ),
+            }
+        }
+        stack.pop();
+
+        order.push(dep);
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    for dep in &unique {
+        visit(dep, &unique, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Returns true if [`build`] (or [`Builder::build`]) for `project_name` will
+/// build the packaged dependencies, or false if it will use the ones already
+/// on the system.
+///
+/// That's decided by the environment: the dependencies are built unless
+/// `CMAKE_PREFIX_PATH` is set, but `CPPMM_<PROJECT>_BUILD_LIBRARIES` can
+/// force building them anyway.
+///
+pub fn will_build_libraries(project_name: &str) -> bool {
+    let env_build_libraries = format!(
+        "CPPMM_{}_BUILD_LIBRARIES",
+        project_name.to_ascii_uppercase()
+    );
+    build_libraries_decision(
+        std::env::var("CMAKE_PREFIX_PATH").is_ok(),
+        std::env::var(&env_build_libraries).ok().as_deref(),
+    )
+}
+
+/// [`will_build_libraries`] given whether `CMAKE_PREFIX_PATH` is set and the
+/// value of `CPPMM_<PROJECT>_BUILD_LIBRARIES`, if any.
+///
+fn build_libraries_decision(
+    prefix_path_set: bool,
+    build_libraries: Option<&str>,
+) -> bool {
+    // If the user has set CMAKE_PREFIX_PATH then we don't want to build the
+    // bundled libraries, *unless* they have also set CPPMM_<project_name>_BUILD_LIBRARIES=1
+    if prefix_path_set {
+        build_libraries.map_or(false, parse_bool_env)
+    } else {
+        true
+    }
+}
+
+/// Build a standard-formatted cppmm c wrapper project and its dependencies.
+///
+/// If the environment variable `CMAKE_PREFIX_PATH` is set, any `dependencies`
+/// will be assumed to be present on the system, available in `CMAKE_PREFIX_PATH`,
+/// apart from any with [`Dependency::force_build`] set, which are still built.
+/// If `CMAKE_PREFIX_PATH` is not set, the list of dependencies will be built
+/// from the `thirdparty` directory.
+///
+/// `project_name` controls the name of the generated C library, as well as the
+/// names of environment variables the user can set to control the build. For
+/// example, setting `project_name` to `openexr` will cause the script to respond
+/// to:
+/// * `CPPMM_OPENEXR_BUILD_LIBRARIES` - Ignore `CMAKE_PREFIX_PATH` and force
+///   building the dependencies if this is set to "1" (or "true", "yes", "on").
+/// * `CPPMM_OPENEXR_BUILD_TYPE` - Set the build profile used for the C library
+///   and all dependencies. This defaults to "Release" so you can use this to
+///   set it to "Debug", for example.
+/// * `CPPMM_OPENEXR_BUILD_JOBS` - Set the number of parallel jobs used to build
+///   the C library and all dependencies. Defaults to Cargo's job count.
+///   Individual dependencies can lower this with [`Dependency::max_jobs`].
+/// * `CPPMM_OPENEXR_PREFIX` - Install the dependencies into this directory
+///   instead of Cargo's target directory, so they can be shared between crates
+///   and survive a `cargo clean`. See [`Builder::install_prefix`].
+/// * `CPPMM_OPENEXR_CXXLIB` - Which C++ runtime to link: `auto` (the default),
+///   `stdc++`, `c++` or `none`. See [`Builder::cxx_stdlib`].
+/// * `CPPMM_OPENEXR_COMPILER_LAUNCHER` - Compile everything through this
+///   launcher, e.g. `ccache`. See [`Builder::compiler_launcher`].
+/// * `CPPMM_OPENEXR_CXX_STANDARD` - Build everything as this C++ standard,
+///   e.g. "17". See [`Builder::cxx_standard`].
+/// * `CPPMM_OPENEXR_OFFLINE` - Build without touching the network if this is
+///   set to "1" (or "true", "yes", "on"). `FetchContent` is disconnected so it
+///   only uses content that's already there, preferring installed packages,
+///   and every packaged dependency's sources are checked for before anything
+///   is built.
+/// * `CPPMM_OPENEXR_VERBOSE` - Show the full compiler and linker commands
+///   of every CMake build if this is set to "1" (or "true", "yes", "on"). See
+///   [`Builder::verbose`].
+/// * `CPPMM_OPENEXR_FIND_ROOT_PATH` - Have CMake look for libraries inside
+///   these roots, e.g. a cross-compiling sysroot, rather than on the host.
+///   See [`Builder::find_root_path`].
+/// * `CPPMM_OPENEXR_IGNORE_LIBS` - A comma-separated list of libraries not to
+///   link, even though CMake links them. See [`Builder::ignore_lib`].
+/// * `CPPMM_OPENEXR_REBUILD` - A comma-separated list of packaged dependencies
+///   to clean and rebuild from scratch. See [`clean_thirdparty`].
+/// * `CPPMM_OPENEXR_RETRIES` - Retry a packaged dependency this many times
+///   if it fails before compiling anything, e.g. because a download in its
+///   configure step timed out. Compile errors, and packages CMake couldn't
+///   find, are never retried. Defaults to 0.
+/// * `CPPMM_OPENEXR_ONLY` - A comma-separated list of the only packaged
+///   dependencies to build, e.g. while working on one of them. The others
+///   must already be installed from an earlier build, and are still linked.
+/// * `CPPMM_OPENEXR_SKIP_ABI` - Don't generate `cppmmabi.rs` if this is set
+///   to "1" (or "true", "yes", "on"). See [`Builder::skip_abi`] for when this is safe.
+/// * `CPPMM_OPENEXR_SKIP_VERSION_CHECK` - Don't check the versions of the
+///   dependencies found on the system against their
+///   [`Dependency::min_version`] and [`Dependency::max_version`] if this is
+///   set to "1" (or "true", "yes", "on").
+/// * `CPPMM_OPENEXR_REGEN_ABI` - Rerun abigen and regenerate `cppmmabi.rs`
+///   if this is set to "1" (or "true", "yes", "on"), even if they're already
+///   there. They're also regenerated whenever abigen has been rebuilt or the
+///   build type has changed.
+///
+/// `major_version` and `minor_version` are the crate version numbers and are
+/// baked into the C library filename.
+///
+pub fn build(
+    project_name: &str,
+    major_version: u32,
+    minor_version: u32,
+    dependencies: &[Dependency],
+) {
+    build_with_report(project_name, major_version, minor_version, dependencies);
+}
+
+/// Build a standard-formatted cppmm c wrapper project and its dependencies,
+/// exactly like [`build`], and return a [`BuildReport`] describing what was
+/// done.
+///
+pub fn build_with_report(
+    project_name: &str,
+    major_version: u32,
+    minor_version: u32,
+    dependencies: &[Dependency],
+) -> BuildReport {
+    Builder::new(project_name, major_version, minor_version)
+        .dependencies(dependencies)
+        .build()
+}
+
+/// Build a standard-formatted cppmm c wrapper project and its dependencies,
+/// exactly like [`build`], for dependencies constructed at runtime, e.g.
+/// from environment variables or a config file.
+///
+pub fn build_owned(
+    project_name: &str,
+    major_version: u32,
+    minor_version: u32,
+    dependencies: &[DependencyOwned],
+) {
+    Builder::new(project_name, major_version, minor_version)
+        .owned_dependencies(dependencies)
+        .build();
+}
+
+/// Build a standard-formatted cppmm c wrapper project like [`build`], with
+/// the dependencies read from the JSON manifest at `manifest_path` (see
+/// [`read_dependency_manifest`] for the format) rather than listed in the
+/// build script. Relative paths are relative to the crate root.
+///
+/// Like any other file in the package, changing the manifest reruns the
+/// build script. We don't print a `rerun-if-changed` for it, since that would
+/// stop Cargo rerunning it for changes to anything else, such as the C
+/// library's sources.
+///
+pub fn build_from_manifest(
+    project_name: &str,
+    major_version: u32,
+    minor_version: u32,
+    manifest_path: &Path,
+) {
+    let dependencies = read_dependency_manifest(manifest_path)
+        .unwrap_or_else(|e| panic!("{}", e));
+    build_owned(project_name, major_version, minor_version, &dependencies);
+}
+
+/// A summary of what a build did, e.g. for reporting on CI.
+///
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BuildReport {
+    /// The packaged dependencies that were built, in the order they were
+    /// built.
+    pub built_dependencies: Vec<String>,
+    /// The dependencies that were expected to be found on the system instead.
+    pub system_dependencies: Vec<String>,
+    /// The CMake build profile used, e.g. "Release".
+    pub profile: String,
+    /// The prefix packaged dependencies were installed into.
+    pub target_dir: PathBuf,
+    /// The link arguments parsed from the C wrapper's build.
+    pub link_args: Vec<LinkArg>,
+    /// What each dependency resolved to: whether it was built or found on
+    /// the system, its version, and its libraries. The build also writes
+    /// this to `OUT_DIR/cppmm-native-lock.json`.
+    pub resolved_dependencies: Vec<ResolvedDependency>,
+}
+
+/// Configures and runs the build of a cppmm C wrapper project and its
+/// dependencies, for when the defaults used by [`build`] aren't enough.
+///
+/// The same environment variables as [`build`] are respected.
+///
+/// In verbose mode, each dependency and the C wrapper is configured a second
+/// time from its cache after it's built, to pass on the warnings CMake
+/// printed as Cargo warnings. This can rewrite the files in the build
+/// directory, such as its `Makefile` or `build.ninja`, but doesn't change the
+/// settings they were generated with.
+///
+/// ```no_run
+/// use cppmm_build::{Builder, Dependency};
+///
+/// Builder::new("openexr", 0, 10)
+///     .dependency(Dependency {
+///         name: "zlib",
+///         ..Default::default()
+///     })
+///     .build();
+/// ```
+///
+#[derive(Debug)]
+pub struct Builder {
+    project_name: String,
+    major_version: u32,
+    minor_version: u32,
+    patch_version: u32,
+    dependencies: Vec<DependencyOwned>,
+    root_key: String,
+    lib_key: String,
+    include_key: String,
+    sentinel_symbol: Option<String>,
+    abi_in_base: AbiDirBase,
+    abi_out_base: AbiDirBase,
+    interface_link_libraries: bool,
+    feature_link_args: Vec<(String, LinkArg)>,
+    install_prefix: Option<PathBuf>,
+    cxx_stdlib: CxxStdlib,
+    compiler_launcher: Option<PathBuf>,
+    abigen_bin: Option<PathBuf>,
+    abigen_out: Option<PathBuf>,
+    abi_template_dir: Option<PathBuf>,
+    skip_abi: bool,
+    cflags: Vec<String>,
+    cxxflags: Vec<String>,
+    dependencies_only: bool,
+    whole_archive: Vec<String>,
+    relocatable: bool,
+    extra_prefix_paths: Vec<PathBuf>,
+    cxx_standard: Option<u32>,
+    glibcxx_cxx11_abi: Option<bool>,
+    osx_architectures: Vec<String>,
+    macos_deployment_target: Option<String>,
+    versioned_name_format: Option<String>,
+    runtime_search_path: RuntimeSearchPath,
+    extra_link_args: Vec<LinkArg>,
+    find_root_paths: Vec<PathBuf>,
+    find_root_path_modes: Vec<(String, String)>,
+    prefer_static_deps: bool,
+    clib_dir: Option<PathBuf>,
+    ignored_libs: Vec<String>,
+    c_compiler: Option<PathBuf>,
+    cxx_compiler: Option<PathBuf>,
+    abi_out_dir: Option<PathBuf>,
+    link_group: bool,
+    weak_frameworks: Vec<String>,
+    verbose: bool,
+    exported_symbol_prefix: Option<String>,
+    dependency_built: Vec<DependencyBuiltCallback>,
+}
+
+/// The signature of the callbacks for [`Builder::on_dependency_built`].
+type DependencyBuiltFn = dyn FnMut(&str, &Path);
+
+/// A callback registered with [`Builder::on_dependency_built`].
+struct DependencyBuiltCallback(Box<DependencyBuiltFn>);
+
+impl fmt::Debug for DependencyBuiltCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DependencyBuiltCallback")
+    }
+}
+
+/// Run the `callbacks` for the dependency `name`, which was just built in
+/// `build_dir` and installed into `prefix`.
+///
+/// If one of them panics, the dependency's stamp is removed so that it's
+/// built again, and the callbacks run again, by the next build, rather than
+/// being left looking up to date without them having run.
+///
+fn run_dependency_built(
+    callbacks: &mut [DependencyBuiltCallback],
+    name: &str,
+    build_dir: &Path,
+    prefix: &Path,
+) -> Result<(), BuildError> {
+    for DependencyBuiltCallback(callback) in callbacks {
+        if let Err(payload) = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            callback(name, prefix)
+        })) {
+            let _ = std::fs::remove_file(build_dir.join(".cppmm-stamp"));
+            return Err(BuildError::DependencyCallback {
+                dependency: name.to_string(),
+                message: panic_message(&*payload).to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The settings the C wrapper is configured with, other than where it looks
+/// for its dependencies. See [`Builder::shim_config`].
+///
+struct ShimSettings<'a> {
+    build_type: &'a str,
+    extra_prefix_paths: &'a [PathBuf],
+    compiler_launcher: Option<&'a Path>,
+    compilers: &'a Compilers,
+    cxx_standard: Option<u32>,
+    osx_architectures: Option<&'a str>,
+    deployment_target: Option<&'a str>,
+    /// The find root, offline and Emscripten definitions.
+    definitions: Vec<&'a (String, String)>,
+    cxxflags: &'a [String],
+    verbose: bool,
+}
+
+impl Builder {
+    /// Create a new `Builder` for the project `project_name`, at crate version
+    /// `major_version.minor_version`.
+    ///
+    pub fn new(
+        project_name: &str,
+        major_version: u32,
+        minor_version: u32,
+    ) -> Builder {
+        Builder {
+            project_name: project_name.to_string(),
+            major_version,
+            minor_version,
+            patch_version: 0,
+            dependencies: Vec::new(),
+            root_key: "root".to_string(),
+            lib_key: "lib".to_string(),
+            include_key: "include".to_string(),
+            sentinel_symbol: None,
+            abi_in_base: AbiDirBase::ManifestDir,
+            abi_out_base: AbiDirBase::OutDir,
+            interface_link_libraries: false,
+            feature_link_args: Vec::new(),
+            install_prefix: None,
+            cxx_stdlib: CxxStdlib::Auto,
+            compiler_launcher: None,
+            abigen_bin: None,
+            abigen_out: None,
+            abi_template_dir: None,
+            skip_abi: false,
+            cflags: Vec::new(),
+            cxxflags: Vec::new(),
+            dependencies_only: false,
+            whole_archive: Vec::new(),
+            relocatable: false,
+            extra_prefix_paths: Vec::new(),
+            cxx_standard: None,
+            glibcxx_cxx11_abi: None,
+            osx_architectures: Vec::new(),
+            macos_deployment_target: None,
+            versioned_name_format: None,
+            runtime_search_path: RuntimeSearchPath::Rpath,
+            extra_link_args: Vec::new(),
+            find_root_paths: Vec::new(),
+            find_root_path_modes: Vec::new(),
+            prefer_static_deps: false,
+            clib_dir: None,
+            ignored_libs: Vec::new(),
+            c_compiler: None,
+            cxx_compiler: None,
+            abi_out_dir: None,
+            link_group: false,
+            weak_frameworks: Vec::new(),
+            verbose: false,
+            exported_symbol_prefix: None,
+            dependency_built: Vec::new(),
+        }
+    }
+
+    /// Add a packaged dependency to be built.
+    ///
+    pub fn dependency(&mut self, dependency: Dependency) -> &mut Builder {
+        self.dependencies.push(dependency.into());
+        self
+    }
+
+    /// Add several packaged dependencies to be built, in order.
+    ///
+    pub fn dependencies(
+        &mut self,
+        dependencies: &[Dependency],
+    ) -> &mut Builder {
+        self.dependencies
+            .extend(dependencies.iter().map(DependencyOwned::from));
+        self
+    }
+
+    /// Add a packaged dependency to be built that owns its strings, e.g. one
+    /// whose name is only known at runtime.
+    ///
+    pub fn owned_dependency(
+        &mut self,
+        dependency: DependencyOwned,
+    ) -> &mut Builder {
+        self.dependencies.push(dependency);
+        self
+    }
+
+    /// Add several packaged dependencies to be built, in order, that own
+    /// their strings, e.g. from [`read_dependency_manifest`].
+    ///
+    pub fn owned_dependencies(
+        &mut self,
+        dependencies: &[DependencyOwned],
+    ) -> &mut Builder {
+        self.dependencies.extend(dependencies.iter().cloned());
+        self
+    }
+
+    /// Set the metadata key under which the install prefix is passed to
+    /// dependent crates. Defaults to "root", i.e. `DEP_<LINKS>_ROOT`.
+    ///
+    pub fn root_metadata_key(&mut self, key: &str) -> &mut Builder {
+        self.root_key = key.to_string();
+        self
+    }
+
+    /// Set the metadata key under which the library directory is passed to
+    /// dependent crates. Defaults to "lib", i.e. `DEP_<LINKS>_LIB`.
+    ///
+    pub fn lib_metadata_key(&mut self, key: &str) -> &mut Builder {
+        self.lib_key = key.to_string();
+        self
+    }
+
+    /// Set the metadata key under which the include directories are passed to
+    /// dependent crates. Defaults to "include", i.e. `DEP_<LINKS>_INCLUDE`.
+    ///
+    pub fn include_metadata_key(&mut self, key: &str) -> &mut Builder {
+        self.include_key = key.to_string();
+        self
+    }
+
+    /// Check that the built C wrapper library exports `symbol`, e.g. the
+    /// `openexr_version` function cppmm always generates, and fail the build
+    /// if it doesn't.
+    ///
+    /// A wrapper with broken visibility or export settings will happily build
+    /// without exporting anything, which otherwise only shows up as undefined
+    /// symbols when linking the downstream Rust crate. The check uses `nm` on
+    /// Unices and `dumpbin` on Windows, so they must be on the `PATH`.
+    ///
+    pub fn sentinel_symbol(&mut self, symbol: &str) -> &mut Builder {
+        self.sentinel_symbol = Some(symbol.to_string());
+        self
+    }
+
+    /// Set which directory the `cppmm_abi_in` ABI template directory is found
+    /// in. Defaults to [`AbiDirBase::ManifestDir`], i.e. next to Cargo.toml.
+    ///
+    pub fn abi_in_base(&mut self, base: AbiDirBase) -> &mut Builder {
+        self.abi_in_base = base;
+        self
+    }
+
+    /// Set which directory the `cppmm_abi_out` directory containing the
+    /// generated `cppmmabi.rs` is written to. Defaults to
+    /// [`AbiDirBase::OutDir`]. See [`Builder::abi_out_dir`] to choose the
+    /// directory itself.
+    ///
+    pub fn abi_out_base(&mut self, base: AbiDirBase) -> &mut Builder {
+        self.abi_out_base = base;
+        self
+    }
+
+    /// Also link the libraries listed in the `INTERFACE_LINK_LIBRARIES` of the
+    /// dependencies' installed CMake package configs.
+    ///
+    /// The linker can resolve a dependency's transitive libraries for the C
+    /// wrapper without them ever appearing on its link line, in which case
+    /// they would be missing from the final Rust link. This is a best-effort
+    /// supplement to parsing the link line: only libraries that can be
+    /// resolved to a file, or are plain system library names, are added.
+    ///
+    pub fn interface_link_libraries(&mut self, enable: bool) -> &mut Builder {
+        self.interface_link_libraries = enable;
+        self
+    }
+
+    /// Link `arg` only when the Cargo feature `feature` of the crate being
+    /// built is enabled.
+    ///
+    /// Cargo tells build scripts which features are enabled by setting
+    /// `CARGO_FEATURE_<NAME>`, where `<NAME>` is the feature name uppercased
+    /// with `-` replaced by `_`, which is what this checks.
+    ///
+    pub fn feature_link_arg(
+        &mut self,
+        feature: &str,
+        arg: LinkArg,
+    ) -> &mut Builder {
+        self.feature_link_args.push((feature.to_string(), arg));
+        self
+    }
+
+    /// Install the packaged dependencies into `prefix` rather than Cargo's
+    /// target directory, so that they survive a `cargo clean` and can be
+    /// shared between several wrapper crates. Relative paths are resolved
+    /// against the crate root.
+    ///
+    /// This can also be set with `CPPMM_<PROJECT>_PREFIX`, which takes
+    /// precedence.
+    ///
+    pub fn install_prefix<P: AsRef<Path>>(
+        &mut self,
+        prefix: P,
+    ) -> &mut Builder {
+        self.install_prefix = Some(prefix.as_ref().to_path_buf());
+        self
+    }
+
+    /// Choose which C++ runtime library is linked, if any. Defaults to
+    /// [`CxxStdlib::Auto`].
+    ///
+    /// This can also be set with `CPPMM_<PROJECT>_CXXLIB` to one of `auto`,
+    /// `stdc++`, `c++` or `none`, which takes precedence.
+    ///
+    pub fn cxx_stdlib(&mut self, cxx_stdlib: CxxStdlib) -> &mut Builder {
+        self.cxx_stdlib = cxx_stdlib;
+        self
+    }
+
+    /// Compile the dependencies and the C library through `launcher`, e.g.
+    /// `ccache` or `sccache`, so they can be cached between builds. This can
+    /// be a path or a program name to find on `PATH`, and is skipped with a
+    /// warning if it can't be found.
+    ///
+    /// This can also be set with `CPPMM_<PROJECT>_COMPILER_LAUNCHER`, which
+    /// takes precedence.
+    ///
+    pub fn compiler_launcher<P: AsRef<Path>>(
+        &mut self,
+        launcher: P,
+    ) -> &mut Builder {
+        self.compiler_launcher = Some(launcher.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the path of the abigen binary built by the C library. Relative
+    /// paths are resolved against the C library's CMake build directory.
+    /// Defaults to `abigen/abigen` (or the `abigen.exe` for the build type on
+    /// Windows).
+    ///
+    pub fn abigen_bin<P: AsRef<Path>>(&mut self, path: P) -> &mut Builder {
+        self.abigen_bin = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the path abigen writes the ABI info to. Relative paths are
+    /// resolved against the C library's CMake build directory. Defaults to
+    /// `abigen.txt`.
+    ///
+    pub fn abigen_out<P: AsRef<Path>>(&mut self, path: P) -> &mut Builder {
+        self.abigen_out = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the directory containing the `insert_abi.py` script that fills in
+    /// the ABI templates. Relative paths are resolved against the crate root.
+    /// Defaults to `abigen` in the C library's directory, see
+    /// [`Builder::clib_dir`].
+    ///
+    pub fn abi_template_dir<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> &mut Builder {
+        self.abi_template_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Skip generating `cppmmabi.rs` entirely, i.e. don't run abigen or
+    /// `insert_abi.py`.
+    ///
+    /// This is only safe if the crate doesn't `include!` the generated file,
+    /// or you provide it yourself and it was generated for the same platform
+    /// and (on Windows) build type, since the sizes of the opaque types it
+    /// describes differ between them. Getting it wrong means memory
+    /// corruption, not a build error.
+    ///
+    /// This can also be set with `CPPMM_<PROJECT>_SKIP_ABI=1`.
+    ///
+    pub fn skip_abi(&mut self, skip_abi: bool) -> &mut Builder {
+        self.skip_abi = skip_abi;
+        self
+    }
+
+    /// Add a flag to pass to the C compiler when building the dependencies
+    /// and the C library.
+    ///
+    /// Flags in `CFLAGS` (and the target-specific variants the `cc` crate
+    /// reads) are passed on too, without needing this.
+    ///
+    pub fn cflag(&mut self, flag: &str) -> &mut Builder {
+        self.cflags.push(flag.to_string());
+        self
+    }
+
+    /// Add a flag to pass to the C++ compiler when building the dependencies
+    /// and the C library.
+    ///
+    /// Flags in `CXXFLAGS` (and the target-specific variants the `cc` crate
+    /// reads) are passed on too, without needing this.
+    ///
+    pub fn cxxflag(&mut self, flag: &str) -> &mut Builder {
+        self.cxxflags.push(flag.to_string());
+        self
+    }
+
+    /// Only build and install the packaged dependencies, without building the
+    /// C library, generating the ABI or emitting any link directives. The
+    /// `root` and `lib` metadata are still emitted.
+    ///
+    /// Use this with [`Builder::install_prefix`] to populate a shared prefix,
+    /// e.g. in an early CI stage, that later builds then pick up through
+    /// `CMAKE_PREFIX_PATH`.
+    ///
+    pub fn dependencies_only(
+        &mut self,
+        dependencies_only: bool,
+    ) -> &mut Builder {
+        self.dependencies_only = dependencies_only;
+        self
+    }
+
+    /// Link every object in the static archive of the library `libname`, not
+    /// just the ones that something refers to, e.g. `usd_ms` for `libusd_ms.a`.
+    ///
+    /// This is needed for libraries that register types or plugins from
+    /// static initializers, which otherwise get dropped by the linker and
+    /// fail to register at runtime. It's only supported on Unices.
+    ///
+    pub fn whole_archive(&mut self, libname: &str) -> &mut Builder {
+        self.whole_archive.push(libname.to_string());
+        self
+    }
+
+    /// On macOS, rewrite the install names of the dylibs built for the
+    /// packaged dependencies to `@rpath/...`, and give this crate's binaries
+    /// rpaths to find them next to the executable, so they keep working when
+    /// the binaries and dylibs are moved together. Defaults to `false`, and
+    /// does nothing on other platforms.
+    ///
+    /// This needs `install_name_tool`, which comes with the Xcode command
+    /// line tools. Note that Cargo only applies the rpaths to the binaries of
+    /// the crate being built, so dependent crates need to add their own.
+    ///
+    pub fn relocatable(&mut self, relocatable: bool) -> &mut Builder {
+        self.relocatable = relocatable;
+        self
+    }
+
+    /// Add a prefix to search for the C library's own dependencies, after the
+    /// packaged dependencies, e.g. for a system package that only the C
+    /// library needs. Relative paths are resolved against the crate root.
+    /// Can be called more than once.
+    ///
+    pub fn extra_prefix_path<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> &mut Builder {
+        self.extra_prefix_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Build the packaged dependencies and the C library all as the same C++
+    /// standard, e.g. 17, by setting `CMAKE_CXX_STANDARD` (and
+    /// `CMAKE_CXX_STANDARD_REQUIRED`) for all of them. This overrides any
+    /// standard the dependencies set in their definitions.
+    ///
+    /// By default the standard is left to each project. Mixing standards can
+    /// make libraries ABI incompatible, e.g. in the layout of standard
+    /// library types, which shows up as crashes at runtime rather than link
+    /// errors.
+    ///
+    pub fn cxx_standard(&mut self, standard: u32) -> &mut Builder {
+        self.cxx_standard = Some(standard);
+        self
+    }
+
+    /// Build the packaged dependencies and the C library all with the new
+    /// (`true`) or old (`false`) libstdc++ ABI, by defining
+    /// `_GLIBCXX_USE_CXX11_ABI` for every C++ compile. By default it's left
+    /// to the compiler's own default.
+    ///
+    /// This is the fix for the classic VFX Platform link failure, where
+    /// libraries built against the old ABI (as older VFX Reference Platform
+    /// years required) are linked with ones built against the new one, giving
+    /// undefined references to `std::__cxx11::basic_string` and friends. It
+    /// has no effect with other standard libraries.
+    ///
+    pub fn glibcxx_cxx11_abi(&mut self, cxx11_abi: bool) -> &mut Builder {
+        self.glibcxx_cxx11_abi = Some(cxx11_abi);
+        self
+    }
+
+    /// On macOS, build the packaged dependencies and the C library for all of
+    /// `architectures`, e.g. `&["x86_64", "arm64"]` for universal binaries,
+    /// by setting `CMAKE_OSX_ARCHITECTURES`. Does nothing on other platforms.
+    ///
+    /// Every library that gets linked has to be built for all of them, so any
+    /// dependencies from the system (or another prefix) need to be universal
+    /// too. The libraries linked are checked with `lipo`, with a warning for
+    /// any that are missing an architecture.
+    ///
+    pub fn osx_architectures(
+        &mut self,
+        architectures: &[&str],
+    ) -> &mut Builder {
+        self.osx_architectures =
+            architectures.iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    /// On macOS, build the packaged dependencies and the C library for
+    /// `target` as the minimum macOS version, e.g. `"10.15"`, by setting
+    /// `CMAKE_OSX_DEPLOYMENT_TARGET`. Does nothing on other platforms.
+    ///
+    /// If `MACOSX_DEPLOYMENT_TARGET` is set that's used instead, with a
+    /// warning if it's different, since rustc and clang read it too and the
+    /// C++ and Rust halves have to agree. Without either CMake picks its own
+    /// default, which can give "built for newer macOS version" warnings when
+    /// linking.
+    ///
+    pub fn macos_deployment_target(&mut self, target: &str) -> &mut Builder {
+        self.macos_deployment_target = Some(target.to_string());
+        self
+    }
+
+    /// Set the format of the C library's versioned name, for projects that
+    /// don't follow cppmm's default of `{project}-c-{major}_{minor}`.
+    /// `{project}`, `{major}`, `{minor}` and `{patch}` are replaced with the
+    /// project name and version, so a format without them is taken as the name
+    /// itself.
+    ///
+    /// This is the name of the static library and its CMake target, and the
+    /// shared library and its target add `-shared` to it.
+    ///
+    pub fn versioned_name_format(&mut self, format: &str) -> &mut Builder {
+        self.versioned_name_format = Some(format.to_string());
+        self
+    }
+
+    /// Set the crate version, including a patch version, replacing the one
+    /// given to [`Builder::new`].
+    ///
+    /// A non-zero `patch_version` is added to the default versioned name, so
+    /// the C library is built as `{project}-c-{major}_{minor}_{patch}`. A zero
+    /// patch version keeps the two-part name.
+    ///
+    pub fn version(
+        &mut self,
+        major_version: u32,
+        minor_version: u32,
+        patch_version: u32,
+    ) -> &mut Builder {
+        self.major_version = major_version;
+        self.minor_version = minor_version;
+        self.patch_version = patch_version;
+        self
+    }
+
+    /// Choose how this crate's binaries find the packaged dependencies'
+    /// dynamic libraries at runtime, so `cargo run` and `cargo test` work
+    /// without setting up a library path by hand. Defaults to
+    /// [`RuntimeSearchPath::Rpath`].
+    ///
+    pub fn runtime_search_path(
+        &mut self,
+        runtime_search_path: RuntimeSearchPath,
+    ) -> &mut Builder {
+        self.runtime_search_path = runtime_search_path;
+        self
+    }
+
+    /// Add `path` to the library search path, after the directories found on
+    /// the CMake link line, for when the link line doesn't account for
+    /// everything, e.g. a system directory of driver libraries.
+    ///
+    pub fn extra_link_search<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> &mut Builder {
+        self.extra_link_args
+            .push(LinkArg::LinkDir(path.as_ref().display().to_string()));
+        self
+    }
+
+    /// Link the library `name` after the libraries found on the CMake link
+    /// line. `kind` is the `rustc-link-lib` kind to link it as, e.g. `dylib`,
+    /// `static` or `framework`.
+    ///
+    pub fn extra_link_lib(&mut self, kind: &str, name: &str) -> &mut Builder {
+        let lib = if kind == "dylib" {
+            name.to_string()
+        } else {
+            format!("{}={}", kind, name)
+        };
+        self.extra_link_args.push(LinkArg::LinkLib(lib));
+        self
+    }
+
+    /// Add a root for CMake's `find_*` commands to search inside instead of
+    /// the host, i.e. `CMAKE_FIND_ROOT_PATH`, for cross builds against a
+    /// sysroot. Relative paths are resolved against the crate root. Can be
+    /// called more than once. Nothing is set by default.
+    ///
+    /// This can also be set with `CPPMM_<PROJECT>_FIND_ROOT_PATH`, a list of
+    /// paths separated like `PATH`, which replaces the roots given here.
+    ///
+    /// CMake searches for each entry of `CMAKE_PREFIX_PATH` inside every
+    /// root, so system prefixes should be given as they are inside the
+    /// sysroot, e.g. `/usr`. Unless changed with
+    /// [`Builder::find_root_path_mode`], programs are only looked for on the
+    /// host and libraries and headers only in the roots, while packages are
+    /// looked for in both so the packaged dependencies, which are installed
+    /// outside the roots, are still found.
+    ///
+    pub fn find_root_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Builder {
+        self.find_root_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set `CMAKE_FIND_ROOT_PATH_MODE_<kind>` to `mode` when there are find
+    /// roots, see [`Builder::find_root_path`]. `kind` is one of `PROGRAM`,
+    /// `LIBRARY`, `INCLUDE` or `PACKAGE`, and `mode` one of `NEVER`, `ONLY` or
+    /// `BOTH`.
+    ///
+    pub fn find_root_path_mode(
+        &mut self,
+        kind: &str,
+        mode: &str,
+    ) -> &mut Builder {
+        let kind = kind.to_ascii_uppercase();
+        self.find_root_path_modes.retain(|(k, _)| *k != kind);
+        self.find_root_path_modes
+            .push((kind, mode.to_ascii_uppercase()));
+        self
+    }
+
+    /// Build the packaged dependencies as static libraries, so they're linked
+    /// into this crate's binaries and there are no shared libraries to ship
+    /// with them. Defaults to `false`, which builds whatever each dependency
+    /// builds by default, usually shared libraries.
+    ///
+    /// This sets `BUILD_SHARED_LIBS=OFF`, along with the similar options some
+    /// projects have of their own (see [`Dependency::shared`] to build a
+    /// dependency shared anyway). Definitions a dependency gives itself win.
+    ///
+    pub fn prefer_static_deps(&mut self, prefer_static: bool) -> &mut Builder {
+        self.prefer_static_deps = prefer_static;
+        self
+    }
+
+    /// Set the directory containing the cppmm-generated C library, for
+    /// layouts where it isn't `<project>-c` in the crate root. Relative paths
+    /// are resolved against the crate root.
+    ///
+    pub fn clib_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Builder {
+        self.clib_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Don't link the library `name`, even though it's on the link line CMake
+    /// gives us, e.g. to provide a conflicting `libz` yourself. `name` is
+    /// matched against the name a library is linked by or the file name of
+    /// its path, i.e. `z` or `libz.so.1`. Call this once for each library.
+    ///
+    /// More libraries can be given in `CPPMM_<PROJECT>_IGNORE_LIBS`, as a
+    /// comma-separated list.
+    ///
+    pub fn ignore_lib(&mut self, name: &str) -> &mut Builder {
+        self.ignored_libs.push(name.to_string());
+        self
+    }
+
+    /// Build the dependencies and the C library with the C compiler `cc` and
+    /// C++ compiler `cxx`, e.g. `clang` and `clang++` or `/usr/bin/g++-12`.
+    /// These can be paths or program names to find on `PATH`.
+    ///
+    /// Without this, `CC` and `CXX` are used if they're set, as are the
+    /// target-specific `CC_<target>` and `CXX_<target>`, otherwise CMake
+    /// picks. Either way, everything is built with the same compilers, since
+    /// mixing them can make the C++ ABIs differ.
+    ///
+    pub fn compiler<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        cc: P,
+        cxx: Q,
+    ) -> &mut Builder {
+        self.c_compiler = Some(cc.as_ref().to_path_buf());
+        self.cxx_compiler = Some(cxx.as_ref().to_path_buf());
+        self
+    }
+
+    /// Write the generated `cppmmabi.rs` into the directory `path`, for
+    /// crates that `include!` it from a fixed location. Relative paths are
+    /// resolved against the crate root. This takes precedence over
+    /// [`Builder::abi_out_base`], and defaults to `OUT_DIR/cppmm_abi_out`.
+    ///
+    pub fn abi_out_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Builder {
+        self.abi_out_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Wrap the dependency libraries in `--start-group`/`--end-group` when
+    /// linking this crate's binaries on Linux, so libraries that refer to
+    /// each other's symbols (as USD's do) link whatever order they come in.
+    /// Defaults to `false`. It does nothing on macOS, whose linker already
+    /// searches the libraries repeatedly, or on Windows.
+    ///
+    /// The linker searches the whole group again for as long as that
+    /// resolves anything new, which can make linking against large static
+    /// archives noticeably slower, so only turn this on if you need it. The
+    /// libraries are passed with `rustc-link-arg`, which Cargo only applies
+    /// to this crate's own binaries, tests and examples.
+    ///
+    pub fn link_group(&mut self, enabled: bool) -> &mut Builder {
+        self.link_group = enabled;
+        self
+    }
+
+    /// Weakly link the macOS framework `name`, e.g. `Metal`, so binaries
+    /// still run on macOS versions that don't have it (or are missing some
+    /// of its symbols), which then resolve to null. Can be called more than
+    /// once.
+    ///
+    /// The framework is linked with `-Wl,-weak_framework,<name>` whether or
+    /// not it's on the CMake link line, instead of the `framework=` library
+    /// any `-framework <name>` there becomes. Frameworks not listed here are
+    /// linked normally. This does nothing on other platforms.
+    ///
+    pub fn weak_framework(&mut self, name: &str) -> &mut Builder {
+        self.weak_frameworks.push(name.to_string());
+        self
+    }
+
+    /// Show the full compiler and linker commands of the builds of the
+    /// packaged dependencies and the C wrapper, to find out why one of them
+    /// failed to compile. Defaults to `false`, and can also be turned on with
+    /// `CPPMM_<PROJECT>_VERBOSE=1`.
+    ///
+    /// This sets `CMAKE_VERBOSE_MAKEFILE=ON` on every configure and builds
+    /// with `VERBOSE=1`, which `cmake --build` takes as `--verbose`.
+    ///
+    pub fn verbose(&mut self, verbose: bool) -> &mut Builder {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Check that the built C wrapper library exports at least one symbol
+    /// starting with `prefix`, e.g. `openexr_` for the functions cppmm
+    /// generates for `openexr`, and fail the build if it doesn't. Off by
+    /// default.
+    ///
+    /// This is like [`Builder::sentinel_symbol`] for when there's no one
+    /// function that's sure to be there, and has the same requirements.
+    ///
+    pub fn exported_symbol_prefix(&mut self, prefix: &str) -> &mut Builder {
+        self.exported_symbol_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Call `callback` after each packaged dependency is built and installed,
+    /// with the dependency's name and the prefix it was installed into, e.g.
+    /// to patch up a pkg-config file or strip the libraries it installed.
+    /// Several callbacks can be added, and are called in the order they were
+    /// added.
+    ///
+    /// Dependencies are built in order of their [`Dependency::depends_on`],
+    /// so by the time the callback is called for a dependency, it has
+    /// already been called for everything that dependency depends on. It
+    /// isn't called for dependencies that were up to date and so weren't
+    /// built, nor for ones found on the system.
+    ///
+    /// If the callback panics, the build fails with
+    /// [`BuildError::DependencyCallback`], and the dependency is built (and
+    /// the callback called) again by the next build.
+    ///
+    pub fn on_dependency_built<F>(&mut self, callback: F) -> &mut Builder
+    where
+        F: FnMut(&str, &Path) + 'static,
+    {
+        self.dependency_built
+            .push(DependencyBuiltCallback(Box::new(callback)));
+        self
+    }
+
+    /// The extra flags for the C++ compiler, including the libstdc++ ABI
+    /// define if one was chosen.
+    fn cxx_flags(&self) -> Vec<String> {
+        let mut flags = self.cxxflags.clone();
+        if let Some(cxx11_abi) = self.glibcxx_cxx11_abi {
+            flags.push(format!(
+                "-D_GLIBCXX_USE_CXX11_ABI={}",
+                if cxx11_abi { 1 } else { 0 }
+            ));
+        }
+        flags
+    }
+
+    /// The CMake configuration for the C wrapper in `clib_dir`, finding its
+    /// dependencies in `prefix` before anywhere else.
+    fn shim_config(
+        &self,
+        clib_dir: &Path,
+        prefix: Option<&Path>,
+        settings: &ShimSettings,
+    ) -> cmake::Config {
+        let mut config = cmake::Config::new(clib_dir);
+        config
+            .define("CMAKE_EXPORT_COMPILE_COMMANDS", "ON")
+            .profile(settings.build_type);
+        // CMake searches the CMAKE_PREFIX_PATH environment variable after
+        // this, so the prefix comes first
+        if let Some(path) =
+            shim_prefix_path(prefix, settings.extra_prefix_paths)
+        {
+            config.define("CMAKE_PREFIX_PATH", path);
+        }
+        if let Some(launcher) = settings.compiler_launcher {
+            set_compiler_launcher(&mut config, launcher);
+        }
+        settings.compilers.apply(&mut config);
+        if let Some(standard) = settings.cxx_standard {
+            set_cxx_standard(&mut config, standard);
+        }
+        if let Some(archs) = settings.osx_architectures {
+            config.define("CMAKE_OSX_ARCHITECTURES", archs);
+        }
+        if let Some(target) = settings.deployment_target {
+            config.define("CMAKE_OSX_DEPLOYMENT_TARGET", target);
+        }
+        for (key, value) in &settings.definitions {
+            config.define(key, value);
+        }
+        add_compiler_flags(&mut config, &self.cflags, settings.cxxflags);
+        set_verbose_makefile(&mut config, settings.verbose);
+        config
+    }
+
+    /// Run the build.
+    ///
+    /// As well as the linker directives, this emits metadata describing where
+    /// things ended up: the install prefix, the library directory and the
+    /// include directories the C wrapper was compiled with. Cargo only passes
+    /// these on to dependent crates (as e.g. `DEP_OPENEXR_ROOT`) if the crate
+    /// calling this sets the `links` key in its Cargo.toml, e.g.
+    /// `links = "openexr"`.
+    ///
+    pub fn build(&mut self) -> BuildReport {
+        match self.try_build() {
+            Ok(report) => report,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Run the build, like [`Builder::build`], but returning an error instead
+    /// of panicking when one of the build steps fails.
+    ///
+    pub fn try_build(&mut self) -> Result<BuildReport, BuildError> {
+        let project_name = self.project_name.as_str();
+        let major_version = self.major_version;
+        let minor_version = self.minor_version;
+        // Only build the dependencies for the features that are turned on
+        let dependencies: Vec<DependencyOwned> = self
+            .dependencies
+            .iter()
+            .filter(|d| d.feature.as_deref().map_or(true, feature_enabled))
+            .cloned()
+            .collect();
+        let dependencies = dependencies.as_slice();
+
+        check_cmake()?;
+
+        let env_build_type =
+            format!("CPPMM_{}_BUILD_TYPE", project_name.to_ascii_uppercase());
+        let env_build_jobs =
+            format!("CPPMM_{}_BUILD_JOBS", project_name.to_ascii_uppercase());
+        let env_retries =
+            format!("CPPMM_{}_RETRIES", project_name.to_ascii_uppercase());
+        let env_prefix =
+            format!("CPPMM_{}_PREFIX", project_name.to_ascii_uppercase());
+        let env_cxxlib =
+            format!("CPPMM_{}_CXXLIB", project_name.to_ascii_uppercase());
+        let env_rebuild =
+            format!("CPPMM_{}_REBUILD", project_name.to_ascii_uppercase());
+        let env_only =
+            format!("CPPMM_{}_ONLY", project_name.to_ascii_uppercase());
+        let env_offline =
+            format!("CPPMM_{}_OFFLINE", project_name.to_ascii_uppercase());
+        let env_verbose =
+            format!("CPPMM_{}_VERBOSE", project_name.to_ascii_uppercase());
+        let env_ignore_libs =
+            format!("CPPMM_{}_IGNORE_LIBS", project_name.to_ascii_uppercase());
+        let env_skip_abi =
+            format!("CPPMM_{}_SKIP_ABI", project_name.to_ascii_uppercase());
+        let env_regen_abi =
+            format!("CPPMM_{}_REGEN_ABI", project_name.to_ascii_uppercase());
+        let env_skip_version_check = format!(
+            "CPPMM_{}_SKIP_VERSION_CHECK",
+            project_name.to_ascii_uppercase()
+        );
+        let env_build_libraries = format!(
+            "CPPMM_{}_BUILD_LIBRARIES",
+            project_name.to_ascii_uppercase()
+        );
+        let env_find_root_path = format!(
+            "CPPMM_{}_FIND_ROOT_PATH",
+            project_name.to_ascii_uppercase()
+        );
+        let env_compiler_launcher = format!(
+            "CPPMM_{}_COMPILER_LAUNCHER",
+            project_name.to_ascii_uppercase()
+        );
+        let env_cxx_standard =
+            format!("CPPMM_{}_CXX_STANDARD", project_name.to_ascii_uppercase());
+
+        let build_libraries = will_build_libraries(project_name);
+        // Even when the rest come from the system, some dependencies may
+        // have to be built from thirdparty
+        let from_source =
+            |dep: &DependencyOwned| build_libraries || dep.force_build;
+        let builds_dependencies = dependencies.iter().any(from_source);
+
+        // Paths from the environment are kept as they are, since they needn't
+        // be valid UTF-8
+        let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+        let manifest_dir =
+            PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+
+        // e.g. target/debug, where Cargo puts the final binaries
+        let profile_dir = Path::new(&out_dir).ancestors().nth(3).unwrap();
+
+        // Install into the user's prefix if they gave one, otherwise into
+        // Cargo's target directory
+        let install_prefix = std::env::var_os(&env_prefix)
+            .map(PathBuf::from)
+            .or_else(|| self.install_prefix.clone());
+        let target_dir = match &install_prefix {
+            Some(prefix) => resolve_prefix(prefix, Path::new(&manifest_dir)),
+            None => profile_dir.to_path_buf(),
+        };
+        let target_dir = target_dir.as_path();
+        let extra_prefix_paths: Vec<PathBuf> = self
+            .extra_prefix_paths
+            .iter()
+            .map(|p| resolve_prefix(p, &manifest_dir))
+            .collect();
+        let find_root_paths: Vec<PathBuf> =
+            match std::env::var_os(&env_find_root_path) {
+                Some(paths) => std::env::split_paths(&paths).collect(),
+                None => self.find_root_paths.clone(),
+            };
+        let find_root = find_root_definitions(
+            &find_root_paths
+                .iter()
+                .map(|p| resolve_prefix(p, &manifest_dir))
+                .collect::<Vec<_>>(),
+            &self.find_root_path_modes,
+        )?;
+        let offline =
+            std::env::var(&env_offline).map_or(false, |v| parse_bool_env(&v));
+        let offline_definitions = if offline {
+            offline_definitions()
+        } else {
+            Vec::new()
+        };
+        let emscripten = is_emscripten_target();
+        let emscripten_definitions = if emscripten {
+            emscripten_definitions()
+        } else {
+            Vec::new()
+        };
+
+        let clib_name = format!("{}-c", project_name);
+        let clib_dir = match &self.clib_dir {
+            Some(dir) => resolve_prefix(dir, &manifest_dir),
+            None => PathBuf::from(&clib_name),
+        };
+        // Check before building anything, since CMake's own error for this
+        // doesn't make it obvious what's missing
+        if !self.dependencies_only && !clib_dir.join("CMakeLists.txt").is_file()
+        {
+            return Err(BuildError::MissingWrapper(clib_dir));
+        }
+        let clib_versioned_name = versioned_name(
+            self.versioned_name_format.as_deref(),
+            project_name,
+            major_version,
+            minor_version,
+            self.patch_version,
+        );
+        let clib_shared_versioned_name =
+            format!("{}-shared", clib_versioned_name);
+
+        let lib_path = lib_dir(target_dir);
+        let bin_path = bin_dir(target_dir);
+
+        // allow user to override build type with environment variables
+        let build_type = if let Ok(build_type) = std::env::var(&env_build_type)
+        {
+            build_type
+        } else {
+            "Release".to_string()
+        };
+
+        let retries = parse_env::<u32>(&env_retries)?.unwrap_or(0);
+        let build_jobs = parse_env::<usize>(&env_build_jobs)?;
+        let cxx_standard =
+            parse_env::<u32>(&env_cxx_standard)?.or(self.cxx_standard);
+
+        let cxxflags = self.cxx_flags();
+        let (deployment_target, warning) = macos_deployment_target(
+            self.macos_deployment_target.as_deref(),
+            std::env::var("MACOSX_DEPLOYMENT_TARGET").ok().as_deref(),
+        );
+        let deployment_target =
+            deployment_target.filter(|_| cfg!(target_os = "macos"));
+        if let Some(warning) = warning.filter(|_| cfg!(target_os = "macos")) {
+            println!("cargo:warning={}", warning);
+        }
+        // A CMake list, so separated with ;
+        let osx_architectures = Some(self.osx_architectures.join(";"))
+            .filter(|a| cfg!(target_os = "macos") && !a.is_empty());
+
+        let compiler_launcher = std::env::var_os(&env_compiler_launcher)
+            .map(PathBuf::from)
+            .or_else(|| self.compiler_launcher.clone())
+            .and_then(|launcher| {
+                let found = find_program(&launcher);
+                if found.is_none() {
+                    println!(
+                        "cargo:warning=Compiler launcher {} not found, \
+                         compiling without it",
+                        launcher.display()
+                    );
+                }
+                found
+            });
+        let compilers = Compilers::resolve(
+            self.c_compiler.as_deref(),
+            self.cxx_compiler.as_deref(),
+        );
+        let verbose = self.verbose
+            || std::env::var(&env_verbose)
+                .map_or(false, |v| parse_bool_env(&v));
+        let _verbose = if verbose {
+            Some(VerboseOverride::new())
+        } else {
+            None
+        };
+
+        // Wall-clock time spent in CMake, reported as we go since the heavy
+        // dependencies can take a long time to build
+        let mut native_build_time = Duration::default();
+
+        let mut built_dependencies = Vec::new();
+        // Whether anything has to be relinked into the wrapper
+        let mut rebuilt_dependency = false;
+        let mut directives = LinkDirectives::default();
+        // What the dependencies installed, since on Emscripten there's no
+        // link line for the wrapper to find them on
+        let mut installed_libs = Vec::new();
+        // The libraries each dependency from thirdparty installed
+        let mut built_libraries: Vec<(String, Vec<PathBuf>)> = Vec::new();
+        // Where each of those was built, for its install manifest
+        let mut dependency_build_dirs = Vec::new();
+        if builds_dependencies {
+            let source_dependencies: Vec<DependencyOwned> = dependencies
+                .iter()
+                .filter(|d| from_source(d))
+                .cloned()
+                .collect();
+            println!(
+                "cargo:warning=Building packaged dependencies {:?}",
+                source_dependencies
+            );
+            for warning in
+                cxx_standard_warnings(&source_dependencies, cxx_standard)
+            {
+                println!("cargo:warning={}", warning);
+            }
+            // Just the dependencies being worked on, if asked. The rest are
+            // assumed to be installed already, and still linked.
+            let only: Option<Vec<String>> =
+                std::env::var(&env_only).ok().map(|names| {
+                    names
+                        .split(',')
+                        .map(|name| name.trim().to_string())
+                        .filter(|name| !name.is_empty())
+                        .collect()
+                });
+            for name in only.iter().flatten() {
+                if !dependencies.iter().any(|d| d.name == *name) {
+                    println!(
+                        "cargo:warning={} names {}, which isn't one of the \
+                         dependencies {:?}",
+                        env_only, name, dependencies
+                    );
+                }
+            }
+            let selected = |dep: &DependencyOwned| {
+                only.as_ref().map_or(true, |only| only.contains(&dep.name))
+            };
+            // Nothing can be fetched later, so find out now rather than
+            // after building half of them
+            if offline {
+                for dep in source_dependencies.iter().filter(|d| selected(d)) {
+                    let source_dir = dependency_source_dir(dep);
+                    if !source_dir.join("CMakeLists.txt").exists() {
+                        return Err(BuildError::MissingSource {
+                            dependency: dep.name.to_string(),
+                            source_dir,
+                        });
+                    }
+                }
+            }
+            let rebuild = std::env::var(&env_rebuild).unwrap_or_default();
+            for dep in build_order(dependencies)?
+                .into_iter()
+                .filter(|d| from_source(d))
+            {
+                let mut thirdparty = ThirdpartyBuild::new(
+                    &dep.name,
+                    target_dir,
+                    &build_type,
+                    &str_pairs(&dep.definitions),
+                );
+                thirdparty.source_dir = dependency_source_dir(dep);
+                thirdparty.jobs = if dep.parallel {
+                    dependency_jobs(dep.max_jobs, build_jobs)
+                } else {
+                    Some(1)
+                };
+                thirdparty.compiler_launcher = compiler_launcher.clone();
+                thirdparty.compilers = compilers.clone();
+                thirdparty.verbose = verbose;
+                thirdparty.retries = retries;
+                thirdparty.cflags = self.cflags.clone();
+                thirdparty.cxxflags = cxxflags.clone();
+                thirdparty.set_policies(&str_pairs(&dep.policies));
+                if let Some(standard) = cxx_standard {
+                    thirdparty
+                        .define("CMAKE_CXX_STANDARD", &standard.to_string());
+                    thirdparty.define("CMAKE_CXX_STANDARD_REQUIRED", "ON");
+                }
+                if let Some(archs) = &osx_architectures {
+                    thirdparty.define("CMAKE_OSX_ARCHITECTURES", archs);
+                }
+                if let Some(target) = &deployment_target {
+                    thirdparty.define("CMAKE_OSX_DEPLOYMENT_TARGET", target);
+                }
+                for (key, value) in &find_root {
+                    thirdparty.define(key, value);
+                }
+                for (key, value) in &offline_definitions {
+                    if !dep.definitions.iter().any(|(k, _)| k == key) {
+                        thirdparty.define(key, value);
+                    }
+                }
+                // Emscripten can't load shared libraries built this way
+                let shared = if emscripten {
+                    Some(false)
+                } else {
+                    dep.shared
+                        .or(Some(false).filter(|_| self.prefer_static_deps))
+                };
+                if let Some(shared) = shared {
+                    for (key, value) in linkage_definitions(&dep.name, shared) {
+                        if !dep.definitions.iter().any(|(k, _)| *k == key) {
+                            thirdparty.define(&key, &value);
+                        }
+                    }
+                }
+
+                if !selected(dep) {
+                    built_libraries.push((
+                        dep.name.clone(),
+                        installed_libraries(&thirdparty.build_dir()),
+                    ));
+                    dependency_build_dirs.push(thirdparty.build_dir());
+                    if !is_installed(&thirdparty.build_dir()) {
+                        println!(
+                            "cargo:warning=Not building {} because of {}, \
+                             but it hasn't been installed",
+                            dep.name, env_only
+                        );
+                    }
+                    if emscripten {
+                        installed_libs.extend(installed_archives(
+                            &thirdparty.build_dir(),
+                        ));
+                    }
+                    continue;
+                }
+
+                if !thirdparty.source_dir.join("CMakeLists.txt").exists() {
+                    return Err(BuildError::MissingSource {
+                        dependency: dep.name.to_string(),
+                        source_dir: thirdparty.source_dir,
+                    });
+                }
+
+                if rebuild.split(',').any(|n| n.trim() == dep.name) {
+                    if let Err(e) = clean_thirdparty(&dep.name, target_dir) {
+                        println!(
+                            "cargo:warning=Could not clean {}: {}",
+                            dep.name, e
+                        );
+                    }
+                }
+
+                let started = Instant::now();
+                let (_, built) = thirdparty.build();
+                if built {
+                    let elapsed = started.elapsed();
+                    native_build_time += elapsed;
+                    rebuilt_dependency = true;
+                    println!(
+                        "cargo:warning=Built {} in {:.1}s",
+                        dep.name,
+                        elapsed.as_secs_f64()
+                    );
+                    built_dependencies.push(dep.name.to_string());
+                    run_dependency_built(
+                        &mut self.dependency_built,
+                        &dep.name,
+                        &thirdparty.build_dir(),
+                        target_dir,
+                    )?;
+                }
+                built_libraries.push((
+                    dep.name.clone(),
+                    installed_libraries(&thirdparty.build_dir()),
+                ));
+                dependency_build_dirs.push(thirdparty.build_dir());
+                if emscripten {
+                    installed_libs
+                        .extend(installed_archives(&thirdparty.build_dir()));
+                }
+            }
+
+            #[cfg(target_os = "macos")]
+            if self.relocatable {
+                make_relocatable(&lib_path, &dependency_build_dirs)?;
+                for rpath in &["@executable_path", "@loader_path"] {
+                    directives.emit(format!(
+                        "cargo:rustc-link-arg=-Wl,-rpath,{}",
+                        rpath
+                    ));
+                }
+                // so they still run from the target directory
+                directives.emit(format!(
+                    "cargo:rustc-link-arg=-Wl,-rpath,{}",
+                    lib_path.display()
+                ));
+            }
+        }
+
+        let system_dependencies: Vec<String> = dependencies
+            .iter()
+            .filter(|d| !from_source(d))
+            .map(|d| d.name.to_string())
+            .collect();
+
+        // Anything we built installs its headers here, so warn if it's
+        // missing rather than leave dependent crates to find out
+        let prefix_include = include_dir(target_dir);
+        if !prefix_include.is_dir() && !built_dependencies.is_empty() {
+            println!(
+                "cargo:warning=Include directory {} was not installed",
+                prefix_include.display()
+            );
+        }
+        let prefix_include = Some(prefix_include).filter(|dir| dir.is_dir());
+
+        // Anything another crate already installed into a shared prefix
+        // counts as a system dependency too, and anything we built has to
+        // shadow the system's copy
+        let shared_prefix = Some(target_dir)
+            .filter(|_| install_prefix.is_some() || builds_dependencies);
+
+        // A record of exactly what was used, for reproducible builds
+        let resolved_dependencies = resolve_dependencies(
+            dependencies,
+            &built_libraries,
+            target_dir,
+            &system_prefixes(shared_prefix, &extra_prefix_paths),
+        );
+        let native_lock_path = native_lock_path(&out_dir);
+        if let Err(e) = std::fs::write(
+            &native_lock_path,
+            native_lock(&resolved_dependencies),
+        ) {
+            println!(
+                "cargo:warning=Could not write {}: {}",
+                native_lock_path.display(),
+                e
+            );
+        }
+
+        if self.dependencies_only {
+            directives.finish(&out_dir);
+            println!(
+                "cargo:warning=Native build took {:.1}s in total",
+                native_build_time.as_secs_f64()
+            );
+            println!("cargo:{}={}", self.root_key, target_dir.display());
+            println!("cargo:{}={}", self.lib_key, lib_path.display());
+            if let Some(dir) = &prefix_include {
+                println!("cargo:{}={}", self.include_key, dir.display());
+            }
+            return Ok(BuildReport {
+                built_dependencies,
+                system_dependencies,
+                profile: build_type,
+                target_dir: target_dir.to_path_buf(),
+                link_args: Vec::new(),
+                resolved_dependencies,
+            });
+        }
+
+        // Like the dependencies, the wrapper is only configured and built
+        // again if something might have changed: its settings, its sources or
+        // any dependency. Forcing the dependencies to build with
+        // CPPMM_<PROJECT>_BUILD_LIBRARIES always builds it too.
+        let shim_stamp_path = out_dir.join(".cppmm-stamp");
+        let shim_stamp = {
+            let mut hash = Fnv1a::new();
+            hash.write(&format!(
+                "{:?}",
+                (
+                    &build_type,
+                    build_libraries,
+                    target_dir,
+                    &extra_prefix_paths,
+                    &compiler_launcher,
+                    cxx_standard,
+                    &osx_architectures,
+                    &deployment_target,
+                    &self.cflags,
+                    &cxxflags,
+                    compiler_flags_from_env(),
+                    std::env::var_os("CMAKE_PREFIX_PATH"),
+                )
+            ));
+            hash.write(&format!(
+                "{:?}",
+                (
+                    &find_root,
+                    &compilers,
+                    &offline_definitions,
+                    &emscripten_definitions,
+                    &system_dependencies
+                )
+            ));
+            format!("{:016x}", hash.finish())
+        };
+        let forced_build = std::env::var(&env_build_libraries)
+            .map_or(false, |v| parse_bool_env(&v));
+        // Verbose output is only any use if the wrapper is built again
+        let shim_up_to_date = !verbose
+            && !rebuilt_dependency
+            && !forced_build
+            && std::fs::read_to_string(&shim_stamp_path).ok().as_deref()
+                == Some(shim_stamp.as_str())
+            && find_wrapper_library(
+                &out_dir,
+                &clib_versioned_name,
+                &clib_shared_versioned_name,
+            )
+            .is_some()
+            && !sources_newer_than(&clib_dir, &shim_stamp_path);
+
+        // Catch an installed dependency that's too old or too new here, rather
+        // than as a crash at runtime. This runs even when the wrapper is up to
+        // date, since the system packages can change underneath it.
+        let skip_version_check = std::env::var(&env_skip_version_check)
+            .map_or(false, |v| parse_bool_env(&v));
+        let versioned: Vec<&DependencyOwned> = dependencies
+            .iter()
+            .filter(|d| d.min_version.is_some() || d.max_version.is_some())
+            .filter(|d| !from_source(d))
+            .collect();
+        if !build_libraries && !skip_version_check && !versioned.is_empty() {
+            // Search where the wrapper's configure will
+            let mut definitions: Vec<(String, std::ffi::OsString)> = find_root
+                .iter()
+                .map(|(k, v)| (k.clone(), v.into()))
+                .collect();
+            if let Some(path) =
+                shim_prefix_path(shared_prefix, &extra_prefix_paths)
+            {
+                definitions.push(("CMAKE_PREFIX_PATH".to_string(), path));
+            }
+
+            let prefixes = system_prefixes(shared_prefix, &extra_prefix_paths);
+            for dep in versioned {
+                match cached_package_version(
+                    &package_names(&dep.name, &prefixes),
+                    &prefixes,
+                    &out_dir.join("version-probe").join(&dep.name),
+                    &definitions,
+                ) {
+                    Some(found) => check_dependency_version(dep, &found)?,
+                    None => println!(
+                        "cargo:warning=Could not find the version of {} to \
+                         check it, set {}=1 to skip this",
+                        dep.name, env_skip_version_check
+                    ),
+                }
+            }
+        }
+
+        let shim_settings = ShimSettings {
+            build_type: &build_type,
+            extra_prefix_paths: &extra_prefix_paths,
+            compiler_launcher: compiler_launcher.as_deref(),
+            compilers: &compilers,
+            cxx_standard,
+            osx_architectures: osx_architectures.as_deref(),
+            deployment_target: deployment_target.as_deref(),
+            definitions: find_root
+                .iter()
+                .chain(&offline_definitions)
+                .chain(&emscripten_definitions)
+                .collect(),
+            cxxflags: &cxxflags,
+            verbose,
+        };
+        let started = Instant::now();
+        let dst = if shim_up_to_date {
+            out_dir.clone()
+        } else {
+            let prefix = if build_libraries {
+                Some(target_dir)
+            } else {
+                println!(
+                    "cargo:warning=Using system dependencies {:?}",
+                    system_dependencies
+                );
+                shared_prefix
+            };
+            let mut config =
+                self.shim_config(&clib_dir, prefix, &shim_settings);
+            build_with_jobs(&mut config, build_jobs)
+        };
+        let elapsed = started.elapsed();
+        native_build_time += elapsed;
+        if shim_up_to_date {
+            println!("cargo:warning={} is up to date", clib_name);
+        } else {
+            println!(
+                "cargo:warning=Built {} in {:.1}s",
+                clib_name,
+                elapsed.as_secs_f64()
+            );
+            if verbose {
+                forward_cmake_warnings(&clib_name, &dst.join("build"));
+            }
+            if let Err(e) = std::fs::write(&shim_stamp_path, &shim_stamp) {
+                println!(
+                    "cargo:warning=Could not write stamp file {}: {}",
+                    shim_stamp_path.display(),
+                    e
+                );
+            }
+        }
+        println!(
+            "cargo:warning=Native build took {:.1}s in total",
+            native_build_time.as_secs_f64()
+        );
+
+        let mut expected_symbols = Vec::new();
+        if let Some(symbol) = &self.sentinel_symbol {
+            expected_symbols.push(ExpectedSymbol::Named(symbol));
+        }
+        if let Some(prefix) = &self.exported_symbol_prefix {
+            expected_symbols.push(ExpectedSymbol::Prefixed(prefix));
+        }
+        if !expected_symbols.is_empty() {
+            check_exported_symbols(
+                &dst,
+                &clib_versioned_name,
+                &clib_shared_versioned_name,
+                &expected_symbols,
+            )?;
+        }
+
+        let build_path = Path::new(&dst).join("build");
+
+        // Emscripten has no shared libraries, so the wrapper's shared
+        // library is built as a static archive and there's no link line to
+        // read. Link whatever the dependencies installed instead.
+        let LinkLine {
+            args: link_args,
+            unclassified,
+        } = if emscripten {
+            LinkLine {
+                args: installed_libs,
+                unclassified: Vec::new(),
+            }
+        } else {
+            link_line(&build_path, &clib_shared_versioned_name, &build_type)?
+        };
+        if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+            for tok in &unclassified {
+                println!("cargo:warning=unclassified link token: {}", tok);
+            }
+        }
+
+        let mut extra_args = Vec::new();
+        if self.interface_link_libraries || emscripten {
+            let mut prefixes = Vec::new();
+            if builds_dependencies {
+                prefixes.push(target_dir.to_path_buf());
+            }
+            prefixes.extend(extra_prefix_paths.iter().cloned());
+            if let Some(paths) = std::env::var_os("CMAKE_PREFIX_PATH") {
+                prefixes.extend(std::env::split_paths(&paths));
+            }
+            let names: Vec<&str> =
+                dependencies.iter().map(|d| d.name.as_str()).collect();
+
+            extra_args.extend(
+                interface_link_libraries(&prefixes, &names)
+                    .into_iter()
+                    .filter(|arg| {
+                        !link_args.iter().any(|a| same_library(a, arg))
+                    }),
+            );
+        }
+
+        extra_args.extend(
+            self.feature_link_args
+                .iter()
+                .filter(|(feature, _)| feature_enabled(feature))
+                .map(|(_, arg)| arg.clone()),
+        );
+
+        let mut link_args =
+            dedup_link_args(merge_link_args(&[link_args, extra_args]));
+        let mut ignored_libs = self.ignored_libs.clone();
+        ignored_libs.extend(
+            std::env::var(&env_ignore_libs)
+                .unwrap_or_default()
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty()),
+        );
+        link_args.retain(|arg| {
+            let ignored = is_ignored_lib(arg, &ignored_libs);
+            if ignored && std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+                println!("cargo:warning=Not linking ignored library {:?}", arg);
+            }
+            !ignored
+        });
+        // These are emitted as given, after everything we found ourselves
+        link_args.extend(self.extra_link_args.iter().cloned());
+        println!("cargo:warning=Link libs: {:?}", link_args);
+        if let Err(e) = verify_link_args(&link_args) {
+            println!("cargo:warning={}", e);
+        }
+
+        #[cfg(target_os = "macos")]
+        if !self.osx_architectures.is_empty() {
+            let mut libs: Vec<PathBuf> = link_args
+                .iter()
+                .filter_map(|arg| match arg {
+                    LinkArg::Path(d) => Some(PathBuf::from(&d.path)),
+                    _ => None,
+                })
+                .collect();
+            libs.extend(find_wrapper_library(
+                &dst,
+                &clib_versioned_name,
+                &clib_shared_versioned_name,
+            ));
+            verify_architectures(&libs, &self.osx_architectures);
+        }
+
+        // Tell dependent crates where everything was installed. Cargo exposes
+        // these to them as DEP_<LINKS>_ROOT etc. The cmake crate prints its
+        // own `root` for each build it runs, so this must come after those.
+        println!("cargo:{}={}", self.root_key, target_dir.display());
+        println!("cargo:{}={}", self.lib_key, lib_path.display());
+
+        // Pass the installed include directory and the ones the wrapper was
+        // built (or linked) with on to any dependent crates as
+        // DEP_<LINKS>_INCLUDE
+        let mut include_dirs: Vec<PathBuf> =
+            prefix_include.into_iter().collect();
+        let link_include_dirs = link_args.iter().filter_map(|arg| match arg {
+            LinkArg::IncludeDir(dir) => Some(PathBuf::from(dir)),
+            _ => None,
+        });
+        for dir in include_dirs_from_compile_commands(&build_path)
+            .into_iter()
+            .chain(link_include_dirs)
+        {
+            if !include_dirs.contains(&dir) {
+                include_dirs.push(dir);
+            }
+        }
+        if !include_dirs.is_empty() {
+            if let Ok(include) = std::env::join_paths(&include_dirs) {
+                println!(
+                    "cargo:{}={}",
+                    self.include_key,
+                    include.to_string_lossy()
+                );
+            }
+        }
+
+        // Link our wrapper library
+        //
+        // We currently build a dylib on windows just so we can enable Debug
+        // builds. This is because Rust always links against the release msvcrt
+        // (presumably since the debug one is unusable in a lot of situations), thus
+        // we cannot link statically since setting the C shim to Debug mode will
+        // cause it to link against the debug msvcrt. This in turn causes all sorts
+        // of bad shit to happen (segfaults mostly). By the way, did you know that
+        // STL types are different sizes in debug and release builds on Windows?
+        // I didn't until today because I couldn't imagine a world in which something
+        // like that would be allowed to happen.
+        //
+        // In theory, you can override this, but like most things with CMake, the
+        // correct incantations are buried somewhere in vague mailing list
+        // threads, and don't actually seem to work (at least not with VS generators,
+        // which appear to want to force the runtime for you).
+        //
+        // So, the easiest way out here is just to build everything from the C shim
+        // down as a DLL so we can neatly sidestep all this (because the C library
+        // provides a nice ABI dambreak against the insanity).
+        //
+        // We still build statically on Linux since that way you don't need to install
+        // the DSO along with any Rust binaries you might want to build. Ultimately
+        // installation in a production environment will require a bit more thought,
+        // but suffice to say it's complex. On Windows at least, just copying DLLs
+        // around everywhere seems to be the norm so we assume it's not the end of
+        // the world.
+        //
+        let mut search_dirs: Vec<PathBuf> = vec![dst.clone()];
+        if builds_dependencies {
+            // Link against the stuff what we built
+            search_dirs.push(lib_path.clone());
+            // we don't actually want to link against anything in /bin but we
+            // need to tell rustc where the DLLs are on windows and this is the
+            // way to do it
+            search_dirs.push(bin_path.clone());
+        }
+        for dir in link_search_dirs(&link_args) {
+            if !search_dirs.contains(&dir) {
+                search_dirs.push(dir);
+            }
+        }
+        for dir in &search_dirs {
+            directives.emit(format!(
+                "cargo:rustc-link-search=native={}",
+                dir.display()
+            ));
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        directives.emit(format!(
+            "cargo:rustc-link-lib=static={}",
+            clib_versioned_name
+        ));
+        #[cfg(target_os = "windows")]
+        directives.emit(format!(
+            "cargo:rustc-link-lib=dylib={}",
+            clib_shared_versioned_name
+        ));
+
+        // Put the DLLs we built next to the test and example executables so
+        // they can actually be run. Windows has no rpath, so the alternative
+        // is making users add the bin directory to their PATH.
+        #[cfg(target_os = "windows")]
+        if builds_dependencies {
+            let mut dlls: Vec<String> = link_args
+                .iter()
+                .filter_map(|arg| match arg {
+                    LinkArg::Path(d) => Some(dll_file_name(d)),
+                    _ => None,
+                })
+                .collect();
+            dlls.push(format!("{}.dll", clib_shared_versioned_name));
+
+            copy_runtime_libs(
+                &dlls,
+                &[bin_path.clone(), dst.join("bin")],
+                &[PathBuf::from(&out_dir), profile_dir.to_path_buf()],
+            );
+        }
+
+        if builds_dependencies {
+            match self.runtime_search_path {
+                // everything is linked statically
+                _ if emscripten => {}
+                // make_relocatable has already added the rpaths
+                RuntimeSearchPath::Rpath
+                    if cfg!(target_os = "macos") && self.relocatable => {}
+                RuntimeSearchPath::Rpath => {
+                    #[cfg(not(target_os = "windows"))]
+                    for rpath in runtime_rpaths(&lib_path, profile_dir) {
+                        directives.emit(format!(
+                            "cargo:rustc-link-arg=-Wl,-rpath,{}",
+                            rpath
+                        ));
+                    }
+                }
+                RuntimeSearchPath::Script => {
+                    #[cfg(target_os = "windows")]
+                    let runtime_dirs = [bin_path.clone(), dst.join("bin")];
+                    #[cfg(not(target_os = "windows"))]
+                    let runtime_dirs = [lib_path.clone()];
+                    let script = PathBuf::from(&out_dir).join(
+                        if cfg!(target_os = "windows") {
+                            "runtime-env.bat"
+                        } else {
+                            "runtime-env.sh"
+                        },
+                    );
+                    match std::fs::write(
+                        &script,
+                        runtime_env_script(&runtime_dirs),
+                    ) {
+                        Ok(()) => println!(
+                            "cargo:warning=Set up the runtime library path \
+                             with {}",
+                            script.display()
+                        ),
+                        Err(e) => println!(
+                            "cargo:warning=Could not write {}: {}",
+                            script.display(),
+                            e
+                        ),
+                    }
+                }
+                RuntimeSearchPath::None => {}
+            }
+        }
+
+        for arg in &link_args {
+            // Link against all our dependencies
+            match arg {
+                LinkArg::Path(d) if is_static_archive(d) => {
+                    // rustc turns +whole-archive into --whole-archive on
+                    // Linux and -force_load on macOS. Unlike passing those
+                    // with rustc-link-arg this also applies when linking the
+                    // crates that depend on us.
+                    let modifiers = if self.whole_archive.contains(&d.libname) {
+                        ":+whole-archive"
+                    } else {
+                        ""
+                    };
+                    directives.emit(format!(
+                        "cargo:rustc-link-lib=static{}={}",
+                        modifiers, &d.libname
+                    ));
+                }
+                LinkArg::Path(d) => {
+                    directives.emit(format!(
+                        "cargo:rustc-link-lib=dylib={}",
+                        &d.libname
+                    ));
+                }
+                // these are passed on as metadata above instead
+                LinkArg::LinkDir(_) | LinkArg::IncludeDir(_) => (),
+                // linked weakly below instead
+                LinkArg::LinkLib(lib)
+                    if framework_name(lib).map_or(false, |f| {
+                        self.weak_frameworks.iter().any(|w| w == f)
+                    }) => {}
+                LinkArg::LinkLib(lib) => {
+                    directives.emit(link_lib_directive(lib));
+                }
+                LinkArg::ObjectFile(o) => {
+                    directives.emit(format!("cargo:rustc-link-arg={}", o));
+                }
+            }
+        }
+
+        #[cfg(target_os = "macos")]
+        for framework in &self.weak_frameworks {
+            directives.emit(format!(
+                "cargo:rustc-link-arg=-Wl,-weak_framework,{}",
+                framework
+            ));
+        }
+
+        // The libraries above are linked in the order CMake gave them, which
+        // isn't enough when they refer to each other. rustc puts link args
+        // after all of the libraries, so the group repeats them rather than
+        // wrapping the ones already given.
+        #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+        if self.link_group {
+            for arg in link_group_args(&link_args) {
+                directives.emit(format!("cargo:rustc-link-arg={}", arg));
+            }
+        }
+
+        for libname in &self.whole_archive {
+            let found = link_args.iter().any(|arg| {
+                matches!(arg, LinkArg::Path(d)
+                    if &d.libname == libname && is_static_archive(d))
+            });
+            if !found {
+                println!(
+                    "cargo:warning=Asked to link all of {} but it isn't a \
+                     static archive we link against",
+                    libname
+                );
+            }
+        }
+
+        // On unices we need to link against the stdlib
+        let cxx_stdlib = match std::env::var(&env_cxxlib) {
+            Ok(value) => CxxStdlib::from_env(&value).unwrap_or_else(|| {
+                println!(
+                    "cargo:warning=Ignoring unknown {} value '{}'",
+                    env_cxxlib, value
+                );
+                self.cxx_stdlib
+            }),
+            Err(_) => self.cxx_stdlib,
+        };
+        // except on Emscripten, where emcc links its own libc++
+        if let Some(lib) = cxx_stdlib.link_lib().filter(|_| !emscripten) {
+            directives.emit(format!("cargo:rustc-link-lib=dylib={}", lib));
+        }
+
+        directives.finish(&out_dir);
+
+        let runtime_libs_txt = runtime_libs_path(
+            &out_dir,
+            project_name,
+            major_version,
+            minor_version,
+        );
+        let mut contents = String::new();
+        for lib in runtime_libs(
+            &link_args,
+            find_wrapper_library(
+                &dst,
+                &clib_versioned_name,
+                &clib_shared_versioned_name,
+            ),
+            &[bin_path.clone(), dst.join("bin")],
+        ) {
+            contents.push_str(&lib.to_string_lossy());
+            contents.push('\n');
+        }
+        if let Err(e) = std::fs::write(&runtime_libs_txt, contents) {
+            println!(
+                "cargo:warning=Could not write {}: {}",
+                runtime_libs_txt.display(),
+                e
+            );
+        }
+
+        // Insert the C++ ABI info
+        //
+        // abigen is a small binary that's autogenerated by cppmm. It simply outputs
+        // the size of all opaquebytes types to a file, `abigen.txt`. Meanwhile,
+        // cppmm sets up both the C and Rust layer source with placeholder markers
+        // that are replaced by the Python script `insert_abi.py`, below.
+        //
+        // We do this because certain types (STL mainly) are different sizes between
+        // platforms (and even between build types on Windows!), and generating
+        // their ABI info at build time here saves us from having to run the entire
+        // binding generation at the crate build level, and thus keeps a libclang
+        // dependency out of all our end-user crates.
+        //
+        let build_dir = Path::new(&out_dir).join("build");
+        let abigen_txt = match &self.abigen_out {
+            Some(path) => build_dir.join(path),
+            None => build_dir.join("abigen.txt"),
+        };
+
+        let cppmm_abi_in =
+            self.abi_in_base
+                .resolve(&out_dir, &manifest_dir, "cppmm_abi_in");
+        let cppmm_abi_out_dir = match &self.abi_out_dir {
+            Some(dir) => resolve_prefix(dir, &manifest_dir),
+            None => self.abi_out_base.resolve(
+                &out_dir,
+                &manifest_dir,
+                "cppmm_abi_out",
+            ),
+        };
+        let cppmm_abi_out = cppmm_abi_out_dir.join("cppmmabi.rs");
+        if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+            println!(
+                "cargo:warning=Writing the generated ABI to {}",
+                cppmm_abi_out.display()
+            );
+        }
+
+        let skip_abi = self.skip_abi
+            || std::env::var(&env_skip_abi)
+                .map_or(false, |v| parse_bool_env(&v));
+
+        let abigen_bin = match &self.abigen_bin {
+            Some(path) => Ok(build_dir.join(path)),
+            None if skip_abi => Ok(PathBuf::new()),
+            // abigen.js on Emscripten, which is run with node
+            None if emscripten => abigen_binary(&build_dir, &build_type)
+                .map(|bin| bin.with_extension("js")),
+            None => abigen_binary(&build_dir, &build_type),
+        };
+
+        // A stale abigen.txt gives the wrong sizes for the opaque types, which
+        // shows up as memory corruption at runtime, so start again if the C++
+        // side has been rebuilt since, or was built with another profile
+        let abi_profile = abi_profile_path(&abigen_txt);
+        let regen_abi = std::env::var(&env_regen_abi)
+            .map_or(false, |v| parse_bool_env(&v))
+            || abigen_bin
+                .as_ref()
+                .map_or(false, |bin| is_newer(bin, &abigen_txt))
+            || std::fs::read_to_string(&abi_profile).ok().as_deref()
+                != Some(build_type.as_str());
+        if !skip_abi && regen_abi {
+            for path in [&abigen_txt, &cppmm_abi_out] {
+                if let Err(e) = std::fs::remove_file(path) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        println!(
+                            "cargo:warning=Could not remove {}: {}",
+                            path.display(),
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        // if the generated rust doesn't exist, run abigen and the python to
+        // generate it
+        if !skip_abi && !cppmm_abi_out.exists() {
+            // Run abigen again if the output doesn't exist.
+            if !abigen_txt.exists() {
+                let abigen_bin = abigen_bin?;
+                if !abigen_bin.is_file() {
+                    return Err(BuildError::Abigen(format!(
+                        "abigen binary not found, expected it at '{}'",
+                        abigen_bin.display()
+                    )));
+                }
+                run_abigen(&abigen_bin, &build_dir, &abigen_txt)?;
+            }
+
+            let insert_abi_py = match &self.abi_template_dir {
+                Some(dir) => dir.join("insert_abi.py"),
+                None => clib_dir.join("abigen").join("insert_abi.py"),
+            };
+            let python = find_python(&python_candidates())?;
+            std::fs::create_dir_all(&cppmm_abi_out_dir).map_err(|e| {
+                BuildError::Abigen(format!(
+                    "could not create '{}': {}",
+                    cppmm_abi_out_dir.display(),
+                    e
+                ))
+            })?;
+            let output = insert_abi_command(
+                &python,
+                &insert_abi_py,
+                &cppmm_abi_in,
+                &cppmm_abi_out_dir,
+                &abigen_txt,
+            )
+            .output()
+            .map_err(|e| {
+                BuildError::Abigen(format!(
+                    "Could not run {} '{}': {}",
+                    python.display(),
+                    insert_abi_py.display(),
+                    e
+                ))
+            })?;
+
+            if !output.status.success() {
+                for line in String::from_utf8_lossy(&output.stderr).lines() {
+                    println!("cargo:warning={}", line);
+                }
+                // so the next build runs it again rather than using it
+                let _ = std::fs::remove_file(&cppmm_abi_out);
+                return Err(BuildError::Abigen(format!(
+                    "'{}' failed with {}",
+                    insert_abi_py.display(),
+                    output.status
+                )));
+            }
+
+            // A marker that wasn't replaced would otherwise only show up as
+            // a compile error in whichever crate includes the file
+            let abi = std::fs::read_to_string(&cppmm_abi_out).map_err(|e| {
+                BuildError::Abigen(format!(
+                    "could not read '{}': {}",
+                    cppmm_abi_out.display(),
+                    e
+                ))
+            })?;
+            if let Some((number, line)) = unreplaced_abi_marker(&abi) {
+                // so the next build generates it again rather than using it
+                let _ = std::fs::remove_file(&cppmm_abi_out);
+                return Err(BuildError::Abigen(format!(
+                    "insert_abi.py left a placeholder in '{}' at line {}: {}",
+                    cppmm_abi_out.display(),
+                    number,
+                    line.trim()
+                )));
+            }
+
+            if let Err(e) = std::fs::write(&abi_profile, &build_type) {
+                println!(
+                    "cargo:warning=Could not write {}: {}",
+                    abi_profile.display(),
+                    e
+                );
+            }
+        }
+
+        Ok(BuildReport {
+            built_dependencies,
+            system_dependencies,
+            profile: build_type,
+            target_dir: target_dir.to_path_buf(),
+            link_args,
+            resolved_dependencies,
+        })
+    }
+}
+
+/// The file next to `abigen_txt` recording the profile it and `cppmmabi.rs`
+/// were generated for, e.g. `abigen.txt.profile`. The sizes of the opaque
+/// types can differ between profiles on Windows, so they're generated again
+/// when the profile changes.
+///
+fn abi_profile_path(abigen_txt: &Path) -> PathBuf {
+    let mut name = abigen_txt.file_name().unwrap_or_default().to_os_string();
+    name.push(".profile");
+    abigen_txt.with_file_name(name)
+}
+
+/// Find the first line of the generated ABI file `abi` that still contains
+/// one of cppmm's `%NAME%` placeholder markers, returning its (1-based) line
+/// number and the line.
+///
+fn unreplaced_abi_marker(abi: &str) -> Option<(usize, &str)> {
+    let re = Regex::new(r"%[A-Za-z_]\w*%").unwrap();
+    abi.lines()
+        .enumerate()
+        .find(|(_, line)| re.is_match(line))
+        .map(|(i, line)| (i + 1, line))
+}
+
+/// The Python interpreters to try for running `insert_abi.py`, in order.
+///
+/// `CPPMM_PYTHON` or `PYTHON` override the search completely if set,
+/// otherwise it's `python3` then `python`, since plenty of systems only have
+/// one or the other.
+///
+fn python_candidates() -> Vec<PathBuf> {
+    match std::env::var_os("CPPMM_PYTHON")
+        .or_else(|| std::env::var_os("PYTHON"))
+    {
+        Some(python) => vec![PathBuf::from(python)],
+        None => vec![PathBuf::from("python3"), PathBuf::from("python")],
+    }
+}
+
+/// Find the first of `candidates` that exists, see [`find_program`].
+///
+fn find_python(candidates: &[PathBuf]) -> Result<PathBuf, BuildError> {
+    candidates
+        .iter()
+        .find_map(|c| find_program(c))
+        .ok_or_else(|| {
+            BuildError::PythonNotFound(
+                candidates.iter().map(|c| c.display().to_string()).collect(),
+            )
+        })
+}
+
+/// Set up the command that runs the `insert_abi_py` script to fill in the ABI
+/// template in `abi_in` from `abigen_txt`, writing the result to `abi_out`.
+///
+/// Each path is passed as its own argument, so paths containing spaces (like
+/// the default Windows user profile directories) survive intact.
+///
+fn insert_abi_command(
+    python: &Path,
+    insert_abi_py: &Path,
+    abi_in: &Path,
+    abi_out: &Path,
+    abigen_txt: &Path,
+) -> std::process::Command {
+    let mut command = std::process::Command::new(python);
+    command
+        .arg(insert_abi_py)
+        .arg(abi_in)
+        .arg(abi_out)
+        .arg(abigen_txt);
+    command
+}
+
+/// The `cargo:rustc-link-*` directives a build emits, kept in order as
+/// they're printed so the whole link line can be looked over in one place.
+///
+#[derive(Default)]
+struct LinkDirectives(Vec<String>);
+
+impl LinkDirectives {
+    /// Print `directive` for Cargo and keep a copy of it.
+    fn emit(&mut self, directive: String) {
+        println!("{}", directive);
+        self.0.push(directive);
+    }
+
+    /// Write all the directives to `cppmm-link-directives.txt` in `out_dir`,
+    /// and list them as warnings too under `CPPMM_DEBUG_BUILD`.
+    fn finish(&self, out_dir: &Path) {
+        if std::env::var("CPPMM_DEBUG_BUILD").is_ok() {
+            println!("cargo:warning=Emitted link directives:");
+            for directive in &self.0 {
+                println!("cargo:warning=    {}", directive);
+            }
+        }
+        let path = out_dir.join("cppmm-link-directives.txt");
+        let mut contents = self.0.join("\n");
+        contents.push('\n');
+        if let Err(e) = std::fs::write(&path, contents) {
+            println!("cargo:warning=Could not write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The rpaths for finding the libraries in `lib_path` at runtime. When it's
+/// inside `profile_dir` these start with ones relative to the executable, for
+/// binaries in the profile directory itself and for tests and examples one
+/// level down, so the whole directory can be moved. The absolute path is
+/// always last.
+///
+#[cfg(not(target_os = "windows"))]
+fn runtime_rpaths(lib_path: &Path, profile_dir: &Path) -> Vec<String> {
+    let origin = if cfg!(target_os = "macos") {
+        "@executable_path"
+    } else {
+        "$ORIGIN"
+    };
+    let mut rpaths = Vec::new();
+    if let Ok(relative) = lib_path.strip_prefix(profile_dir) {
+        rpaths.push(format!("{}/{}", origin, relative.display()));
+        rpaths.push(format!("{}/../{}", origin, relative.display()));
+    }
+    rpaths.push(lib_path.display().to_string());
+    rpaths
+}
+
+/// The contents of the script that adds `dirs` to the runtime library path,
+/// see [`RuntimeSearchPath::Script`].
+///
+fn runtime_env_script(dirs: &[PathBuf]) -> String {
+    let dirs: Vec<String> =
+        dirs.iter().map(|d| d.display().to_string()).collect();
+    if cfg!(target_os = "windows") {
+        format!("@set \"PATH={};%PATH%\"\r\n", dirs.join(";"))
+    } else {
+        let var = if cfg!(target_os = "macos") {
+            "DYLD_LIBRARY_PATH"
+        } else {
+            "LD_LIBRARY_PATH"
+        };
+        format!(
+            "# Source this to run binaries linked against the packaged \
+             libraries\nexport {var}=\"{}${{{var}:+:${var}}}\"\n",
+            dirs.join(":"),
+            var = var
+        )
+    }
+}
+
+/// Copy the `dlls` from the first of `bin_dirs` they are found in into each
+/// of `dest_dirs`, returning the DLLs that were found.
+///
+/// DLLs that aren't in any of `bin_dirs` are assumed to come from the system
+/// and are skipped.
+///
+#[cfg(target_os = "windows")]
+fn copy_runtime_libs(
+    dlls: &[String],
+    bin_dirs: &[PathBuf],
+    dest_dirs: &[PathBuf],
+) -> Vec<PathBuf> {
+    let mut copied = Vec::new();
+    for dll in dlls {
+        let src =
+            match bin_dirs.iter().map(|d| d.join(dll)).find(|p| p.exists()) {
+                Some(src) => src,
+                None => continue,
+            };
+
+        for dest_dir in dest_dirs {
+            let dest = dest_dir.join(dll);
+            if let Err(e) = std::fs::copy(&src, &dest) {
+                println!(
+                    "cargo:warning=Could not copy {} to {}: {}",
+                    src.display(),
+                    dest.display(),
+                    e
+                );
+            }
+        }
+        copied.push(src);
+    }
+    copied
+}
+
+/// Where the build records the libraries [`runtime_dlls`] returns, under
+/// `out_dir`. This is named for the project and version rather than the
+/// wrapper library, so it can be found without the builder's settings.
+///
+fn runtime_libs_path(
+    out_dir: &Path,
+    project_name: &str,
+    major_version: u32,
+    minor_version: u32,
+) -> PathBuf {
+    out_dir.join(format!(
+        "cppmm-{}-{}_{}-runtime-libs.txt",
+        project_name, major_version, minor_version
+    ))
+}
+
+/// The shared libraries needed at runtime by binaries linked with
+/// `link_args` and the C `wrapper` library, see [`runtime_dlls`]. On
+/// Windows the DLLs are looked for in `bin_dirs`.
+///
+fn runtime_libs(
+    link_args: &[LinkArg],
+    wrapper: Option<PathBuf>,
+    bin_dirs: &[PathBuf],
+) -> Vec<PathBuf> {
+    #[cfg(target_os = "windows")]
+    {
+        let mut dlls: Vec<PathBuf> = wrapper
+            .filter(|w| w.extension() == Some("dll".as_ref()))
+            .into_iter()
+            .collect();
+        for arg in link_args {
+            if let LinkArg::Path(d) = arg {
+                let dll = dll_file_name(d);
+                if let Some(path) = bin_dirs
+                    .iter()
+                    .map(|dir| dir.join(&dll))
+                    .find(|p| p.exists())
+                {
+                    if !dlls.contains(&path) {
+                        dlls.push(path);
+                    }
+                }
+            }
+        }
+        dlls
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = (wrapper, bin_dirs);
+        let mut libs: Vec<PathBuf> = Vec::new();
+        for arg in link_args {
+            match arg {
+                LinkArg::Path(d) if !is_static_archive(d) => {
+                    let path = PathBuf::from(&d.path);
+                    if !libs.contains(&path) {
+                        libs.push(path);
+                    }
+                }
+                _ => (),
+            }
+        }
+        libs
+    }
+}
+
+/// Warn about any of `libs` that weren't built for all of `architectures`,
+/// according to `lipo`.
+///
+#[cfg(target_os = "macos")]
+fn verify_architectures(libs: &[PathBuf], architectures: &[String]) {
+    for lib in libs {
+        let output = match std::process::Command::new("lipo")
+            .arg("-archs")
+            .arg(lib)
+            .output()
+        {
+            Ok(output) if output.status.success() => output,
+            _ => {
+                println!(
+                    "cargo:warning=Could not run lipo to check the \
+                     architectures of {}",
+                    lib.display()
+                );
+                continue;
+            }
+        };
+
+        let missing = missing_architectures(
+            &String::from_utf8_lossy(&output.stdout),
+            architectures,
+        );
+        if !missing.is_empty() {
+            println!(
+                "cargo:warning={} is not built for {}, so it can't be linked \
+                 into a universal binary",
+                lib.display(),
+                missing.join(", ")
+            );
+        }
+    }
+}
+
+/// The `architectures` missing from the output of `lipo -archs`.
+///
+#[cfg(target_os = "macos")]
+fn missing_architectures<'a>(
+    lipo_archs: &str,
+    architectures: &'a [String],
+) -> Vec<&'a str> {
+    let found: Vec<&str> = lipo_archs.split_whitespace().collect();
+    architectures
+        .iter()
+        .map(String::as_str)
+        .filter(|a| !found.contains(a))
+        .collect()
+}
+
+/// Rewrite the install names of the dylibs the dependencies built in
+/// `build_dirs` installed into `lib_path`, and their references to each
+/// other, to be relative to `@rpath`. Only the libraries in install manifests
+/// that haven't been through this yet are touched, i.e. those of dependencies
+/// built in this run, so nothing else in `lib_path` is, and an up to date
+/// build doesn't run `install_name_tool` at all.
+///
+#[cfg(target_os = "macos")]
+fn make_relocatable(
+    lib_path: &Path,
+    build_dirs: &[PathBuf],
+) -> Result<(), BuildError> {
+    let pending: Vec<&PathBuf> =
+        build_dirs.iter().filter(|d| needs_relocating(d)).collect();
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let install_name_tool = find_program(Path::new("install_name_tool"))
+        .ok_or_else(|| BuildError::MissingTool("install_name_tool".into()))?;
+
+    // Any dependency's libraries can be referenced, including the versioned
+    // symlinks
+    let mut names: Vec<String> = build_dirs
+        .iter()
+        .flat_map(|d| installed_libraries(d))
+        .filter_map(|p| Some(p.file_name()?.to_string_lossy().into_owned()))
+        .filter(|n| n.ends_with(".dylib"))
+        .collect();
+    names.sort();
+    names.dedup();
+
+    for build_dir in pending {
+        let mut failed = false;
+        for dylib in relocatable_dylibs(&installed_libraries(build_dir)) {
+            let output = std::process::Command::new(&install_name_tool)
+                .args(install_name_tool_args(&dylib, lib_path, &names))
+                .output()
+                .map_err(|e| {
+                    BuildError::MissingTool(format!(
+                        "install_name_tool ({})",
+                        e
+                    ))
+                })?;
+            if !output.status.success() {
+                for line in String::from_utf8_lossy(&output.stderr).lines() {
+                    println!("cargo:warning={}", line);
+                }
+                println!(
+                    "cargo:warning=install_name_tool failed on {}",
+                    dylib.display()
+                );
+                failed = true;
+            }
+        }
+        if !failed {
+            if let Some(stamp) = relocation_stamp(build_dir) {
+                let _ =
+                    std::fs::write(build_dir.join(".cppmm-relocated"), stamp);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The dylibs among the `installed` libraries for `install_name_tool` to
+/// rewrite: only the real files, not the versioned symlinks pointing at them.
+///
+#[cfg(target_os = "macos")]
+fn relocatable_dylibs(installed: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dylibs: Vec<PathBuf> = installed
+        .iter()
+        .filter(|p| {
+            p.extension().map_or(false, |e| e == "dylib")
+                && p.symlink_metadata().map_or(false, |m| m.is_file())
+        })
+        .cloned()
+        .collect();
+    dylibs.sort();
+    dylibs
+}
+
+/// Whether the libraries installed from `build_dir` still need
+/// [`make_relocatable`]: they do unless it finished with them since they were
+/// last installed.
+///
+#[cfg(target_os = "macos")]
+fn needs_relocating(build_dir: &Path) -> bool {
+    match relocation_stamp(build_dir) {
+        Some(stamp) => {
+            std::fs::read_to_string(build_dir.join(".cppmm-relocated"))
+                .map_or(true, |relocated| relocated != stamp)
+        }
+        None => false,
+    }
+}
+
+/// What identifies the last install from `build_dir`: its install manifest
+/// and when it was written. `None` if nothing's been installed.
+///
+#[cfg(target_os = "macos")]
+fn relocation_stamp(build_dir: &Path) -> Option<String> {
+    let manifest = build_dir.join("build").join("install_manifest.txt");
+    let modified = std::fs::metadata(&manifest)
+        .and_then(|m| m.modified())
+        .ok()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?;
+    let mut hash = Fnv1a::new();
+    hash.write(&std::fs::read_to_string(&manifest).ok()?);
+    hash.write(&modified.as_nanos().to_string());
+    Some(format!("{:016x}", hash.finish()))
+}
+
+/// The arguments to `install_name_tool` to give `dylib` an `@rpath` install
+/// name, and to point its references to any of the `names` installed in
+/// `lib_path` (including symlinks) at `@rpath` too.
+///
+#[cfg(target_os = "macos")]
+fn install_name_tool_args(
+    dylib: &Path,
+    lib_path: &Path,
+    names: &[String],
+) -> Vec<std::ffi::OsString> {
+    let file_name = dylib.file_name().unwrap().to_string_lossy();
+    let mut args: Vec<std::ffi::OsString> =
+        vec!["-id".into(), format!("@rpath/{}", file_name).into()];
+    for name in names.iter().filter(|n| **n != file_name) {
+        args.push("-change".into());
+        args.push(lib_path.join(name).into());
+        args.push(format!("@rpath/{}", name).into());
+    }
+    args.push(dylib.into());
+    args
+}
+
+/// Returns true if both `a` and `b` exist and `a` was modified after `b`.
+///
+fn is_newer(a: &Path, b: &Path) -> bool {
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified());
+    match (modified(a), modified(b)) {
+        (Ok(a), Ok(b)) => a > b,
+        _ => false,
+    }
+}
+
+/// Find the abigen binary in the wrapper's CMake `build_dir`.
+///
+#[cfg(not(target_os = "windows"))]
+fn abigen_binary(
+    build_dir: &Path,
+    _build_type: &str,
+) -> Result<PathBuf, BuildError> {
+    Ok(build_dir.join("abigen").join("abigen"))
+}
+
+/// Find the abigen binary in the wrapper's CMake `build_dir` that was built
+/// in the `build_type` configuration.
+///
+/// This matters because the sizes abigen reports for STL types are different
+/// between Debug and Release on Windows, so running a Release abigen for a
+/// Debug wrapper gives the wrong ABI. Multi-config (Visual Studio) builds put
+/// each configuration's binary in its own subdirectory, so if there isn't one
+/// for `build_type` yet we build it.
+///
+#[cfg(target_os = "windows")]
+fn abigen_binary(
+    build_dir: &Path,
+    build_type: &str,
+) -> Result<PathBuf, BuildError> {
+    let abigen_dir = build_dir.join("abigen");
+    if !abigen_dir.join("abigen.vcxproj").exists() {
+        // single-config generator, so there's only one abigen
+        return Ok(abigen_dir.join("abigen.exe"));
+    }
+
+    let abigen_bin = abigen_dir.join(build_type).join("abigen.exe");
+    if abigen_bin.exists() {
+        return Ok(abigen_bin);
+    }
+
+    let output = std::process::Command::new(cmake_program())
+        .arg("--build")
+        .arg(build_dir)
+        .args(["--target", "abigen", "--config", build_type])
+        .output()
+        .map_err(|e| {
+            BuildError::Abigen(format!("Could not run cmake: {}", e))
+        })?;
+    if !output.status.success() || !abigen_bin.exists() {
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            println!("cargo:warning={}", line);
+        }
+        return Err(BuildError::Abigen(format!(
+            "Could not build abigen in the {} configuration",
+            build_type
+        )));
+    }
+
+    Ok(abigen_bin)
+}
+
+/// Run the `abigen` binary in `build_dir` and check it actually wrote out
+/// `abigen_txt`.
+///
+/// If abigen fails we remove whatever it managed to write so that we don't
+/// pick up a partial `abigen.txt` on the next build. Some versions of abigen
+/// write it next to themselves rather than into the working directory, so if
+/// it's not where we expect we look there too and move it into place.
+///
+fn run_abigen(
+    abigen_bin: &Path,
+    build_dir: &Path,
+    abigen_txt: &Path,
+) -> Result<(), BuildError> {
+    let mut command = if abigen_bin.extension() == Some("js".as_ref()) {
+        let node = find_node().ok_or_else(|| {
+            BuildError::Abigen(format!(
+                "abigen '{}' was built for Emscripten and needs node to run, \
+                 but it wasn't found on PATH or in EMSDK_NODE",
+                abigen_bin.display()
+            ))
+        })?;
+        let mut command = std::process::Command::new(node);
+        command.arg(abigen_bin);
+        command
+    } else {
+        std::process::Command::new(abigen_bin)
+    };
+    let started = SystemTime::now();
+    let output = command.current_dir(build_dir).output().map_err(|e| {
+        BuildError::Abigen(format!(
+            "Could not run abigen '{}': {}",
+            abigen_bin.display(),
+            e
+        ))
+    })?;
+
+    if !output.status.success() {
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            println!("cargo:warning={}", line);
+        }
+        let _ = std::fs::remove_file(abigen_txt);
+        return Err(BuildError::Abigen(format!(
+            "abigen '{}' failed with {}",
+            abigen_bin.display(),
+            output.status
+        )));
+    }
+
+    if !abigen_txt.exists() {
+        if let Some(found) =
+            misplaced_abigen_txt(abigen_bin, build_dir, abigen_txt, started)
+        {
+            println!(
+                "cargo:warning=abigen wrote {} rather than {}, moving it",
+                found.display(),
+                abigen_txt.display()
+            );
+            if std::fs::rename(&found, abigen_txt).is_err() {
+                // e.g. across filesystems
+                std::fs::copy(&found, abigen_txt)
+                    .and_then(|_| std::fs::remove_file(&found))
+                    .map_err(|e| {
+                        BuildError::Abigen(format!(
+                            "Could not move '{}' to '{}': {}",
+                            found.display(),
+                            abigen_txt.display(),
+                            e
+                        ))
+                    })?;
+            }
+        }
+    }
+
+    match std::fs::metadata(abigen_txt) {
+        Ok(m) if m.len() > 0 => Ok(()),
+        Ok(_) => {
+            let _ = std::fs::remove_file(abigen_txt);
+            Err(BuildError::Abigen(format!(
+                "abigen ran but '{}' is empty",
+                abigen_txt.display()
+            )))
+        }
+        Err(_) => Err(BuildError::Abigen(format!(
+            "abigen ran but did not create '{}'",
+            abigen_txt.display()
+        ))),
+    }
+}
+
+/// Look for the `abigen.txt` that abigen should have written to `abigen_txt`
+/// in the other places it might have gone: the build directory itself, next
+/// to `abigen_bin`, `build_dir/abigen` and the build script's working
+/// directory.
+///
+/// Only a file modified after `started`, when abigen was run, counts, since
+/// one left over from an earlier run would describe a stale ABI. This allows
+/// a couple of seconds for filesystems that keep coarse timestamps.
+///
+fn misplaced_abigen_txt(
+    abigen_bin: &Path,
+    build_dir: &Path,
+    abigen_txt: &Path,
+    started: SystemTime,
+) -> Option<PathBuf> {
+    let file_name = abigen_txt.file_name()?;
+    let mut dirs = vec![build_dir.to_path_buf()];
+    dirs.extend(abigen_bin.parent().map(|d| build_dir.join(d)));
+    dirs.push(build_dir.join("abigen"));
+    dirs.extend(std::env::current_dir().ok());
+    dirs.into_iter().map(|d| d.join(file_name)).find(|p| {
+        p != abigen_txt
+            && std::fs::metadata(p).map_or(false, |m| {
+                m.is_file()
+                    && m.modified().map_or(false, |t| {
+                        t + Duration::from_secs(2) >= started
+                    })
+            })
+    })
+}
+
+/// The directory the ABI template and output directories are resolved
+/// relative to.
+///
+/// cppmm normally generates the `cppmm_abi_in` template alongside the crate
+/// sources, while the generated `cppmmabi.rs` is written to `OUT_DIR` and
+/// `include!`d from there, but some projects keep both in one place or the
+/// other.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbiDirBase {
+    /// Relative to Cargo's `OUT_DIR` for the crate being built.
+    OutDir,
+    /// Relative to the crate's `CARGO_MANIFEST_DIR`.
+    ManifestDir,
+}
+
+impl AbiDirBase {
+    fn resolve(
+        &self,
+        out_dir: &Path,
+        manifest_dir: &Path,
+        dir: &str,
+    ) -> PathBuf {
+        match self {
+            AbiDirBase::OutDir => out_dir.join(dir),
+            AbiDirBase::ManifestDir => manifest_dir.join(dir),
+        }
+    }
+}
+
+/// The C++ runtime library to link the wrapper against.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CxxStdlib {
+    /// The platform's usual runtime: libstdc++ on Linux, libc++ on macOS and
+    /// nothing elsewhere.
+    Auto,
+    /// GCC's libstdc++.
+    LibStdCxx,
+    /// LLVM's libc++.
+    LibCxx,
+    /// Don't link any C++ runtime, e.g. because it's linked some other way.
+    None,
+}
+
+impl CxxStdlib {
+    fn from_env(value: &str) -> Option<CxxStdlib> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Some(CxxStdlib::Auto),
+            "stdc++" | "libstdc++" => Some(CxxStdlib::LibStdCxx),
+            "c++" | "libc++" => Some(CxxStdlib::LibCxx),
+            "none" | "" => Some(CxxStdlib::None),
+            _ => None,
+        }
+    }
+
+    /// The name to pass to `rustc-link-lib`.
+    fn link_lib(&self) -> Option<&'static str> {
+        match self {
+            CxxStdlib::Auto if cfg!(target_os = "linux") => Some("stdc++"),
+            CxxStdlib::Auto if cfg!(target_os = "macos") => Some("c++"),
+            CxxStdlib::Auto => None,
+            CxxStdlib::LibStdCxx => Some("stdc++"),
+            CxxStdlib::LibCxx => Some("c++"),
+            CxxStdlib::None => None,
+        }
+    }
+}
+
+/// How binaries find the packaged dependencies' dynamic libraries at runtime,
+/// see [`Builder::runtime_search_path`].
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeSearchPath {
+    /// Give the binaries rpaths to the installed libraries, both relative to
+    /// the executable (when the libraries are installed in the target
+    /// directory) and absolute. Windows has no rpath, so there the DLLs are
+    /// copied next to the executables instead.
+    Rpath,
+    /// Write a script into `OUT_DIR` that adds the installed libraries to
+    /// `LD_LIBRARY_PATH`, `DYLD_LIBRARY_PATH` or `PATH`, to be sourced (or
+    /// run, on Windows) before running the binaries.
+    Script,
+    /// Do neither, e.g. because the libraries are installed somewhere the
+    /// loader already looks.
+    None,
+}
+
+/// Errors that can occur while running a [`Builder`].
+///
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// Running the abigen binary failed, or it didn't produce `abigen.txt`.
+    Abigen(String),
+    /// The `depends_on` of the dependencies form a cycle, listed in order.
+    DependencyCycle(Vec<String>),
+    /// A dependency's source directory doesn't contain a `CMakeLists.txt`.
+    MissingSource {
+        dependency: String,
+        source_dir: PathBuf,
+    },
+    /// The directory of the cppmm-generated C library, see
+    /// [`Builder::clib_dir`], doesn't contain a `CMakeLists.txt`.
+    MissingWrapper(PathBuf),
+    /// Some of the libraries or search directories to be linked are missing.
+    MissingLinkArgs(Vec<String>),
+    /// None of the Python interpreters tried for `insert_abi.py` were found.
+    PythonNotFound(Vec<String>),
+    /// A program needed for the build isn't on the `PATH`.
+    MissingTool(String),
+    /// A path that has to be passed on as a string isn't valid UTF-8.
+    NonUtf8Path(PathBuf),
+    /// A dependency manifest couldn't be read or isn't valid.
+    InvalidManifest { path: PathBuf, message: String },
+    /// The version of a dependency found on the system is outside its
+    /// [`Dependency::min_version`] and [`Dependency::max_version`].
+    DependencyVersion {
+        dependency: String,
+        found: String,
+        required: String,
+    },
+    /// A callback added with [`Builder::on_dependency_built`] panicked with
+    /// `message` after the dependency was built.
+    DependencyCallback { dependency: String, message: String },
+    /// The environment variable `var` is set to a `value` that isn't valid
+    /// for it.
+    InvalidEnv { var: String, value: String },
+    /// CMake didn't write the C wrapper's link line to any of these paths.
+    MissingLinkLine(Vec<PathBuf>),
+    /// The C wrapper doesn't export the symbols it was checked for, see
+    /// [`Builder::sentinel_symbol`] and [`Builder::exported_symbol_prefix`],
+    /// or they couldn't be listed.
+    MissingExports(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Abigen(msg) => {
+                write!(f, "ABI generation failed: {}", msg)
+            }
+            BuildError::DependencyCycle(cycle) => {
+                write!(f, "Dependency cycle: {}", cycle.join(" -> "))
+            }
+            BuildError::MissingSource {
+                dependency,
+                source_dir,
+            } => write!(
+                f,
+                "No CMakeLists.txt found in '{}', the source directory for \
+                 dependency {}",
+                source_dir.display(),
+                dependency
+            ),
+            BuildError::MissingWrapper(dir) => write!(
+                f,
+                "The cppmm-generated C wrapper was not found: expected a \
+                 CMakeLists.txt in '{}'. Run cppmm to generate it, or set \
+                 Builder::clib_dir if it lives somewhere else.",
+                dir.display()
+            ),
+            BuildError::MissingLinkArgs(missing) => {
+                write!(f, "Missing link arguments: {}", missing.join("; "))
+            }
+            BuildError::MissingTool(tool) => {
+                write!(f, "Could not run {}, is it on the PATH?", tool)
+            }
+            BuildError::NonUtf8Path(path) => {
+                write!(f, "Path is not valid UTF-8: {}", path.display())
+            }
+            BuildError::InvalidManifest { path, message } => write!(
+                f,
+                "Invalid dependency manifest '{}': {}",
+                path.display(),
+                message
+            ),
+            BuildError::DependencyVersion {
+                dependency,
+                found,
+                required,
+            } => write!(
+                f,
+                "Found version {} of dependency {} on the system, but {} is \
+                 required. Point CMAKE_PREFIX_PATH at a matching version, or \
+                 unset it to build the packaged one.",
+                found, dependency, required
+            ),
+            BuildError::PythonNotFound(tried) => write!(
+                f,
+                "Could not find Python to run insert_abi.py, tried {}. Set \
+                 CPPMM_PYTHON to the interpreter to use.",
+                tried.join(", ")
+            ),
+            BuildError::DependencyCallback {
+                dependency,
+                message,
+            } => write!(
+                f,
+                "The on_dependency_built callback for dependency {} \
+                 panicked: {}. The dependency will be built again, and the \
+                 callback called again, by the next build.",
+                dependency, message
+            ),
+            BuildError::InvalidEnv { var, value } => {
+                write!(f, "Invalid value '{}' for {}", value, var)
+            }
+            BuildError::MissingLinkLine(tried) => write!(
+                f,
+                "Could not read the link line of the C wrapper, tried {}",
+                tried
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" and ")
+            ),
+            BuildError::MissingExports(msg) => {
+                write!(f, "Exported symbol check failed: {}", msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory for a test to work in, unique to it under the system's
+    /// temporary directory, which is removed again when dropped so it's
+    /// cleaned up even if the test fails.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!(
+                "cppmm-build-{}-{}",
+                name,
+                std::process::id()
+            ));
+            // left behind by a run that was killed
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn it_works() {
+        assert_eq!(2 + 2, 4);
+    }
+
+    #[test]
+    fn include_dirs_are_parsed_from_compile_commands() {
+        let build_path = TempDir::new("cc");
+        std::fs::write(
+            build_path.join("compile_commands.json"),
+            r#"[
+  {
+    "directory": "/build/openexr-c",
+    "command": "/usr/bin/c++ -I/src/include -I\"/x y\" -isystem /usr/include/Imath -o a.o -c a.cpp",
+    "file": "a.cpp"
+  },
+  {
+    "directory": "/build/openexr-c",
+    "arguments": ["/usr/bin/c++", "-Igenerated", "-I/src/include", "-isystem/opt/include", "-c", "b.cpp"],
+    "file": "b.cpp"
+  }
+]"#,
+        )
+        .unwrap();
+
+        let dirs = include_dirs_from_compile_commands(&build_path);
+
+        assert_eq!(
+            dirs,
+            vec![
+                PathBuf::from("/src/include"),
+                PathBuf::from("/x y"),
+                PathBuf::from("/usr/include/Imath"),
+                PathBuf::from("/build/openexr-c/generated"),
+                PathBuf::from("/opt/include"),
+            ]
+        );
+    }
+
+    #[test]
+    fn nmake_link_libs_reads_inline_and_response_files() {
+        let build_path = TempDir::new("nmake");
+        std::fs::write(
+            build_path.join("linkLibs.rsp"),
+            "C:\\deps\\lib\\Imath.lib kernel32.lib\r\n\
+             \"C:\\Program Files\\deps\\lib\\zlib.lib\"",
+        )
+        .unwrap();
+
+        let build_make = r#"
+CMakeFiles\openexr-c.dir\a.cpp.obj: a.cpp
+	C:\cl.exe @<<
+ /nologo /TP -c a.cpp
+<<
+
+openexr-c-0_10-shared.dll: $(openexr-c_OBJECTS)
+	C:\cmake.exe -E vs_link_dll --intdir=x -- C:\link.exe /nologo @objects1.rsp @<<
+ /out:openexr-c-0_10-shared.dll /dll /version:0.0 C:\deps\lib\OpenEXR.lib @linkLibs.rsp
+ "C:\Program Files\deps\lib\Half.lib"
+<<
+"#;
+        let libs = nmake_link_libs(build_make, &build_path, &mut Vec::new());
+
+        let names: Vec<&str> = libs
+            .iter()
+            .map(|l| match l {
+                LinkArg::Path(d) => d.libname.as_str(),
+                _ => panic!("unexpected {:?}", l),
+            })
+            .collect();
+        assert_eq!(names, vec!["OpenEXR", "Imath", "zlib", "Half"]);
+    }
+
+    #[test]
+    fn missing_wrapper_fails_the_export_check() {
+        let dst = TempDir::new("no-wrapper");
+        let result = check_exported_symbols(
+            &dst,
+            "openexr-c-0_1",
+            "openexr-c-0_1-shared",
+            &[ExpectedSymbol::Named("openexr_version")],
+        );
+        assert!(matches!(result, Err(BuildError::MissingExports(_))));
+    }
+
+    #[test]
+    fn exported_symbol_is_found_in_symbol_listing() {
+        let nm = "
+openexr-c.o:
+0000000000000000 T openexr_version
+                 U _ZNSt6vectorIiE
+                 U openexr_missing
+";
+        assert!(has_exported_symbol(nm, "openexr_version"));
+        assert!(has_exported_symbol(
+            "0000000000000000 T _openexr_version",
+            "openexr_version"
+        ));
+        assert!(!has_exported_symbol(nm, "openexr"));
+        assert!(!has_exported_symbol(nm, "openexr_missing"));
+
+        let dumpbin = "
+    ordinal hint RVA      name
+
+          1    0 00001000 openexr_version
+";
+        assert!(has_exported_symbol(dumpbin, "openexr_version"));
+
+        assert!(has_exported_symbol_prefix(nm, "openexr_"));
+        assert!(has_exported_symbol_prefix(
+            "0000000000000000 T _openexr_version",
+            "openexr_"
+        ));
+        assert!(has_exported_symbol_prefix(dumpbin, "openexr_"));
+        assert!(!has_exported_symbol_prefix(nm, "imath_"));
+        // only referenced, not exported
+        assert!(!has_exported_symbol_prefix(nm, "openexr_missing"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn abigen_failures_are_reported() {
+        let build_dir = TempDir::new("abigen");
+        let abigen_txt = build_dir.join("abigen.txt");
+
+        let failed = run_abigen(Path::new("false"), &build_dir, &abigen_txt);
+        let no_output = run_abigen(Path::new("true"), &build_dir, &abigen_txt);
+        let missing = run_abigen(
+            &build_dir.join("no-such-abigen"),
+            &build_dir,
+            &abigen_txt,
+        );
+
+        assert!(matches!(failed, Err(BuildError::Abigen(_))));
+        assert!(matches!(no_output, Err(BuildError::Abigen(_))));
+        assert!(matches!(missing, Err(BuildError::Abigen(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn misplaced_abigen_txt_is_moved() {
+        use std::os::unix::fs::PermissionsExt;
+        let build_dir = TempDir::new("abigen-dir");
+        std::fs::create_dir_all(build_dir.join("abigen")).unwrap();
+        let abigen_bin = build_dir.join("abigen").join("abigen");
+        std::fs::write(
+            &abigen_bin,
+            "#!/bin/sh
+echo 'Imf_Header|1096|8' > \"$(dirname \"$0\")/abigen.txt\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(
+            &abigen_bin,
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        let abigen_txt = build_dir.join("abigen.txt");
+
+        let result = run_abigen(&abigen_bin, &build_dir, &abigen_txt);
+        let contents = std::fs::read_to_string(&abigen_txt);
+        let left_behind = build_dir.join("abigen").join("abigen.txt").exists();
+
+        // one from before abigen was run is stale
+        std::fs::rename(
+            &abigen_txt,
+            build_dir.join("abigen").join("abigen.txt"),
+        )
+        .unwrap();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        let stale =
+            misplaced_abigen_txt(&abigen_bin, &build_dir, &abigen_txt, later);
+
+        assert!(result.is_ok());
+        assert_eq!(contents.unwrap(), "Imf_Header|1096|8\n");
+        assert!(!left_behind);
+        assert_eq!(stale, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cmake_version_is_reported() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("cmake");
+        let fake_cmake = dir.join("cmake");
+        std::fs::write(
+            &fake_cmake,
+            "#!/bin/sh\necho 'cmake version 3.27.4'\necho\n\
+             echo 'CMake suite maintained and supported by Kitware'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(
+            &fake_cmake,
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+
+        let version = cmake_version(fake_cmake.as_os_str());
+        let missing = cmake_version(dir.join("no-such-cmake").as_os_str());
+        let failed = cmake_version("false".as_ref());
+
+        assert_eq!(version.unwrap(), "3.27.4");
+        assert!(matches!(missing, Err(BuildError::MissingTool(_))));
+        assert!(matches!(failed, Err(BuildError::MissingTool(_))));
+    }
+
+    #[test]
+    fn settings_hash_changes_with_settings() {
+        let target_dir = Path::new("/target");
+        let defs = [("BUILD_TESTING", "OFF"), ("BUILD_SHARED_LIBS", "ON")];
+        let hash = |profile, defs| {
+            ThirdpartyBuild::new("zlib", target_dir, profile, defs)
+                .settings_hash()
+        };
+        assert_eq!(hash("Release", &defs), hash("Release", &defs));
+        assert_ne!(hash("Release", &defs), hash("Debug", &defs));
+        assert_ne!(hash("Release", &defs), hash("Release", &defs[..1]));
+        assert_ne!(
+            hash("Release", &[("AB", "C")]),
+            hash("Release", &[("A", "BC")])
+        );
+
+        let mut build =
+            ThirdpartyBuild::new("zlib", target_dir, "Release", &[]);
+        let unflagged = build.settings_hash();
+        build.cxxflags.push("-march=native".to_string());
+        assert_ne!(build.settings_hash(), unflagged);
+
+        let unpolicied = build.settings_hash();
+        build.set_policies(&[("CMP0077", "NEW")]);
+        assert_ne!(build.settings_hash(), unpolicied);
+    }
+
+    #[test]
+    fn build_dirs_are_per_variant() {
+        let target_dir = Path::new("/target");
+        let dir = |profile, defs| {
+            ThirdpartyBuild::new("zlib", target_dir, profile, defs).build_dir()
+        };
+        let release = dir("Release", &[]);
+        assert_eq!(release, dir("Release", &[]));
+        assert_ne!(release, dir("Debug", &[]));
+        assert_ne!(release, dir("Release", &[("ZLIB_COMPAT", "ON")]));
+
+        let name = release.file_name().unwrap().to_str().unwrap();
+        let variant = name.strip_prefix("build-zlib-").unwrap();
+        assert_eq!(variant.len(), 8);
+        assert_eq!(release.parent(), Some(target_dir));
+    }
+
+    #[test]
+    fn dependency_policies_override_defaults() {
+        let mut build =
+            ThirdpartyBuild::new("zlib", Path::new("/target"), "Release", &[]);
+        build.set_policies(&[("CMP0091", "OLD"), ("CMP0077", "NEW")]);
+        assert_eq!(
+            build.policies,
+            vec![
+                ("CMP0091".to_string(), "OLD".to_string()),
+                ("CMP0077".to_string(), "NEW".to_string()),
+            ]
+        );
+
+        let defaults =
+            ThirdpartyBuild::new("zlib", Path::new("/target"), "Release", &[]);
+        assert_eq!(
+            defaults.policies.iter().any(|(id, _)| id == "CMP0091"),
+            cfg!(target_os = "windows")
+        );
+    }
+
+    #[test]
+    fn stale_install_is_detected() {
+        let out_dir = TempDir::new("installed");
+        std::fs::create_dir_all(out_dir.join("build")).unwrap();
+        assert!(!is_installed(&out_dir));
+
+        let lib = out_dir.join("libhello.so");
+        std::fs::write(&lib, "").unwrap();
+        std::fs::write(
+            out_dir.join("build").join("install_manifest.txt"),
+            format!("{}\n", lib.display()),
+        )
+        .unwrap();
+        assert!(is_installed(&out_dir));
+
+        std::fs::remove_file(&lib).unwrap();
+        assert!(!is_installed(&out_dir));
+    }
+
+    #[test]
+    fn clean_thirdparty_removes_installed_files() {
+        let root = TempDir::new("clean");
+        let target_dir = root.join("target");
+        let build_dir = target_dir.join("build-zlib-0123abcd").join("build");
+        std::fs::create_dir_all(target_dir.join("build-zlib")).unwrap();
+        std::fs::create_dir_all(target_dir.join("build-zlib-ng")).unwrap();
+        std::fs::create_dir_all(&build_dir).unwrap();
+        std::fs::create_dir_all(target_dir.join("lib")).unwrap();
+        let lib = target_dir.join("lib").join("libz.so");
+        let other = target_dir.join("lib").join("libImath.so");
+        let outside = root.join("libz.so");
+        for f in &[&lib, &other, &outside] {
+            std::fs::write(f, "").unwrap();
+        }
+        std::fs::write(
+            build_dir.join("install_manifest.txt"),
+            format!("{}\n{}\n", lib.display(), outside.display()),
+        )
+        .unwrap();
+
+        clean_thirdparty("zlib", &target_dir).unwrap();
+        let removed = (
+            !lib.exists(),
+            !target_dir.join("build-zlib-0123abcd").exists(),
+            !target_dir.join("build-zlib").exists(),
+        );
+        let kept = (
+            other.exists(),
+            outside.exists(),
+            target_dir.join("build-zlib-ng").exists(),
+        );
+        // cleaning twice is fine
+        let again = clean_thirdparty("zlib", &target_dir);
+
+        assert_eq!(removed, (true, true, true));
+        assert_eq!(kept, (true, true, true));
+        assert!(again.is_ok());
+    }
+
+    #[test]
+    fn abi_dirs_resolve_against_chosen_base() {
+        assert_eq!(
+            AbiDirBase::OutDir.resolve(
+                Path::new("/out"),
+                Path::new("/crate"),
+                "cppmm_abi_out"
+            ),
+            PathBuf::from("/out/cppmm_abi_out")
+        );
+        assert_eq!(
+            AbiDirBase::ManifestDir.resolve(
+                Path::new("/out"),
+                Path::new("/crate"),
+                "cppmm_abi_in"
+            ),
+            PathBuf::from("/crate/cppmm_abi_in")
+        );
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn interface_link_libraries_are_read_from_package_configs() {
+        let prefix = TempDir::new("iface");
+        let package_dir = prefix.join("lib").join("cmake").join("OpenEXR");
+        std::fs::create_dir_all(&package_dir).unwrap();
+        std::fs::write(
+            package_dir.join("OpenEXRTargets.cmake"),
+            r#"
+add_library(OpenEXR::OpenEXR SHARED IMPORTED)
+set_target_properties(OpenEXR::OpenEXR PROPERTIES
+  INTERFACE_INCLUDE_DIRECTORIES "${_IMPORT_PREFIX}/include"
+  INTERFACE_LINK_LIBRARIES "OpenEXR::Iex;$<LINK_ONLY:Threads::Threads>;$<LINK_ONLY:dl>;/usr/lib/libz.so.1"
+)
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            package_dir.join("OpenEXRTargets-release.cmake"),
+            r#"
+set_target_properties(OpenEXR::Iex PROPERTIES
+  IMPORTED_LOCATION_RELEASE "${_IMPORT_PREFIX}/lib/libIex-3_1.so.30.5.1"
+  IMPORTED_SONAME_RELEASE "libIex-3_1.so.30"
+  )
+"#,
+        )
+        .unwrap();
+
+        let libs =
+            interface_link_libraries(&[prefix.to_path_buf()], &["openexr"]);
+
+        let names: Vec<String> = libs
+            .iter()
+            .map(|l| match l {
+                LinkArg::Path(d) => format!("path:{}", d.libname),
+                LinkArg::LinkLib(l) => format!("lib:{}", l),
+                LinkArg::LinkDir(d) => format!("dir:{}", d),
+                LinkArg::ObjectFile(o) => format!("obj:{}", o),
+                LinkArg::IncludeDir(d) => format!("include:{}", d),
+            })
+            .collect();
+        assert_eq!(names, vec!["path:Iex-3_1", "lib:dl", "path:z"]);
+    }
+
+    #[test]
+    fn dependencies_are_built_in_dependency_order() {
+        let deps = [
+            Dependency {
+                name: "openexr",
+                depends_on: &["Imath", "zlib"],
+                ..Default::default()
+            },
+            Dependency {
+                name: "zlib",
+                ..Default::default()
+            },
+            Dependency {
+                name: "Imath",
+                ..Default::default()
+            },
+            Dependency {
+                name: "zlib",
+                ..Default::default()
+            },
+        ];
+        let deps: Vec<DependencyOwned> =
+            deps.iter().map(DependencyOwned::from).collect();
+        let order: Vec<&str> = build_order(&deps)
+            .unwrap()
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect();
+        assert_eq!(order, vec!["Imath", "zlib", "openexr"]);
+
+        let no_deps: Vec<&str> = build_order(&deps[1..])
+            .unwrap()
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect();
+        assert_eq!(no_deps, vec!["zlib", "Imath"]);
+    }
+
+    #[test]
+    fn static_and_owned_dependencies_mix() {
+        let name = format!("open{}", "exr");
+        let mut builder = Builder::new("openexr", 0, 10);
+        builder
+            .dependency(Dependency {
+                name: "zlib",
+                definitions: vec![("ZLIB_COMPAT", "ON")],
+                depends_on: &["cmake"],
+                policies: &[("CMP0077", "NEW")],
+                ..Default::default()
+            })
+            .owned_dependency(DependencyOwned {
+                name: name.clone(),
+                depends_on: vec!["zlib".into()],
+                ..Default::default()
+            });
+
+        assert_eq!(
+            builder.dependencies,
+            vec![
+                DependencyOwned {
+                    name: "zlib".into(),
+                    definitions: vec![("ZLIB_COMPAT".into(), "ON".into())],
+                    depends_on: vec!["cmake".into()],
+                    policies: vec![("CMP0077".into(), "NEW".into())],
+                    ..Default::default()
+                },
+                DependencyOwned {
+                    name,
+                    depends_on: vec!["zlib".into()],
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn dependencies_are_read_from_manifest() {
+        let dir = TempDir::new("manifest");
+        let read = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            read_dependency_manifest(&path)
+        };
+
+        let deps = read(
+            "good.json",
+            r#"{"dependencies": [
+                {"name": "zlib"},
+                {"name": "openexr", "definitions": {"BUILD_TESTING": "OFF"},
+                 "depends_on": ["zlib"], "max_jobs": 4,
+                 "source_dir": "/src/openexr", "feature": "exr",
+                 "policies": {"CMP0077": "NEW"}, "shared": false,
+                 "min_version": "3.1", "parallel": false,
+                 "force_build": true}
+            ]}"#,
+        );
+        let invalid: Vec<String> = [
+            r#"[{"name": "zlib"}]"#,
+            r#"{"dependencies": [{"definitions": {}}]}"#,
+            r#"{"dependencies": [{"name": "zlib", "max_job": 4}]}"#,
+            r#"{"dependencies": [{"name": "zlib", "max_jobs": 0}]}"#,
+            r#"{"dependencies": [{"name": "a", "definitions": {"X": 1}}]}"#,
+            r#"{"dependencies": [{"name": "zlib"}"#,
+            r#"{"dependencies": [{"name": "zlib", "shared": "no"}]}"#,
+            r#"{"dependencies": [{"name": "zlib", "parallel": 1}]}"#,
+            r#"{"dependencies": [{"name": "zlib", "force_build": "1"}]}"#,
+        ]
+        .iter()
+        .map(|contents| match read("bad.json", contents) {
+            Err(e @ BuildError::InvalidManifest { .. }) => e.to_string(),
+            r => panic!("expected an invalid manifest, got {:?}", r),
+        })
+        .collect();
+
+        let deps = deps.unwrap();
+        assert_eq!(
+            deps[0],
+            DependencyOwned {
+                name: "zlib".into(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            deps[1],
+            DependencyOwned {
+                name: "openexr".into(),
+                definitions: vec![("BUILD_TESTING".into(), "OFF".into())],
+                max_jobs: Some(4),
+                depends_on: vec!["zlib".into()],
+                source_dir: Some("/src/openexr".into()),
+                feature: Some("exr".into()),
+                policies: vec![("CMP0077".into(), "NEW".into())],
+                shared: Some(false),
+                min_version: Some("3.1".into()),
+                max_version: None,
+                parallel: false,
+                force_build: true,
+            }
+        );
+        assert!(invalid[0].contains("\"dependencies\" array"));
+        assert!(invalid[1].contains("dependency 0: missing \"name\""));
+        assert!(invalid[2].contains("unknown field \"max_job\""));
+        assert!(invalid[3].contains("\"max_jobs\""));
+        assert!(invalid[4].contains("\"definitions\" values must be strings"));
+        assert!(invalid[5].contains("bad.json"));
+        assert!(invalid[6].contains("\"shared\" must be true or false"));
+        assert!(invalid[7].contains("\"parallel\" must be true or false"));
+        assert!(invalid[8].contains("\"force_build\" must be true or false"));
+    }
+
+    #[test]
+    fn system_dependency_versions_are_checked() {
+        let dep = |min: Option<&str>, max: Option<&str>| DependencyOwned {
+            name: "openexr".into(),
+            min_version: min.map(str::to_string),
+            max_version: max.map(str::to_string),
+            ..Default::default()
+        };
+        let check = |min, max, found| {
+            check_dependency_version(&dep(min, max), found).map_err(|e| {
+                assert!(matches!(e, BuildError::DependencyVersion { .. }));
+                e.to_string()
+            })
+        };
+
+        assert!(check(Some("3.1"), None, "3.1.4").is_ok());
+        assert!(check(Some("3.1.2"), None, "3.1.10").is_ok());
+        assert!(check(None, Some("3.2"), "3.2.9").is_ok());
+        assert!(check(Some("2.5"), Some("3.2"), "3.0.0-rc1").is_ok());
+        assert!(check(Some("3.1.2"), None, "3.1")
+            .unwrap_err()
+            .contains("version 3.1 of dependency openexr"));
+        assert!(check(Some("3.1"), Some("3.2"), "3.3.0")
+            .unwrap_err()
+            .contains("3.1 to 3.2 is required"));
+        assert!(check(None, Some("2"), "3.0")
+            .unwrap_err()
+            .contains("at most 2"));
+    }
+
+    #[test]
+    fn package_names_match_installed_case() {
+        let prefix = TempDir::new("names");
+        std::fs::create_dir_all(
+            prefix.join("lib").join("cmake").join("OpenEXR"),
+        )
+        .unwrap();
+        let names = package_names("openexr", &[prefix.to_path_buf()]);
+        assert_eq!(names, vec!["openexr", "OpenEXR"]);
+    }
+
+    #[test]
+    fn probed_versions_are_cached_until_the_package_changes() {
+        let dir = TempDir::new("probe-cache");
+        let prefix = dir.join("prefix");
+        let package = prefix.join("lib").join("cmake").join("Imath");
+        std::fs::create_dir_all(&package).unwrap();
+        std::fs::write(package.join("ImathConfig.cmake"), "").unwrap();
+        let probe_dir = dir.join("probe");
+        std::fs::create_dir_all(&probe_dir).unwrap();
+        let names = vec!["Imath".to_string()];
+        let prefixes = vec![prefix];
+
+        // a version an earlier build found, which the probe wouldn't
+        let key = version_probe_key(&names, &prefixes, &[]);
+        std::fs::write(
+            probe_dir.join("version.stamp"),
+            format!("{}\n3.1.9", key),
+        )
+        .unwrap();
+        let cached = cached_package_version(&names, &prefixes, &probe_dir, &[]);
+        let definitions = [("Imath_ROOT".to_string(), "/opt".into())];
+        let other_definitions =
+            version_probe_key(&names, &prefixes, &definitions);
+        std::fs::write(package.join("ImathConfigVersion.cmake"), "").unwrap();
+        let upgraded = version_probe_key(&names, &prefixes, &[]);
+
+        assert_eq!(cached.as_deref(), Some("3.1.9"));
+        assert_ne!(other_definitions, key);
+        assert_ne!(upgraded, key);
+    }
+
+    #[test]
+    fn dependency_cycles_are_reported() {
+        let deps = [
+            Dependency {
+                name: "a",
+                depends_on: &["b"],
+                ..Default::default()
+            },
+            Dependency {
+                name: "b",
+                depends_on: &["a"],
+                ..Default::default()
+            },
+        ];
+        let deps: Vec<DependencyOwned> =
+            deps.iter().map(DependencyOwned::from).collect();
+        match build_order(&deps) {
+            Err(BuildError::DependencyCycle(cycle)) => {
+                assert_eq!(cycle, vec!["a", "b", "a"])
+            }
+            r => panic!("expected a cycle, got {:?}", r),
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn abigen_is_found_for_the_matching_configuration() {
+        let build_dir = TempDir::new("abigen-cfg");
+        let abigen_dir = build_dir.join("abigen");
+        std::fs::create_dir_all(abigen_dir.join("Release")).unwrap();
+        std::fs::create_dir_all(abigen_dir.join("Debug")).unwrap();
+        std::fs::write(abigen_dir.join("Release").join("abigen.exe"), "")
+            .unwrap();
+        std::fs::write(abigen_dir.join("Debug").join("abigen.exe"), "")
+            .unwrap();
+
+        let single = abigen_binary(&build_dir, "Debug").unwrap();
+        std::fs::write(abigen_dir.join("abigen.vcxproj"), "").unwrap();
+        let debug = abigen_binary(&build_dir, "Debug").unwrap();
+        let release = abigen_binary(&build_dir, "Release").unwrap();
+
+        assert_eq!(single, abigen_dir.join("abigen.exe"));
+        assert_eq!(debug, abigen_dir.join("Debug").join("abigen.exe"));
+        assert_eq!(release, abigen_dir.join("Release").join("abigen.exe"));
+    }
+
+    fn dylib(path: &str, libname: &str) -> LinkArg {
+        LinkArg::Path(DylibPathInfo {
+            path: path.to_string(),
+            basename: Path::new(path)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+            libname: libname.to_string(),
+            dll: None,
+        })
+    }
+
+    #[test]
+    fn link_sets_are_merged() {
+        let merged = merge_link_args(&[
+            vec![
+                LinkArg::LinkLib("dl".to_string()),
+                dylib("/a/libImath.so", "Imath"),
+                LinkArg::LinkDir("/a".to_string()),
+            ],
+            vec![
+                LinkArg::LinkDir("/b".to_string()),
+                dylib("/b/libImath.so", "Imath"),
+                LinkArg::LinkDir("/a".to_string()),
+                LinkArg::LinkLib("dl".to_string()),
+                dylib("/b/libz.so", "z"),
+            ],
+        ]);
+        assert_eq!(
+            merged,
+            vec![
+                LinkArg::LinkDir("/a".to_string()),
+                LinkArg::LinkDir("/b".to_string()),
+                LinkArg::LinkLib("dl".to_string()),
+                dylib("/a/libImath.so", "Imath"),
+                dylib("/b/libz.so", "z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn insert_abi_args_keep_paths_with_spaces_whole() {
+        let out_dir = Path::new("C:/Users/First Last/target/debug/build/out");
+        let command = insert_abi_command(
+            Path::new("python3"),
+            &Path::new("openexr-c").join("abigen").join("insert_abi.py"),
+            &Path::new("C:/Users/First Last/openexr-sys").join("cppmm_abi_in"),
+            &out_dir.join("cppmm_abi_out"),
+            &out_dir.join("build").join("abigen.txt"),
+        );
+        let args: Vec<&std::ffi::OsStr> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                Path::new("openexr-c")
+                    .join("abigen")
+                    .join("insert_abi.py")
+                    .as_os_str(),
+                Path::new("C:/Users/First Last/openexr-sys")
+                    .join("cppmm_abi_in")
+                    .as_os_str(),
+                out_dir.join("cppmm_abi_out").as_os_str(),
+                out_dir.join("build").join("abigen.txt").as_os_str(),
+            ]
+        );
+    }
+
+    #[test]
+    fn python_is_found_from_candidates() {
+        let dir = TempDir::new("python");
+        let python = dir.join("python3.9");
+        std::fs::write(&python, "").unwrap();
+
+        let found = find_python(&[dir.join("python3"), python.clone()]);
+        let missing = find_python(&[dir.join("python3"), dir.join("python")]);
+
+        assert_eq!(found.unwrap(), python);
+        match missing {
+            Err(BuildError::PythonNotFound(tried)) => {
+                assert_eq!(tried.len(), 2)
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn bool_env_values() {
+        for val in &["1", "true", "TRUE", "Yes", "on", " on "] {
+            assert!(parse_bool_env(val), "{}", val);
+        }
+        for val in &["0", "false", "No", "OFF", "", "maybe"] {
+            assert!(!parse_bool_env(val), "{}", val);
+        }
+    }
+
+    #[test]
+    fn feature_env_vars_follow_cargo_convention() {
+        assert_eq!(feature_env_var("exr"), "CARGO_FEATURE_EXR");
+        assert_eq!(feature_env_var("deep-images"), "CARGO_FEATURE_DEEP_IMAGES");
+    }
+
+    #[test]
+    fn relative_prefixes_resolve_against_crate_root() {
+        let manifest_dir = Path::new("/src/openexr-sys");
+        assert_eq!(
+            resolve_prefix(Path::new("../prefix"), manifest_dir),
+            PathBuf::from("/src/openexr-sys/../prefix")
+        );
+        assert_eq!(
+            resolve_prefix(Path::new("/opt/vfx"), manifest_dir),
+            PathBuf::from("/opt/vfx")
+        );
+    }
+
+    #[test]
+    fn invalid_vsproj_has_no_link_libs() {
+        let proj = r#"<Project>
+  <ItemDefinitionGroup Condition="'$(Configuration)'=='Release'">
+    <Link>
+      <AdditionalDependencies>Imath.lib</Link>
+  </ItemDefinitionGroup>
+</Project>"#;
+        assert_eq!(vsproj_link_libs(proj, "Release", &mut Vec::new()), None);
+        let proj = r#"<Project>
+  <ItemDefinitionGroup Condition="'$(Configuration)'=='Release'">
+    <Link>
+      <AdditionalDependencies>Imath&bogus;.lib</AdditionalDependencies>
+    </Link>
+  </ItemDefinitionGroup>
+</Project>"#;
+        assert_eq!(vsproj_link_libs(proj, "Release", &mut Vec::new()), None);
+    }
+
+    #[test]
+    fn vsproj_link_libs_for_configuration() {
+        let proj = r#"<?xml version="1.0" encoding="utf-8"?>
+<Project DefaultTargets="Build" ToolsVersion="16.0">
+  <ItemDefinitionGroup Condition="'$(Configuration)|$(Platform)'=='Debug|x64'">
+    <Link>
+      <AdditionalDependencies>C:\t\lib\Imath-3_1_d.lib;kernel32.lib;%(AdditionalDependencies)</AdditionalDependencies>
+    </Link>
+  </ItemDefinitionGroup>
+  <ItemDefinitionGroup Condition="'$(Configuration)|$(Platform)'=='Release|x64'">
+    <ClCompile>
+      <Optimization>MaxSpeed</Optimization>
+    </ClCompile>
+    <Link>
+      <AdditionalDependencies>C:\t\lib\Imath-3_1.lib;kernel32.lib</AdditionalDependencies>
+    </Link>
+  </ItemDefinitionGroup>
+  <ItemDefinitionGroup Condition="'$(Configuration)|$(Platform)'=='Release|Win32'">
+    <Link>
+      <AdditionalDependencies>C:\t\lib\OpenEXR-3_1.lib;C:\t\lib\Imath-3_1.lib</AdditionalDependencies>
+    </Link>
+  </ItemDefinitionGroup>
+  <ItemDefinitionGroup Condition="'$(Configuration)|$(Platform)'=='MinSizeRelease|x64'">
+    <Link>
+      <AdditionalDependencies>C:\t\lib\Iex-3_1.lib</AdditionalDependencies>
+    </Link>
+  </ItemDefinitionGroup>
+</Project>
+"#;
+
+        let libnames = |build_type: &str| -> Option<Vec<String>> {
+            vsproj_link_libs(proj, build_type, &mut Vec::new()).map(|args| {
+                args.into_iter()
+                    .map(|a| match a {
+                        LinkArg::Path(d) => d.libname,
+                        a => panic!("unexpected {:?}", a),
+                    })
+                    .collect()
+            })
+        };
+
+        assert_eq!(
+            libnames("Release"),
+            Some(vec!["Imath-3_1".to_string(), "OpenEXR-3_1".to_string()])
+        );
+        assert_eq!(libnames("Debug"), Some(vec!["Imath-3_1_d".to_string()]));
+        assert_eq!(
+            libnames("Release|x64"),
+            Some(vec!["Imath-3_1".to_string()])
+        );
+        assert_eq!(
+            libnames(&vsproj_configuration("release", "i686-pc-windows-msvc")),
+            Some(vec!["OpenEXR-3_1".to_string(), "Imath-3_1".to_string()])
+        );
+        assert_eq!(libnames("RelWithDebInfo"), None);
+        assert_eq!(libnames("Release|ARM64"), None);
+        assert_eq!(
+            vsproj_configuration("Release", "aarch64-pc-windows-msvc"),
+            "Release|ARM64"
+        );
+    }
+
+    #[test]
+    fn vsproj_librarian_libs() {
+        let proj = r#"<?xml version="1.0" encoding="utf-8"?>
+<Project DefaultTargets="Build" ToolsVersion="16.0">
+  <ItemDefinitionGroup Condition="'$(Configuration)|$(Platform)'=='Debug|x64'">
+    <Lib>
+      <AdditionalDependencies>C:\t\lib\zlibd.lib;%(AdditionalDependencies)</AdditionalDependencies>
+    </Lib>
+  </ItemDefinitionGroup>
+  <ItemDefinitionGroup Condition="'$(Configuration)|$(Platform)'=='Release|x64'">
+    <Lib>
+      <AdditionalDependencies>C:\t\lib\zlib.lib;%(AdditionalDependencies)</AdditionalDependencies>
+      <AdditionalOptions>%(AdditionalOptions) /machine:x64</AdditionalOptions>
+    </Lib>
+    <Link>
+      <AdditionalDependencies>C:\t\lib\Imath-3_1.lib</AdditionalDependencies>
+    </Link>
+  </ItemDefinitionGroup>
+</Project>
+"#;
+        let libnames = |configuration: &str| -> Option<Vec<String>> {
+            vsproj_link_libs(proj, configuration, &mut Vec::new()).map(|args| {
+                args.into_iter()
+                    .map(|a| match a {
+                        LinkArg::Path(d) => d.libname,
+                        a => panic!("unexpected {:?}", a),
+                    })
+                    .collect()
+            })
+        };
+        assert_eq!(
+            libnames("Release|x64"),
+            Some(vec!["zlib".to_string(), "Imath-3_1".to_string()])
+        );
+        assert_eq!(libnames("Debug"), Some(vec!["zlibd".to_string()]));
+        assert_eq!(libnames("Release|Win32"), None);
+    }
+
+    #[test]
+    fn ninja_link_libs_from_build_ninja() {
+        let build_ninja = r#"
+build CMakeFiles\openexr-c-0_1.dir\openexr.cpp.obj: CXX_COMPILER__openexr-c-0_1_Release ..\openexr.cpp
+  FLAGS = /O2
+
+build bin\openexr-c-0_1.dll lib\openexr-c-0_1.lib: CXX_SHARED_LIBRARY_LINKER__openexr-c-0_1-shared_Release CMakeFiles\openexr-c-0_1.dir\openexr.cpp.obj
+  LINK_FLAGS = /machine:x64 /INCREMENTAL:NO
+  LINK_LIBRARIES = C:\t\lib\OpenEXR-3_1.lib  "C:\Program Files\t\lib\Imath-3_1.lib"  kernel32.lib user32.lib
+  RSP_FILE = CMakeFiles\openexr-c-0_1-shared.rsp
+  TARGET_FILE = bin\openexr-c-0_1.dll
+"#;
+        let mut unclassified = Vec::new();
+        let libs = ninja_link_libs(
+            build_ninja,
+            "openexr-c-0_1",
+            Path::new("."),
+            &mut unclassified,
+        )
+        .unwrap();
+        let paths: Vec<_> = libs
+            .into_iter()
+            .map(|a| match a {
+                LinkArg::Path(d) => d.path,
+                a => panic!("unexpected {:?}", a),
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                r"C:\t\lib\OpenEXR-3_1.lib",
+                r"C:\Program Files\t\lib\Imath-3_1.lib"
+            ]
+        );
+        assert_eq!(unclassified, vec!["kernel32.lib", "user32.lib"]);
+
+        assert!(ninja_link_libs(
+            build_ninja,
+            "imath-c-0_1",
+            Path::new("."),
+            &mut Vec::new()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn windows_command_lines_are_split() {
+        assert_eq!(
+            split_windows_words(
+                r#"C:\a\x.lib  "C:\Program Files\y.lib" a"b c"d "" \\"e\" \\\\"f""#
+            ),
+            vec![
+                r"C:\a\x.lib",
+                r"C:\Program Files\y.lib",
+                "ab cd",
+                "",
+                r#"\e" \\f"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn dll_lib_paths() {
+        let re = Regex::new(DLL_LIB_RE).unwrap();
+        for path in &[
+            r"C:\deps\lib\Imath-3_1.lib",
+            "C:/deps/lib/Imath-3_1.lib",
+            r#""C:/Program Files/deps/lib/Imath-3_1.lib""#,
+            r#""C:\Program Files\deps/lib\Imath-3_1.lib""#,
+        ] {
+            match is_dll_lib_path(path, &re) {
+                Some(LinkArg::Path(d)) => {
+                    assert_eq!(d.libname, "Imath-3_1");
+                    assert_eq!(d.basename, "Imath-3_1.lib");
+                    assert!(!d.path.contains('"'));
+                }
+                a => panic!("{} gave {:?}", path, a),
+            }
+        }
+        assert_eq!(is_dll_lib_path("kernel32.lib", &re), None);
+        assert_eq!(is_dll_lib_path("C:/deps/lib/Imath-3_1.dll", &re), None);
+        assert_eq!(is_dll_lib_path("C:/deps/bin/Imath-3_1.DLL", &re), None);
+    }
+
+    #[test]
+    fn dll_lib_paths_ignore_extension_case() {
+        let re = Regex::new(DLL_LIB_RE).unwrap();
+        let sdk =
+            r"C:\Program Files (x86)\Windows Kits\10\Lib\10.0.19041.0\um\x64";
+        for (path, libname) in &[
+            (format!(r"{}\OpenGL32.Lib", sdk), "OpenGL32"),
+            (format!(r"{}\WS2_32.Lib", sdk), "WS2_32"),
+            (format!(r"{}\DbgHelp.Lib", sdk), "DbgHelp"),
+            (r"C:\tbb\lib\intel64\vc14\TBB12.LIB".to_string(), "TBB12"),
+        ] {
+            match is_dll_lib_path(path, &re) {
+                Some(LinkArg::Path(d)) => assert_eq!(d.libname, *libname),
+                a => panic!("{} gave {:?}", path, a),
+            }
+        }
+        assert_eq!(is_dll_lib_path("WS2_32.Lib", &re), None);
+    }
+
+    /// An `ar` archive member named `name` holding `data`.
+    fn archive_member(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut member = format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name,
+            0,
+            0,
+            0,
+            0,
+            data.len()
+        )
+        .into_bytes();
+        member.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            member.push(b'\n');
+        }
+        member
+    }
+
+    /// A short import member's data, saying it holds `size_of_data` bytes
+    /// of `symbol_and_dll`.
+    fn short_import(symbol_and_dll: &[u8], size_of_data: u32) -> Vec<u8> {
+        let mut import = vec![0, 0, 0xff, 0xff, 0, 0, 0x64, 0x86, 0, 0, 0, 0];
+        import.extend_from_slice(&size_of_data.to_le_bytes());
+        import.extend_from_slice(&[0, 0, 0x08, 0]);
+        import.extend_from_slice(symbol_and_dll);
+        import
+    }
+
+    #[test]
+    fn dll_names_are_read_from_import_libs() {
+        let symbol_and_dll = b"?greet@@YAHXZ\0foo.dll\0";
+        let import = short_import(symbol_and_dll, symbol_and_dll.len() as u32);
+
+        let mut lib = b"!<arch>\n".to_vec();
+        lib.extend(archive_member("/", &[0, 0, 0, 1, 0, 0, 0, 0, 0]));
+        // The import descriptor is a regular object file
+        lib.extend(archive_member("foo.dll/", &[0x64, 0x86, 1, 0, 0, 0]));
+        lib.extend(archive_member("foo.dll/", &import));
+
+        let root = TempDir::new("implib");
+        let path = root.join("foo_md.lib");
+        std::fs::write(&path, &lib).unwrap();
+        let re = Regex::new(DLL_LIB_RE).unwrap();
+        let info = dll_lib_path_info(path.to_str().unwrap(), &re);
+        let mut static_lib = b"!<arch>\n".to_vec();
+        static_lib.extend(archive_member("foo.obj/", &[0x64, 0x86, 1, 0]));
+        std::fs::write(&path, &static_lib).unwrap();
+        let static_info = dll_lib_path_info(path.to_str().unwrap(), &re);
+
+        // Linked as foo_md, but loaded as foo.dll
+        let info = info.unwrap();
+        assert_eq!(info.libname, "foo_md");
+        assert_eq!(info.dll.as_deref(), Some("foo.dll"));
+        assert_eq!(static_info.unwrap().dll, None);
+        assert_eq!(import_lib_dll(std::io::Cursor::new(b"not a lib")), None);
+    }
 
-    // if the generated rust doesn't exist, run the python to generate it
-    if !cppmm_abi_out.exists() {
-        let output = std::process::Command::new("python")
-            .args(&[&format!("{}-c/abigen/insert_abi.py", project_name), 
-                "cppmm_abi_in", 
-                &format!("{}/cppmm_abi_out", out_dir), 
-                &format!("{}/build/abigen.txt", out_dir)])
-            .output()
-            .expect("Could not launch python insert_abi.py");
+    #[test]
+    fn corrupt_import_libs_have_no_dll() {
+        let read = |members: &[Vec<u8>]| {
+            let mut lib = b"!<arch>\n".to_vec();
+            for member in members {
+                lib.extend_from_slice(member);
+            }
+            import_lib_dll(std::io::Cursor::new(lib))
+        };
+
+        // A negative size used to seek back onto the same header forever
+        let mut negative = archive_member("foo.dll/", &[0; 4]);
+        negative[48..58].copy_from_slice(format!("{:<10}", -60).as_bytes());
+        assert_eq!(read(&[negative]), None);
 
-        if !output.status.success() {{
-            for line in std::str::from_utf8(&output.stderr).unwrap().lines() {{
-                println!("cargo:warning={}", line);
-            }}
-            panic!("python insert_abi failed");
-        }}
+        let header = archive_member("foo.dll/", &[0; 4]);
+        assert_eq!(read(&[header[..30].to_vec()]), None);
+
+        let oversized = short_import(b"?greet@@YAHXZ\0foo.dll\0", u32::MAX);
+        assert_eq!(read(&[archive_member("foo.dll/", &oversized)]), None);
     }
 
-}
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn link_txt_without_output() {
+        let re = Regex::new(DYLIB_RE).unwrap();
+        let build = Path::new("/build");
+        let mut unclassified = Vec::new();
+        let with_output = link_txt_link_args(
+            "/usr/bin/c++ -fPIC -shared -o libfoo.so CMakeFiles/foo.dir/foo.o \
+             -L/deps/lib /deps/lib/libImath.so.29 -Wl,--as-needed -lz\n",
+            build,
+            &re,
+            &mut unclassified,
+        );
+        assert_eq!(unclassified, vec!["-Wl,--as-needed"]);
+        assert_eq!(
+            with_output,
+            vec![
+                LinkArg::LinkDir("/deps/lib".into()),
+                LinkArg::Path(
+                    parse_dylib_path("/deps/lib/libImath.so.29").unwrap()
+                ),
+                LinkArg::LinkLib("z".into()),
+            ]
+        );
 
-#[cfg(test)]
-mod tests {
+        // e.g. when the output is given as -o<file> or via a response file
+        let without_output = link_txt_link_args(
+            "/usr/bin/c++ -shared @objects.rsp /deps/lib/libImath.so.29 -lz",
+            build,
+            &re,
+            &mut Vec::new(),
+        );
+        assert_eq!(without_output, with_output[1..]);
+
+        assert!(link_txt_link_args(
+            "/usr/bin/ar qc libfoo.a foo.o\n/usr/bin/ranlib libfoo.a\n",
+            build,
+            &re,
+            &mut Vec::new()
+        )
+        .is_empty());
+        assert!(link_txt_link_args(
+            "/usr/bin/x86_64-linux-gnu-ar qc libfoo.a foo.o",
+            build,
+            &re,
+            &mut Vec::new()
+        )
+        .is_empty());
+    }
+
+    #[cfg(not(target_os = "windows"))]
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn link_txt_paths_with_spaces() {
+        let libs = link_txt_link_args(
+            "/usr/bin/c++ -shared -o \"/my build/libfoo.so\" \
+             \"/my build/deps/lib/libImath.so.29\" \
+             '/opt/My SDK/lib/libtbb.so' /home/me/VFX\\ Libs/lib/libz.so \
+             -L\"/my build/deps/lib\" -lOpenEXR\n",
+            Path::new("/my build"),
+            &Regex::new(DYLIB_RE).unwrap(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            libs,
+            vec![
+                LinkArg::Path(
+                    parse_dylib_path("/my build/deps/lib/libImath.so.29")
+                        .unwrap()
+                ),
+                LinkArg::Path(
+                    parse_dylib_path("/opt/My SDK/lib/libtbb.so").unwrap()
+                ),
+                LinkArg::Path(
+                    parse_dylib_path("/home/me/VFX Libs/lib/libz.so").unwrap()
+                ),
+                LinkArg::LinkDir("/my build/deps/lib".into()),
+                LinkArg::LinkLib("OpenEXR".into()),
+            ]
+        );
+        assert_eq!(
+            split_shell_words("a\\\n b '' \"x\\\"y\\z\""),
+            vec!["a", "b", "", "x\"y\\z"]
+        );
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn object_files_keep_their_place_on_the_link_line() {
+        let libs = link_txt_link_args(
+            "/usr/bin/c++ -shared -o libfoo.so CMakeFiles/foo.dir/foo.o \
+             /deps/lib/resources.o /deps/lib/libImath.so.29 res/icons.o -lz",
+            Path::new("/build"),
+            &Regex::new(DYLIB_RE).unwrap(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            libs,
+            vec![
+                LinkArg::ObjectFile("/deps/lib/resources.o".into()),
+                LinkArg::Path(
+                    parse_dylib_path("/deps/lib/libImath.so.29").unwrap()
+                ),
+                LinkArg::ObjectFile("/build/res/icons.o".into()),
+                LinkArg::LinkLib("z".into()),
+            ]
+        );
+        assert_eq!(merge_link_args(&[libs.clone(), libs.clone()]), libs);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn link_txt_is_found_in_shared_target_dir() {
+        let build = TempDir::new("linktxt");
+        let target_dir =
+            build.join("CMakeFiles").join("openexr-c-0_1-shared.dir");
+        std::fs::create_dir_all(&target_dir).unwrap();
+        std::fs::write(
+            target_dir.join("link.txt"),
+            "/usr/bin/c++ -shared -o libopenexr-c-0_1.so \
+             CMakeFiles/openexr-c-0_1-shared.dir/a.o -lz\n",
+        )
+        .unwrap();
+        let libs = get_linking_from_cmake(&build, "openexr-c-0_1", "Release");
+        assert_eq!(libs, vec![LinkArg::LinkLib("z".into())]);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn missing_link_txt_names_both_paths() {
+        let build = TempDir::new("nolinktxt");
+        match link_line(&build, "openexr-c-0_1", "Release") {
+            Err(BuildError::MissingLinkLine(tried)) => assert_eq!(
+                tried,
+                vec![
+                    build.join("CMakeFiles/openexr-c-0_1.dir/link.txt"),
+                    build.join("CMakeFiles/openexr-c-0_1-shared.dir/link.txt"),
+                ]
+            ),
+            r => panic!("expected a missing link line, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn invalid_env_values_are_errors() {
+        let var = format!("CPPMM_TEST_{}_RETRIES", std::process::id());
+        assert_eq!(parse_env::<u32>(&var).unwrap(), None);
+        std::env::set_var(&var, "3");
+        assert_eq!(parse_env::<u32>(&var).unwrap(), Some(3));
+        std::env::set_var(&var, "three");
+        match parse_env::<u32>(&var) {
+            Err(BuildError::InvalidEnv { var: v, value }) => {
+                assert_eq!((v, value), (var.clone(), "three".to_string()))
+            }
+            r => panic!("expected an invalid value, got {:?}", r),
+        }
+        std::env::remove_var(&var);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn include_dirs_on_link_line() {
+        let re = Regex::new(DYLIB_RE).unwrap();
+        let mut unclassified = Vec::new();
+        let libs = link_txt_link_args(
+            "/usr/bin/c++ -shared -o libfoo.so -isystem /deps/include \
+             -iquote gen -isystem/sdk/include /deps/lib/libz.so",
+            Path::new("/build"),
+            &re,
+            &mut unclassified,
+        );
+        assert_eq!(
+            libs,
+            vec![
+                LinkArg::IncludeDir("/deps/include".into()),
+                LinkArg::IncludeDir("/build/gen".into()),
+                LinkArg::IncludeDir("/sdk/include".into()),
+                LinkArg::Path(parse_dylib_path("/deps/lib/libz.so").unwrap()),
+            ]
+        );
+        assert!(unclassified.is_empty(), "{:?}", unclassified);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn frameworks_on_link_line() {
+        let libs = link_txt_link_args(
+            "/usr/bin/c++ -dynamiclib -o libfoo.dylib -framework Metal \
+             /deps/lib/libz.dylib -framework CoreFoundation",
+            Path::new("/build"),
+            &Regex::new(DYLIB_RE).unwrap(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            libs,
+            vec![
+                LinkArg::LinkLib("framework=Metal".into()),
+                LinkArg::Path(
+                    parse_dylib_path("/deps/lib/libz.dylib").unwrap()
+                ),
+                LinkArg::LinkLib("framework=CoreFoundation".into()),
+            ]
+        );
+        assert_eq!(framework_name("framework=Metal"), Some("Metal"));
+        assert_eq!(framework_name("framework:+verbatim=Metal"), Some("Metal"));
+        assert_eq!(framework_name("static=Metal"), None);
+        assert_eq!(framework_name("Metal"), None);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn response_files_are_read() {
+        let re = Regex::new(DYLIB_RE).unwrap();
+        let build = TempDir::new("rsp");
+        std::fs::create_dir_all(build.join("CMakeFiles")).unwrap();
+        std::fs::write(
+            build.join("CMakeFiles").join("linkLibs.rsp"),
+            " /deps/lib/libImath.so.29\n@CMakeFiles/more.rsp -lz ",
+        )
+        .unwrap();
+        std::fs::write(
+            build.join("CMakeFiles").join("more.rsp"),
+            "/deps/lib/libOpenEXR.so",
+        )
+        .unwrap();
+        let objects = build.join("objects1.rsp");
+        std::fs::write(&objects, "CMakeFiles/foo.dir/foo.o extra.o").unwrap();
+
+        let libs = link_txt_link_args(
+            &format!(
+                "/usr/bin/c++ -shared -o libfoo.so @{} \
+                 @CMakeFiles/linkLibs.rsp @missing.rsp",
+                objects.display()
+            ),
+            &build,
+            &re,
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            libs,
+            vec![
+                LinkArg::ObjectFile(
+                    build.join("extra.o").to_string_lossy().into_owned()
+                ),
+                LinkArg::Path(
+                    parse_dylib_path("/deps/lib/libImath.so.29").unwrap()
+                ),
+                LinkArg::Path(
+                    parse_dylib_path("/deps/lib/libOpenEXR.so").unwrap()
+                ),
+                LinkArg::LinkLib("z".into()),
+            ]
+        );
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn missing_windows_link_line_is_not_fatal() {
+        let build = TempDir::new("nolink");
+        let libs = get_linking_from_cmake(&build, "openexr-c-0_1", "Release");
+        assert!(libs.is_empty());
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn missing_slices_are_found() {
+        let archs = ["x86_64".to_string(), "arm64".to_string()];
+        assert!(missing_architectures("x86_64 arm64\n", &archs).is_empty());
+        assert_eq!(missing_architectures("arm64\n", &archs), vec!["x86_64"]);
+        assert_eq!(missing_architectures("", &archs), vec!["x86_64", "arm64"]);
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn only_newly_installed_dylibs_are_relocated() {
+        let root = TempDir::new("relocate");
+        let build_dir = root.join("build-imath-0123abcd");
+        let lib = root.join("lib");
+        std::fs::create_dir_all(build_dir.join("build")).unwrap();
+        std::fs::create_dir_all(&lib).unwrap();
+        let dylib = lib.join("libImath-3_1.29.dylib");
+        let link = lib.join("libImath-3_1.dylib");
+        std::fs::write(&dylib, "").unwrap();
+        std::os::unix::fs::symlink(&dylib, &link).unwrap();
+        let manifest = build_dir.join("build").join("install_manifest.txt");
+        let dylibs = relocatable_dylibs(&[
+            link.clone(),
+            dylib.clone(),
+            lib.join("libImath-3_1.a"),
+        ]);
+        let never_installed = needs_relocating(&build_dir);
+        std::fs::write(&manifest, format!("{}\n", dylib.display())).unwrap();
+        let installed = needs_relocating(&build_dir);
+        std::fs::write(
+            build_dir.join(".cppmm-relocated"),
+            relocation_stamp(&build_dir).unwrap(),
+        )
+        .unwrap();
+        let relocated = needs_relocating(&build_dir);
+        std::fs::write(
+            &manifest,
+            format!("{}\n{}\n", dylib.display(), link.display()),
+        )
+        .unwrap();
+        let reinstalled = needs_relocating(&build_dir);
+
+        assert_eq!(dylibs, vec![dylib]);
+        assert!(!never_installed);
+        assert!(installed);
+        assert!(!relocated);
+        assert!(reinstalled);
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn install_names_are_made_relative_to_rpath() {
+        let args = install_name_tool_args(
+            Path::new("/t/lib/libImath-3_1.29.dylib"),
+            Path::new("/t/lib"),
+            &[
+                "libImath-3_1.29.dylib".to_string(),
+                "libImath-3_1.dylib".to_string(),
+            ],
+        );
+        let args: Vec<_> = args.iter().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            vec![
+                "-id",
+                "@rpath/libImath-3_1.29.dylib",
+                "-change",
+                "/t/lib/libImath-3_1.dylib",
+                "@rpath/libImath-3_1.dylib",
+                "/t/lib/libImath-3_1.29.dylib",
+            ]
+        );
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn runtime_dlls_are_copied() {
+        let root = TempDir::new("dlls");
+        let bin = root.join("bin");
+        let dest = root.join("out");
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        std::fs::write(bin.join("Imath.dll"), "dll").unwrap();
+
+        let copied = copy_runtime_libs(
+            &["Imath.dll".to_string(), "kernel32.dll".to_string()],
+            &[root.join("missing"), bin.clone()],
+            std::slice::from_ref(&dest),
+        );
+        let exists = dest.join("Imath.dll").exists();
+
+        assert_eq!(copied, vec![bin.join("Imath.dll")]);
+        assert!(exists);
+    }
+
+    #[test]
+    fn repeated_link_args_are_removed() {
+        let deduped = dedup_link_args(vec![
+            LinkArg::LinkDir("/a".to_string()),
+            dylib("/a/libImath.so", "Imath"),
+            LinkArg::LinkLib("z".to_string()),
+            LinkArg::LinkDir("/b".to_string()),
+            LinkArg::LinkDir("/a".to_string()),
+            dylib("/b/libz.so", "z"),
+            LinkArg::LinkLib("Imath".to_string()),
+            LinkArg::LinkLib("dl".to_string()),
+            LinkArg::LinkLib("z".to_string()),
+        ]);
+        assert_eq!(
+            deduped,
+            vec![
+                LinkArg::LinkDir("/a".to_string()),
+                dylib("/a/libImath.so", "Imath"),
+                // the path wins over the bare name, where the name came first
+                dylib("/b/libz.so", "z"),
+                LinkArg::LinkDir("/b".to_string()),
+                LinkArg::LinkLib("dl".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_static_archives_are_kept() {
+        let mut args = vec![
+            LinkArg::LinkLib("static=a".to_string()),
+            LinkArg::LinkLib("static=b".to_string()),
+            LinkArg::LinkLib("static=a".to_string()),
+        ];
+        if cfg!(not(target_os = "windows")) {
+            args.extend(vec![
+                dylib("/l/libc1.a", "c1"),
+                dylib("/l/libc2.a", "c2"),
+                dylib("/l/libc1.a", "c1"),
+            ]);
+        }
+        assert_eq!(dedup_link_args(args.clone()), args);
+    }
+
+    #[test]
+    fn missing_link_args_are_all_reported() {
+        let dir = TempDir::new("verify");
+        let lib = dir.join("libImath.so");
+        std::fs::write(&lib, "").unwrap();
+        let dir_str = dir.to_str().unwrap().to_string();
+
+        let present = vec![
+            LinkArg::LinkDir(dir_str.clone()),
+            LinkArg::LinkLib("dl".to_string()),
+            dylib(lib.to_str().unwrap(), "Imath"),
+        ];
+        let ok = verify_link_args(&present);
+
+        let mut absent = present.clone();
+        absent.push(LinkArg::LinkDir(format!("{}-nope", dir_str)));
+        absent.push(dylib("/nope/libz.so", "z"));
+        let err = verify_link_args(&absent);
+
+        assert!(ok.is_ok());
+        match err {
+            Err(BuildError::MissingLinkArgs(missing)) => {
+                assert_eq!(missing.len(), 2);
+                assert!(missing[1].contains("/nope/libz.so"));
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn cxx_stdlib_from_env() {
+        assert_eq!(CxxStdlib::from_env("libc++"), Some(CxxStdlib::LibCxx));
+        assert_eq!(CxxStdlib::from_env("C++"), Some(CxxStdlib::LibCxx));
+        assert_eq!(CxxStdlib::from_env("stdc++"), Some(CxxStdlib::LibStdCxx));
+        assert_eq!(CxxStdlib::from_env("none"), Some(CxxStdlib::None));
+        assert_eq!(CxxStdlib::from_env("msvcrt"), None);
+
+        assert_eq!(CxxStdlib::LibCxx.link_lib(), Some("c++"));
+        assert_eq!(CxxStdlib::None.link_lib(), None);
+        #[cfg(target_os = "linux")]
+        assert_eq!(CxxStdlib::Auto.link_lib(), Some("stdc++"));
+    }
+
+    #[test]
+    fn cmake_prefix_path_probes_package_dirs() {
+        let prefix = TempDir::new("pkgdirs");
+        let empty = cmake_prefix_path(&prefix);
+        std::fs::create_dir_all(prefix.join("lib64").join("cmake")).unwrap();
+        std::fs::create_dir_all(prefix.join("share").join("cmake")).unwrap();
+        let probed = cmake_prefix_path(&prefix);
+
+        assert_eq!(empty, prefix.join("lib").join("cmake"));
+        let mut expected = prefix.join("lib64").join("cmake").into_os_string();
+        expected.push(";");
+        expected.push(prefix.join("share").join("cmake"));
+        assert_eq!(probed, expected);
+    }
+
+    #[test]
+    fn compiler_launcher_must_exist() {
+        let dir = TempDir::new("launcher");
+        let launcher = dir.join("ccache");
+        std::fs::write(&launcher, "").unwrap();
+
+        let found = find_program(&launcher);
+        let missing = find_program(&dir.join("sccache"));
+
+        assert_eq!(found, Some(launcher));
+        assert_eq!(missing, None);
+        assert_eq!(
+            find_program(Path::new("cppmm-build-no-such-launcher")),
+            None
+        );
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn dylib_paths_are_parsed() {
+        for (path, basename, libname) in &[
+            ("/home/libs/libmylib.so", "libmylib.so", "mylib"),
+            ("/home/libs/libmylib.so.3.1", "libmylib.so.3.1", "mylib"),
+            ("/home/libs/libmylib.dylib", "libmylib.dylib", "mylib"),
+            (
+                "/home/lib/libImath-3_1.so.29",
+                "libImath-3_1.so.29",
+                "Imath-3_1",
+            ),
+        ] {
+            let info = parse_dylib_path(path).unwrap();
+            assert_eq!(info.path, *path);
+            assert_eq!(info.basename, *basename);
+            assert_eq!(info.libname, *libname);
+        }
+        assert_eq!(parse_dylib_path("/home/libs/libmylib.la"), None);
+        assert!(is_static_archive(
+            &parse_dylib_path("/home/libs/libmylib.a").unwrap()
+        ));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn link_names_on_unix() {
+        for (path, name) in &[
+            ("/usr/lib/libFoo.so", "Foo"),
+            ("/usr/lib/libFoo.so.3", "Foo"),
+            ("/usr/lib/libFoo.so.3.1.4", "Foo"),
+            ("/usr/lib/libFoo.dylib", "Foo"),
+            ("/usr/lib/libFoo.a", "Foo"),
+            ("libImath-3_1.so.29", "Imath-3_1"),
+        ] {
+            assert_eq!(link_name(path).as_deref(), Some(*name), "{}", path);
+        }
+        assert_eq!(link_name("/usr/lib/libFoo.la"), None);
+        assert_eq!(link_name("/usr/lib/Foo.so"), None);
+    }
+
+    #[test]
+    fn link_names_on_windows() {
+        let re = Regex::new(WINDOWS_LINK_NAME_RE).unwrap();
+        for (path, name) in &[
+            (r"C:\libs\Foo.lib", "Foo"),
+            ("C:/libs/Foo-3_1.lib", "Foo-3_1"),
+            (r"C:\bin\Foo.dll", "Foo"),
+            ("kernel32.lib", "kernel32"),
+            (r"C:\bin\FOO.DLL", "FOO"),
+        ] {
+            assert_eq!(link_name_with(path, &re), Some(*name), "{}", path);
+        }
+        assert_eq!(link_name_with(r"C:\libs\Foo.pdb", &re), None);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn dylib_paths_are_parsed() {
+        let info = parse_dylib_path(r"C:\libs\mylib-3_1.lib").unwrap();
+        assert_eq!(info.basename, "mylib-3_1.lib");
+        assert_eq!(info.libname, "mylib-3_1");
+        assert_eq!(parse_dylib_path("mylib-3_1.dll"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_are_named() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"/deps/caf\xe9/lib"));
+        let err = path_str(path).unwrap_err();
+        assert!(matches!(&err, BuildError::NonUtf8Path(p) if p == path));
+        assert_eq!(
+            err.to_string(),
+            "Path is not valid UTF-8: /deps/caf\u{fffd}/lib"
+        );
+        assert_eq!(path_str(Path::new("/deps/lib")).unwrap(), "/deps/lib");
+    }
+
+    #[test]
+    fn extra_prefix_paths_follow_the_internal_one() {
+        let extra = [PathBuf::from("/opt/ocio"), PathBuf::from("/opt/tbb")];
+        let prefix = Path::new("/tmp/cppmm-build-no-such-prefix");
+        let mut expected = cmake_prefix_path(prefix);
+        expected.push(";/opt/ocio;/opt/tbb");
+        assert_eq!(shim_prefix_path(Some(prefix), &extra), Some(expected));
+        assert_eq!(
+            shim_prefix_path(None, &extra),
+            Some("/opt/ocio;/opt/tbb".into())
+        );
+        assert_eq!(shim_prefix_path(None, &[]), None);
+    }
+
+    #[test]
+    fn mismatched_cxx_standards_are_reported() {
+        let dep = |name: &str, standard: &str| DependencyOwned {
+            name: name.to_string(),
+            definitions: vec![(
+                "CMAKE_CXX_STANDARD".to_string(),
+                standard.to_string(),
+            )],
+            ..Default::default()
+        };
+        let deps =
+            [dep("Imath", "14"), dep("openexr", "17"), dep("zlib", "17")];
+
+        assert_eq!(
+            cxx_standard_warnings(&deps, Some(17)),
+            vec![
+                "Imath sets CMAKE_CXX_STANDARD=14 but is being built as C++17"
+            ]
+        );
+        let warnings = cxx_standard_warnings(&deps, None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("Imath=14, openexr=17, zlib=17"));
+        assert!(cxx_standard_warnings(&deps[1..], None).is_empty());
+    }
+
+    #[test]
+    fn glibcxx_abi_is_defined_for_cxx() {
+        let mut builder = Builder::new("openexr", 0, 1);
+        builder.cxxflag("-Wall");
+        assert_eq!(builder.cxx_flags(), vec!["-Wall"]);
+        builder.glibcxx_cxx11_abi(false);
+        assert_eq!(
+            builder.cxx_flags(),
+            vec!["-Wall", "-D_GLIBCXX_USE_CXX11_ABI=0"]
+        );
+        builder.glibcxx_cxx11_abi(true);
+        assert_eq!(
+            builder.cxx_flags(),
+            vec!["-Wall", "-D_GLIBCXX_USE_CXX11_ABI=1"]
+        );
+    }
+
+    #[test]
+    fn build_libraries_decision_follows_env() {
+        assert!(build_libraries_decision(false, None));
+        assert!(build_libraries_decision(false, Some("0")));
+        assert!(!build_libraries_decision(true, None));
+        assert!(build_libraries_decision(true, Some("yes")));
+        assert!(!build_libraries_decision(true, Some("0")));
+    }
+
+    #[test]
+    fn deployment_target_prefers_env() {
+        assert_eq!(macos_deployment_target(None, None), (None, None));
+        assert_eq!(
+            macos_deployment_target(Some("10.15"), None),
+            (Some("10.15".to_string()), None)
+        );
+        assert_eq!(
+            macos_deployment_target(Some("11.0"), Some("11.0")),
+            (Some("11.0".to_string()), None)
+        );
+        assert_eq!(
+            macos_deployment_target(None, Some("11.0")),
+            (Some("11.0".to_string()), None)
+        );
+        let (target, warning) =
+            macos_deployment_target(Some("10.15"), Some("11.0"));
+        assert_eq!(target.as_deref(), Some("11.0"));
+        assert!(warning.unwrap().contains("asked for 10.15"));
+    }
+
+    #[test]
+    fn search_dirs_are_listed_once() {
+        let path = |p: &str| LinkArg::Path(parse_dylib_path(p).unwrap());
+        #[cfg(not(target_os = "windows"))]
+        let libs = [
+            path("/deps/lib/libImath.so"),
+            LinkArg::LinkLib("z".into()),
+            path("/deps/lib/libOpenEXR.so"),
+            LinkArg::LinkDir("/opt/lib".into()),
+            path("/opt/lib/libtbb.so"),
+            LinkArg::LinkDir("/deps/lib".into()),
+            path("/deps/lib/libIex.so"),
+        ];
+        #[cfg(target_os = "windows")]
+        let libs = [
+            path("/deps/lib/Imath.lib"),
+            LinkArg::LinkLib("z".into()),
+            path("/deps/lib/OpenEXR.lib"),
+            LinkArg::LinkDir("/opt/lib".into()),
+            path("/opt/lib/tbb.lib"),
+            LinkArg::LinkDir("/deps/lib".into()),
+            path("/deps/lib/Iex.lib"),
+        ];
+        assert_eq!(
+            link_search_dirs(&libs),
+            vec![PathBuf::from("/deps/lib"), PathBuf::from("/opt/lib")]
+        );
+    }
+
+    #[test]
+    fn versioned_names_follow_format() {
+        assert_eq!(versioned_name(None, "openexr", 0, 10, 0), "openexr-c-0_10");
+        assert_eq!(
+            versioned_name(
+                Some("{project}-c-{major}.{minor}"),
+                "oiio",
+                2,
+                4,
+                0
+            ),
+            "oiio-c-2.4"
+        );
+        assert_eq!(versioned_name(Some("usd-c"), "usd", 0, 1, 0), "usd-c");
+    }
+
+    #[test]
+    fn patch_version_is_in_default_name() {
+        assert_eq!(
+            versioned_name(None, "openexr", 0, 10, 3),
+            "openexr-c-0_10_3"
+        );
+        assert_eq!(
+            versioned_name(
+                Some("{project}-{major}.{minor}.{patch}"),
+                "oiio",
+                2,
+                4,
+                1
+            ),
+            "oiio-2.4.1"
+        );
+        assert_eq!(
+            versioned_name(
+                Some("{project}-c-{major}_{minor}"),
+                "oiio",
+                2,
+                4,
+                1
+            ),
+            "oiio-c-2_4"
+        );
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    #[test]
+    fn rpaths_are_relative_inside_target_dir() {
+        let profile = Path::new("/work/target/release");
+        assert_eq!(
+            runtime_rpaths(&profile.join("lib"), profile),
+            ["$ORIGIN/lib", "$ORIGIN/../lib", "/work/target/release/lib"]
+        );
+        assert_eq!(
+            runtime_rpaths(Path::new("/opt/vfx/lib"), profile),
+            ["/opt/vfx/lib"]
+        );
+        assert_eq!(
+            runtime_env_script(&[PathBuf::from("/opt/vfx/lib")]),
+            "# Source this to run binaries linked against the packaged \
+             libraries\nexport LD_LIBRARY_PATH=\"/opt/vfx/lib\
+             ${LD_LIBRARY_PATH:+:$LD_LIBRARY_PATH}\"\n"
+        );
+    }
+
+    #[test]
+    fn extra_link_libs_keep_their_kind() {
+        let mut builder = Builder::new("openexr", 0, 10);
+        builder
+            .extra_link_search("/usr/lib/nvidia")
+            .extra_link_lib("dylib", "cuda")
+            .extra_link_lib("static", "drv");
+        assert_eq!(
+            builder.extra_link_args,
+            [
+                LinkArg::LinkDir("/usr/lib/nvidia".to_string()),
+                LinkArg::LinkLib("cuda".to_string()),
+                LinkArg::LinkLib("static=drv".to_string()),
+            ]
+        );
+        assert_eq!(
+            link_lib_directive("cuda"),
+            "cargo:rustc-link-lib=dylib=cuda"
+        );
+        assert_eq!(
+            link_lib_directive("static=drv"),
+            "cargo:rustc-link-lib=static=drv"
+        );
+    }
+
+    #[test]
+    fn unreplaced_abi_markers_are_found() {
+        let abi = "pub const Imf_Header_size: usize = 1096;\n\
+                   pub const Imf_Header_align: usize = 8;\n\
+                   let x = a % b;\n";
+        assert_eq!(unreplaced_abi_marker(abi), None);
+        let abi = "pub const Imf_Header_size: usize = 1096;\n\
+                   pub const Imf_Attribute_size: usize = %SIZE_Imf_Attribute%;\n";
+        assert_eq!(
+            unreplaced_abi_marker(abi),
+            Some((
+                2,
+                "pub const Imf_Attribute_size: usize = %SIZE_Imf_Attribute%;"
+            ))
+        );
+    }
+
+    #[test]
+    fn rebuilt_abigen_is_newer() {
+        let dir = TempDir::new("newer");
+        let abigen = dir.join("abigen");
+        let abigen_txt = dir.join("abigen.txt");
+        std::fs::write(&abigen, "").unwrap();
+        assert!(!is_newer(&abigen, &abigen_txt));
+
+        std::fs::write(&abigen_txt, "").unwrap();
+        let earlier = std::time::SystemTime::now() - Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&abigen_txt)
+            .unwrap()
+            .set_modified(earlier)
+            .unwrap();
+        assert!(is_newer(&abigen, &abigen_txt));
+        assert!(!is_newer(&abigen_txt, &abigen));
+    }
+
+    #[test]
+    fn changed_sources_are_newer_than_stamp() {
+        let dir = TempDir::new("sources");
+        let src_dir = dir.join("src");
+        std::fs::create_dir_all(src_dir.join("nested")).unwrap();
+        std::fs::create_dir_all(src_dir.join(".git")).unwrap();
+        let stamp = dir.join(".cppmm-stamp");
+        assert!(sources_newer_than(&src_dir, &stamp));
+
+        let source = src_dir.join("nested").join("a.cpp");
+        std::fs::write(&source, "").unwrap();
+        std::fs::write(src_dir.join(".git").join("index"), "").unwrap();
+        std::fs::write(&stamp, "").unwrap();
+        let set_mtime = |path: &Path, secs_ago: u64| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(
+                    std::time::SystemTime::now()
+                        - Duration::from_secs(secs_ago),
+                )
+                .unwrap()
+        };
+        set_mtime(&source, 120);
+        set_mtime(&stamp, 60);
+        assert!(!sources_newer_than(&src_dir, &stamp));
+
+        set_mtime(&source, 0);
+        let changed = sources_newer_than(&src_dir, &stamp);
+        assert!(changed);
+    }
+
+    #[test]
+    fn find_root_modes_default_for_sysroots() {
+        assert!(find_root_definitions(&[], &[]).unwrap().is_empty());
+
+        let roots =
+            [PathBuf::from("/sdk/sysroot"), PathBuf::from("/sdk/extra")];
+        let modes = [("PACKAGE".to_string(), "ONLY".to_string())];
+        assert_eq!(
+            find_root_definitions(&roots, &modes).unwrap(),
+            [
+                ("CMAKE_FIND_ROOT_PATH", "/sdk/sysroot;/sdk/extra"),
+                ("CMAKE_FIND_ROOT_PATH_MODE_PROGRAM", "NEVER"),
+                ("CMAKE_FIND_ROOT_PATH_MODE_LIBRARY", "ONLY"),
+                ("CMAKE_FIND_ROOT_PATH_MODE_INCLUDE", "ONLY"),
+                ("CMAKE_FIND_ROOT_PATH_MODE_PACKAGE", "ONLY"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn static_linkage_covers_project_options() {
+        let defs = linkage_definitions("OpenEXR", false);
+        let value = |key: &str| {
+            defs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+        };
+        assert_eq!(value("BUILD_SHARED_LIBS"), Some("OFF"));
+        assert_eq!(value("OPENEXR_BUILD_SHARED"), Some("OFF"));
+        assert_eq!(value("OPENEXR_BUILD_STATIC"), Some("ON"));
+        assert_eq!(
+            linkage_definitions("libjpeg-turbo", true)[1],
+            ("LIBJPEG_TURBO_BUILD_SHARED".to_string(), "ON".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_wrapper_names_the_directory() {
+        let message =
+            BuildError::MissingWrapper(PathBuf::from("openexr-c")).to_string();
+        assert!(message.contains("cppmm-generated C wrapper"));
+        assert!(message.contains("'openexr-c'"));
+    }
+
+    #[test]
+    fn link_directives_are_kept_in_order() {
+        let out_dir = TempDir::new("directives");
+        let mut directives = LinkDirectives::default();
+        directives.emit("cargo:rustc-link-search=native=/deps/lib".into());
+        directives.emit(link_lib_directive("z"));
+        directives.finish(&out_dir);
+        let written =
+            std::fs::read_to_string(out_dir.join("cppmm-link-directives.txt"));
+        assert_eq!(
+            written.unwrap(),
+            "cargo:rustc-link-search=native=/deps/lib\n\
+             cargo:rustc-link-lib=dylib=z\n"
+        );
+    }
+
+    #[test]
+    fn ignored_libs_match_by_name() {
+        let ignored = vec!["z".to_string(), "libpng16.so.16".to_string()];
+        assert!(is_ignored_lib(&LinkArg::LinkLib("z".into()), &ignored));
+        assert!(is_ignored_lib(
+            &LinkArg::LinkLib("static=z".into()),
+            &ignored
+        ));
+        assert!(!is_ignored_lib(&LinkArg::LinkLib("zstd".into()), &ignored));
+        let path = |path: &str, basename: &str, libname: &str| {
+            LinkArg::Path(DylibPathInfo {
+                path: path.into(),
+                basename: basename.into(),
+                libname: libname.into(),
+                dll: None,
+            })
+        };
+        assert!(is_ignored_lib(
+            &path("/usr/lib/libz.so.1", "libz.so.1", "z"),
+            &ignored
+        ));
+        assert!(is_ignored_lib(
+            &path("/usr/lib/libpng16.so.16", "libpng16.so.16", "png16"),
+            &ignored
+        ));
+        assert!(!is_ignored_lib(&LinkArg::LinkDir("z".into()), &ignored));
+    }
+
+    #[test]
+    fn prefix_dirs() {
+        let prefix = Path::new("/deps");
+        assert_eq!(include_dir(prefix), Path::new("/deps/include"));
+        assert_eq!(lib_dir(prefix), Path::new("/deps/lib"));
+        assert_eq!(bin_dir(prefix), Path::new("/deps/bin"));
+    }
+
+    #[test]
+    fn compiler_from_target_env() {
+        // Not CC, so this doesn't change what the other tests would compile
+        // with
+        let var = "CPPMMTEST_CC";
+        assert_eq!(compiler_from_env(var), None);
+        std::env::set_var(var, "gcc");
+        assert_eq!(compiler_from_env(var), Some(PathBuf::from("gcc")));
+        std::env::set_var(format!("TARGET_{}", var), "clang");
+        assert_eq!(compiler_from_env(var), Some(PathBuf::from("clang")));
+        std::env::remove_var(var);
+        std::env::remove_var(format!("TARGET_{}", var));
+    }
+
+    #[test]
+    fn interrupted_builds_are_cleaned() {
+        let target_dir = TempDir::new("interrupted");
+        let thirdparty =
+            ThirdpartyBuild::new("zlib", &target_dir, "Release", &[]);
+        let build_dir = thirdparty.build_dir();
+        std::fs::create_dir_all(build_dir.join("build")).unwrap();
+        std::fs::create_dir_all(target_dir.join("lib")).unwrap();
+        let installed = target_dir.join("lib").join("libz.so");
+        std::fs::write(&installed, "").unwrap();
+        std::fs::write(
+            build_dir.join("build").join("install_manifest.txt"),
+            installed.to_string_lossy().as_bytes(),
+        )
+        .unwrap();
+
+        let finished = thirdparty.clean_interrupted_build();
+        // a process id that can't be running
+        std::fs::write(build_dir.join(".cppmm-building"), "4294967295")
+            .unwrap();
+        let interrupted = thirdparty.clean_interrupted_build();
+        let cleaned = !build_dir.exists() && !installed.exists();
+
+        assert!(!finished);
+        assert!(interrupted);
+        assert!(cleaned);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn builds_by_running_processes_are_not_interrupted() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let running = is_building_elsewhere(&child.id().to_string());
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(running);
+        assert!(!is_building_elsewhere(&std::process::id().to_string()));
+        assert!(!is_building_elsewhere("4294967295"));
+        assert!(!is_building_elsewhere("not a pid"));
+    }
+
+    #[test]
+    fn abi_profile_is_next_to_abigen_txt() {
+        assert_eq!(
+            abi_profile_path(Path::new("/out/build/abigen.txt")),
+            Path::new("/out/build/abigen.txt.profile")
+        );
+        assert_eq!(
+            abi_profile_path(Path::new("/out/build/gen/sizes")),
+            Path::new("/out/build/gen/sizes.profile")
+        );
+    }
+
+    #[test]
+    fn dependency_sources_default_to_thirdparty() {
+        let mut dep = DependencyOwned {
+            name: "zlib".into(),
+            ..Default::default()
+        };
+        assert_eq!(dependency_source_dir(&dep), Path::new("thirdparty/zlib"));
+        dep.source_dir = Some("/src/zlib".into());
+        assert_eq!(dependency_source_dir(&dep), Path::new("/src/zlib"));
+    }
+
+    #[test]
+    fn link_group_repeats_libraries() {
+        let args = vec![
+            LinkArg::LinkDir("/deps/lib".into()),
+            LinkArg::Path(DylibPathInfo {
+                path: "/deps/lib/libusd_tf.a".into(),
+                basename: "libusd_tf.a".into(),
+                libname: "usd_tf".into(),
+                dll: None,
+            }),
+            LinkArg::LinkLib("static=usd_vt".into()),
+            LinkArg::LinkLib("pthread".into()),
+            LinkArg::LinkLib("framework=CoreFoundation".into()),
+            LinkArg::ObjectFile("/deps/lib/init.o".into()),
+        ];
+        assert_eq!(
+            link_group_args(&args),
+            vec![
+                "-Wl,--start-group",
+                "/deps/lib/libusd_tf.a",
+                "-lusd_vt",
+                "-lpthread",
+                "-Wl,--end-group",
+            ]
+        );
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn installed_archives_from_manifest() {
+        let out_dir = TempDir::new("archives");
+        std::fs::create_dir_all(out_dir.join("build")).unwrap();
+        let empty = installed_archives(&out_dir);
+        std::fs::write(
+            out_dir.join("build").join("install_manifest.txt"),
+            "/deps/include/zlib.h\n/deps/lib/libz.a\n/deps/lib/libz.so\n",
+        )
+        .unwrap();
+        let archives = installed_archives(&out_dir);
+
+        assert!(empty.is_empty());
+        assert_eq!(
+            archives,
+            vec![LinkArg::Path(DylibPathInfo {
+                path: "/deps/lib/libz.a".into(),
+                basename: "libz.a".into(),
+                libname: "z".into(),
+                dll: None,
+            })]
+        );
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn runtime_libs_are_the_linked_shared_libraries() {
+        let path = |p: &str| LinkArg::Path(parse_dylib_path(p).unwrap());
+        let args = vec![
+            LinkArg::LinkDir("/deps/lib".into()),
+            path("/deps/lib/libImath-3_1.so.29"),
+            path("/deps/lib/libz.a"),
+            LinkArg::LinkLib("pthread".into()),
+            path("/deps/lib/libOpenEXR-3_1.so"),
+            path("/deps/lib/libImath-3_1.so.29"),
+        ];
+        assert_eq!(
+            runtime_libs(&args, Some("/out/libopenexr-c-0_1.a".into()), &[]),
+            vec![
+                PathBuf::from("/deps/lib/libImath-3_1.so.29"),
+                PathBuf::from("/deps/lib/libOpenEXR-3_1.so"),
+            ]
+        );
+        assert_eq!(
+            runtime_libs_path(Path::new("/out"), "openexr", 0, 1),
+            Path::new("/out/cppmm-openexr-0_1-runtime-libs.txt")
+        );
+    }
+
+    #[test]
+    fn only_failures_before_compiling_are_transient() {
+        let build_dir = TempDir::new("retry");
+
+        // Configuring failed, there's no build system yet
+        assert!(is_transient_failure(&build_dir));
+
+        // A build system means it failed compiling
+        for file in &["Makefile", "build.ninja", "openexr.sln"] {
+            std::fs::write(build_dir.join(file), "").unwrap();
+            assert!(!is_transient_failure(&build_dir));
+            // but one left over from an earlier build doesn't count
+            remove_build_system(&build_dir);
+            assert!(is_transient_failure(&build_dir));
+        }
+
+        // Nor is a package that wasn't found
+        std::fs::write(
+            build_dir.join("CMakeCache.txt"),
+            "Imath_DIR:PATH=Imath_DIR-NOTFOUND\n",
+        )
+        .unwrap();
+        assert!(!is_transient_failure(&build_dir));
+
+        assert_eq!(retry_delay(1), Duration::from_secs(2));
+        assert_eq!(retry_delay(3), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn cmake_warnings_from_configure_output() {
+        let output = "\
+CMake Deprecation Warning at CMakeLists.txt:1 (cmake_minimum_required):
+  Compatibility with CMake < 3.5 will be removed from a future version of
+  CMake.
+
+  Update the VERSION argument <min> value.
+
+
+CMake Warning (dev) at CMakeLists.txt:4 (project):
+  Policy CMP0048 is not set: project() command manages VERSION variables.
+This warning is for project developers.  Use -Wno-dev to suppress it.
+
+-- Configuring done
+CMake Warning:
+  Manually-specified variables were not used by the project:
+
+    BUILD_TESTING
+
+CMake Warning (dev) at CMakeLists.txt:4 (project):
+  Policy CMP0048 is not set: project() command manages VERSION variables.
+This warning is for project developers.  Use -Wno-dev to suppress it.
+";
+        assert_eq!(
+            cmake_warnings(output),
+            [
+                "CMake Deprecation Warning at CMakeLists.txt:1 \
+                 (cmake_minimum_required): Compatibility with CMake < 3.5 \
+                 will be removed from a future version of CMake. Update \
+                 the VERSION argument <min> value.",
+                "CMake Warning (dev) at CMakeLists.txt:4 (project): Policy \
+                 CMP0048 is not set: project() command manages VERSION \
+                 variables.",
+                "CMake Warning: Manually-specified variables were not used \
+                 by the project: BUILD_TESTING",
+            ]
+        );
+        assert!(cmake_warnings("-- Configuring done\n").is_empty());
+    }
+
+    #[test]
+    fn dependency_built_callbacks() {
+        let build_dir = TempDir::new("callback");
+        let stamp = build_dir.join(".cppmm-stamp");
+        std::fs::write(&stamp, "0").unwrap();
+
+        let called = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let record = called.clone();
+        let mut callbacks = vec![DependencyBuiltCallback(Box::new(
+            move |name: &str, prefix: &Path| {
+                record
+                    .borrow_mut()
+                    .push((name.to_string(), prefix.to_owned()))
+            },
+        ))];
+        let ok = run_dependency_built(
+            &mut callbacks,
+            "Imath",
+            &build_dir,
+            Path::new("/prefix"),
+        );
+        let kept_stamp = stamp.exists();
+
+        callbacks.push(DependencyBuiltCallback(Box::new(
+            |_: &str, _: &Path| panic!("pkg-config file not found"),
+        )));
+        let err = run_dependency_built(
+            &mut callbacks,
+            "openexr",
+            &build_dir,
+            Path::new("/prefix"),
+        );
+        let removed_stamp = !stamp.exists();
+
+        assert!(ok.is_ok());
+        assert!(kept_stamp);
+        assert_eq!(
+            *called.borrow(),
+            [
+                ("Imath".to_string(), PathBuf::from("/prefix")),
+                ("openexr".to_string(), PathBuf::from("/prefix")),
+            ]
+        );
+        match err {
+            Err(BuildError::DependencyCallback {
+                dependency,
+                message,
+            }) => {
+                assert_eq!(dependency, "openexr");
+                assert_eq!(message, "pkg-config file not found");
+            }
+            r => panic!("expected a callback error, got {:?}", r),
+        }
+        assert!(removed_stamp);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn native_dependencies_are_locked() {
+        let root = TempDir::new("lock");
+        let target_dir = root.join("target");
+        let system = root.join("system");
+        let imath_cmake = target_dir.join("lib").join("cmake").join("Imath");
+        let openexr_cmake = system.join("lib").join("cmake").join("OpenEXR");
+        let pkgconfig = system.join("lib").join("pkgconfig");
+        for dir in &[&imath_cmake, &openexr_cmake, &pkgconfig] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::write(
+            imath_cmake.join("ImathConfigVersion.cmake"),
+            "set(PACKAGE_VERSION \"3.1.9\")\n",
+        )
+        .unwrap();
+        std::fs::write(
+            openexr_cmake.join("OpenEXRConfigVersion.cmake"),
+            "set(PACKAGE_VERSION \"3.2.1\")\n",
+        )
+        .unwrap();
+        std::fs::write(
+            openexr_cmake.join("OpenEXRTargets-release.cmake"),
+            r#"
+set_target_properties(OpenEXR::OpenEXR PROPERTIES
+  IMPORTED_LOCATION_RELEASE "${_IMPORT_PREFIX}/lib/libOpenEXR-3_2.so.31"
+  )
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            pkgconfig.join("zlib.pc"),
+            "prefix=/usr\nName: zlib\nVersion: 1.2.13\n",
+        )
+        .unwrap();
+
+        let dep = |name: &str| DependencyOwned {
+            name: name.into(),
+            ..Default::default()
+        };
+        let imath_libs = vec![target_dir.join("lib").join("libImath-3_1.so")];
+        let resolved = resolve_dependencies(
+            &[dep("Imath"), dep("openexr"), dep("zlib"), dep("tbb")],
+            &[("Imath".to_string(), imath_libs.clone())],
+            &target_dir,
+            std::slice::from_ref(&system),
+        );
+
+        assert_eq!(
+            resolved,
+            [
+                ResolvedDependency {
+                    name: "Imath".into(),
+                    built: true,
+                    version: Some("3.1.9".into()),
+                    libraries: imath_libs,
+                },
+                ResolvedDependency {
+                    name: "openexr".into(),
+                    built: false,
+                    version: Some("3.2.1".into()),
+                    libraries: vec![system
+                        .join("lib")
+                        .join("libOpenEXR-3_2.so.31")],
+                },
+                ResolvedDependency {
+                    name: "zlib".into(),
+                    built: false,
+                    version: Some("1.2.13".into()),
+                    libraries: Vec::new(),
+                },
+                ResolvedDependency {
+                    name: "tbb".into(),
+                    built: false,
+                    version: None,
+                    libraries: Vec::new(),
+                },
+            ]
+        );
+
+        let lock = json::parse(&native_lock(&resolved[..2])).unwrap();
+        let locked = lock.get("dependencies").unwrap().as_array().unwrap();
+        assert_eq!(locked[0].get("source").unwrap().as_str(), Some("built"));
+        assert_eq!(locked[1].get("source").unwrap().as_str(), Some("system"));
+        assert_eq!(locked[1].get("version").unwrap().as_str(), Some("3.2.1"));
+        assert_eq!(
+            locked[1].get("libraries").unwrap().as_array().unwrap()[0]
+                .as_str()
+                .map(PathBuf::from),
+            Some(system.join("lib").join("libOpenEXR-3_2.so.31"))
+        );
+        assert_eq!(
+            native_lock_path(Path::new("/out")),
+            Path::new("/out/cppmm-native-lock.json")
+        );
+    }
+
+    #[test]
+    fn max_jobs_caps_global_jobs() {
+        assert!(Dependency::default().parallel);
+        assert!(DependencyOwned::default().parallel);
+        assert!(!Dependency::default().force_build);
+        assert_eq!(dependency_jobs(Some(2), Some(8)), Some(2));
+        assert_eq!(dependency_jobs(Some(8), Some(2)), Some(2));
+        assert_eq!(dependency_jobs(None, Some(8)), Some(8));
     }
 }
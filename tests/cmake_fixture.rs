@@ -0,0 +1,125 @@
+//! Builds a tiny CMake project as a packaged dependency, to check the parts
+//! of the build that actually run CMake.
+//!
+//! This needs `cmake` and a C compiler, so it only runs when
+//! `CPPMM_BUILD_CMAKE_TESTS=1` is set, e.g.
+//!
+//! ```sh
+//! CPPMM_BUILD_CMAKE_TESTS=1 cargo test --test cmake_fixture
+//! ```
+//!
+//! Keep this the only test in this file: it changes the working directory,
+//! since `build_thirdparty` looks for `thirdparty/<name>` relative to it.
+
+use std::path::Path;
+
+const CMAKE_LISTS: &str = r#"cmake_minimum_required(VERSION 3.5)
+project(hello C)
+
+# Installs into lib64 on some distros unless the build says otherwise
+include(GNUInstallDirs)
+
+add_library(hello SHARED hello.c)
+add_library(greeter SHARED greeter.c)
+target_link_libraries(greeter PRIVATE hello)
+
+install(TARGETS hello greeter
+    RUNTIME DESTINATION ${CMAKE_INSTALL_BINDIR}
+    LIBRARY DESTINATION ${CMAKE_INSTALL_LIBDIR}
+    ARCHIVE DESTINATION ${CMAKE_INSTALL_LIBDIR})
+"#;
+
+#[cfg(target_os = "windows")]
+const HELLO_C: &str = "__declspec(dllexport) int hello(void) { return 42; }\n";
+#[cfg(not(target_os = "windows"))]
+const HELLO_C: &str = "int hello(void) { return 42; }\n";
+
+#[cfg(target_os = "windows")]
+const GREETER_C: &str = "__declspec(dllimport) int hello(void);
+__declspec(dllexport) int greet(void) { return hello(); }
+";
+#[cfg(not(target_os = "windows"))]
+const GREETER_C: &str = "int hello(void);
+int greet(void) { return hello(); }
+";
+
+/// The cmake crate expects to be run from a build script, so fill in what
+/// Cargo would have set for one.
+fn set_build_script_env() {
+    let os_suffix = if cfg!(target_os = "windows") {
+        "pc-windows-msvc"
+    } else if cfg!(target_os = "macos") {
+        "apple-darwin"
+    } else {
+        "unknown-linux-gnu"
+    };
+    let triple = format!("{}-{}", std::env::consts::ARCH, os_suffix);
+
+    for (key, value) in &[
+        ("TARGET", triple.as_str()),
+        ("HOST", triple.as_str()),
+        ("OPT_LEVEL", "3"),
+        ("DEBUG", "false"),
+        ("PROFILE", "release"),
+        ("CARGO_CFG_TARGET_OS", std::env::consts::OS),
+        ("CARGO_CFG_TARGET_ARCH", std::env::consts::ARCH),
+    ] {
+        if std::env::var_os(key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
+}
+
+#[test]
+fn builds_thirdparty_fixture() {
+    if std::env::var("CPPMM_BUILD_CMAKE_TESTS").as_deref() != Ok("1") {
+        eprintln!("skipping, set CPPMM_BUILD_CMAKE_TESTS=1 to run");
+        return;
+    }
+    set_build_script_env();
+
+    let root = std::env::temp_dir()
+        .join(format!("cppmm-build-fixture-{}", std::process::id()));
+    let source = root.join("thirdparty").join("hello");
+    let target = root.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(source.join("CMakeLists.txt"), CMAKE_LISTS).unwrap();
+    std::fs::write(source.join("hello.c"), HELLO_C).unwrap();
+    std::fs::write(source.join("greeter.c"), GREETER_C).unwrap();
+
+    std::env::set_current_dir(&root).unwrap();
+    let dst = cppmm_build::build_thirdparty("hello", &target, "Release", &[]);
+    assert!(Path::new(&dst).join("build").is_dir());
+
+    #[cfg(target_os = "windows")]
+    let installed = ["bin/hello.dll", "lib/hello.lib"];
+    #[cfg(target_os = "macos")]
+    let installed = ["lib/libhello.dylib"];
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let installed = ["lib/libhello.so"];
+    for lib in &installed {
+        assert!(target.join(lib).exists(), "{} was not installed", lib);
+    }
+    assert!(!target.join("lib64").exists(), "installed into lib64");
+
+    // The greeter links against hello, which we should find on its link line
+    #[cfg(not(target_os = "windows"))]
+    {
+        let libs = cppmm_build::get_linking_from_cmake(
+            &Path::new(&dst).join("build"),
+            "greeter",
+            "Release",
+        );
+        assert!(
+            libs.iter().any(|arg| matches!(
+                arg,
+                cppmm_build::LinkArg::Path(d) if d.libname == "hello"
+            )),
+            "hello not found in {:?}",
+            libs
+        );
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}